@@ -2,6 +2,9 @@ fn main() {
     let mut config = prost_build::Config::new();
     config.bytes(&["."]);
     config.type_attribute(".", "#[derive(PartialOrd)]");
+    // prost 生成的 enum 已经 derive 了 PartialOrd，再加一次会冲突。
+    // prost-build 对同一个类型只取最精确匹配的 attribute，这里用空 attribute 替换掉上面的全局设置
+    config.type_attribute(".abi.ErrorCode", "");
     config
         .out_dir("src/pb")
         .compile_protos(&["abi.proto"], &["."])
@@ -1,7 +1,7 @@
 use std::fs;
 use anyhow::Result;
 
-use mini_kv::{ClientConfig, ClientTlsConfig, GeneralConfig, LogConfig, RotationConfig, ServerConfig, ServerTlsConfig, StorageConfig};
+use mini_kv::{ClientConfig, ClientTlsConfig, GeneralConfig, LogConfig, NetworkConfig, RotationConfig, ServerConfig, ServerTlsConfig, StorageConfig};
 
 fn main() -> Result<()> {
     const CA_CERT: &str = include_str!("../fixtures/ca.cert");
@@ -22,7 +22,18 @@ fn main() -> Result<()> {
         log: LogConfig {
             path: "/tmp/kv-log".into(),
             rotation: RotationConfig::Daily,
+            filter: None,
         },
+        network: NetworkConfig::default(),
+        auth: None,
+        namespace: None,
+        metrics: None,
+        limits: None,
+        memory: None,
+        keyspace: None,
+        durable: None,
+        groups: None,
+        backpressure: None,
     };
 
     fs::write(
@@ -37,6 +48,7 @@ fn main() -> Result<()> {
             ca: Some(CA_CERT.into()),
             domain: "kvserver.acme.inc".into(),
         },
+        network: NetworkConfig::default(),
     };
     fs::write(
         "fixtures/client.conf",
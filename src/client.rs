@@ -38,6 +38,11 @@ async fn main() -> Result<()> {
     let cmd = CommandRequest::new_subscribe(channel);
     let mut stream = stream.execute_streaming(&cmd).await?;
 
+    // 查看 topic 的订阅数和消息统计
+    let cmd = CommandRequest::new_topic_info(channel);
+    let data = ctrl.open_stream().await?.execute_unary(&cmd).await?;
+    info!("Topic info of {}: {:?}", channel, data.pairs);
+
     // 取消订阅
     let id = stream.id;
     start_unsubscribe(ctrl.open_stream().await?, channel, id)?;
@@ -68,9 +73,9 @@ fn start_publishing(
 fn start_unsubscribe(
     mut stream: ProstClientStream<Compat<yamux::Stream>>,
     name: &str,
-    id: u32,
+    id: u64,
 ) -> Result<(), KvError> {
-    let cmd = CommandRequest::new_unsubscribe(name, id as _);
+    let cmd = CommandRequest::new_unsubscribe(name, id);
     tokio::spawn(async move {
         time::sleep(Duration::from_millis(2000)).await;
         let res = stream.execute_unary(&cmd).await.unwrap();
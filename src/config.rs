@@ -1,4 +1,10 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::str::FromStr;
+use glob::Pattern;
 use serde::{Deserialize, Serialize};
 use crate::KvError;
 
@@ -9,12 +15,25 @@ pub struct ServerConfig {
     pub storage: StorageConfig,
     pub tls: ServerTlsConfig,
     pub log: LogConfig,
+    #[serde(default)]
+    pub network: NetworkConfig,
+    pub auth: Option<AuthConfig>,
+    pub namespace: Option<NamespaceConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub limits: Option<LimitConfig>,
+    pub memory: Option<MemoryConfig>,
+    pub keyspace: Option<KeyspaceConfig>,
+    pub durable: Option<DurableConfig>,
+    pub groups: Option<GroupConfig>,
+    pub backpressure: Option<BackpressureConfig>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ClientConfig {
     pub general: GeneralConfig,
     pub tls: ClientTlsConfig,
+    #[serde(default)]
+    pub network: NetworkConfig,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -26,6 +45,9 @@ pub struct GeneralConfig {
 pub struct LogConfig {
     pub path: String,
     pub rotation: RotationConfig,
+    /// tracing 的过滤规则，比如 "info,mini_kv=debug"，不设置时使用 RUST_LOG 环境变量
+    #[serde(default)]
+    pub filter: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -42,6 +64,127 @@ pub enum StorageConfig {
     SledDb(String),
 }
 
+impl FromStr for StorageConfig {
+    type Err = KvError;
+
+    /// 解析命令行中的 storage，格式是 "memtable" 或者 "sleddb:<path>"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s.eq_ignore_ascii_case("memtable") => Ok(Self::MemTable),
+            Some((kind, path)) if kind.eq_ignore_ascii_case("sleddb") && !path.is_empty() => {
+                Ok(Self::SledDb(path.into()))
+            }
+            _ => Err(KvError::InvalidConfig(format!(
+                "invalid storage {}, expect memtable or sleddb:<path>",
+                s
+            ))),
+        }
+    }
+}
+
+/// keyspace 事件通知配置，key 被修改时发布事件到 `__keyspace__:{table}`
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct KeyspaceConfig {
+    /// 开启通知的 table，支持 glob 模式
+    pub tables: Vec<String>,
+    /// set 事件中是否包含新的 value
+    #[serde(default)]
+    pub include_value: bool,
+}
+
+/// 持久化 topic 的配置，消息保存在配置的 storage 中，订阅时可以从某个 offset 开始重放
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct DurableConfig {
+    /// 需要持久化的 topic，支持 glob 模式
+    pub topics: Vec<String>,
+    /// 每个 topic 最多保留的消息数，不设置时使用 DEFAULT_MAX_MESSAGES
+    #[serde(default = "default_max_messages")]
+    pub max_messages: u64,
+}
+
+/// 每个持久化 topic 缺省保留的消息数
+pub const DEFAULT_MAX_MESSAGES: u64 = 10_000;
+
+fn default_max_messages() -> u64 {
+    DEFAULT_MAX_MESSAGES
+}
+
+impl Default for DurableConfig {
+    fn default() -> Self {
+        Self {
+            topics: vec![],
+            max_messages: DEFAULT_MAX_MESSAGES,
+        }
+    }
+}
+
+/// 消费组的配置
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct GroupConfig {
+    /// 消息投递后多久没有 Ack 就重新投递，单位毫秒
+    pub ack_timeout_ms: u64,
+}
+
+/// 订阅者处理太慢、消息队列满了时的处理方式
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct BackpressureConfig {
+    /// 没有单独配置的 topic 使用的策略
+    #[serde(default)]
+    pub policy: SlowSubscriberPolicy,
+    /// 单独配置的 topic，按顺序使用第一个匹配的配置
+    #[serde(default)]
+    pub topics: Vec<TopicPolicyConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TopicPolicyConfig {
+    /// topic 的名字，支持 glob 模式
+    pub topic: String,
+    pub policy: SlowSubscriberPolicy,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum SlowSubscriberPolicy {
+    /// 等待订阅者的队列有空位，只会阻塞发给这个订阅者的消息
+    /// 每个订阅者同时只有一条消息在等待，最多等待 5 秒，其他的消息被丢弃
+    #[default]
+    Block,
+    /// 丢弃队列里最旧的消息
+    DropOldest,
+    /// 丢弃新的消息
+    DropNewest,
+    /// 断开这个订阅
+    Disconnect,
+}
+
+/// MemTable 的内存限制，对 SledDb 不生效
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MemoryConfig {
+    /// 所有 key 和 value 最多占用的字节数
+    pub max_memory: u64,
+    /// 超过限制时的处理方式
+    #[serde(default)]
+    pub policy: EvictionPolicy,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum EvictionPolicy {
+    /// 不淘汰数据，超过限制的写入返回错误
+    #[default]
+    NoEviction,
+    /// 淘汰最久没有访问的 key
+    Lru,
+    /// 淘汰访问次数最少的 key
+    Lfu,
+    /// 随机淘汰
+    Random,
+    /// 优先淘汰最快过期的 key
+    /// 目前 key 不支持过期时间，配置检查会拒绝这个策略，MemTable 中它和 NoEviction 一样拒绝写入
+    TtlFirst,
+}
+
+/// 证书、私钥和 CA 可以直接写 PEM 内容，也可以是文件路径
+/// 使用文件路径时，服务器会定期检查文件，文件变化后新的连接使用新的证书
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ServerTlsConfig {
     pub cert: String,
@@ -49,6 +192,128 @@ pub struct ServerTlsConfig {
     pub ca: Option<String>,
 }
 
+/// 认证和权限配置，不配置的话所有客户端都可以访问所有的 table / topic
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct AuthConfig {
+    /// 可以通过 Auth 命令认证的用户
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    /// 权限规则，只要有一条规则允许，命令就可以执行
+    #[serde(default)]
+    pub rules: Vec<AclRule>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct UserConfig {
+    pub name: String,
+    pub token: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AclRule {
+    /// 用户名或者客户端证书的 CN，"*" 表示任何认证过的客户端
+    pub identity: String,
+    /// table 或者 topic 的 glob 模式，比如 "orders*"
+    pub pattern: String,
+    /// Read / Write 作用于 table，Subscribe / Publish / Admin 作用于 topic
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Permission {
+    Read,
+    Write,
+    Subscribe,
+    Publish,
+    /// 作用于 topic 的管理命令，比如 ListSubscriptions
+    Admin,
+}
+
+/// 多租户 namespace 配置，开启后 table / topic 的名字中不能包含 '/'
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct NamespaceConfig {
+    /// 客户端身份 -> namespace，绑定后客户端不能再 Select 其他的 namespace
+    #[serde(default)]
+    pub bindings: HashMap<String, String>,
+    /// 没有单独配置配额的 namespace 使用的配额
+    #[serde(default)]
+    pub default_quota: QuotaConfig,
+    /// 每个 namespace 单独的配额
+    #[serde(default)]
+    pub quotas: HashMap<String, QuotaConfig>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct QuotaConfig {
+    pub max_keys: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+/// Prometheus metrics 配置，配置后在 addr 上提供 /metrics
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MetricsConfig {
+    pub addr: String,
+}
+
+/// yamux 和 frame 的参数，不配置的项使用默认值
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// yamux 每个 stream 的接收窗口，不能小于 256KB
+    pub window_size: u32,
+    /// 每个连接上最多同时打开的 yamux stream 数，配置了 limits.max_streams_per_connection 时使用后者
+    pub max_streams: usize,
+    /// payload 超过这个大小时用 gzip 压缩
+    pub compression_threshold: usize,
+    /// 发送和接收的最大 frame（压缩后的大小），不能超过 2G，收到更大的 frame 时关闭 stream
+    pub max_frame_size: usize,
+    /// 收到的 frame 解压后的最大大小，超过时关闭 stream
+    pub max_decompressed_size: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            window_size: MIN_WINDOW_SIZE,
+            max_streams: 8192,
+            compression_threshold: crate::network::COMPRESSION_LIMIT,
+            max_frame_size: crate::network::FRAME_LIMIT,
+            max_decompressed_size: crate::network::FRAME_LIMIT,
+        }
+    }
+}
+
+/// yamux 要求的最小接收窗口
+const MIN_WINDOW_SIZE: u32 = 256 * 1024;
+
+/// 连接数和请求速率的限制，不配置的项不做限制
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct LimitConfig {
+    /// 每个 IP 的最大连接数
+    pub max_connections_per_ip: Option<usize>,
+    /// 每个客户端身份的最大连接数
+    pub max_connections_per_identity: Option<usize>,
+    /// 每个连接上最多同时打开的 yamux stream 数
+    pub max_streams_per_connection: Option<usize>,
+    /// 每个客户端的命令速率限制
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RateLimitConfig {
+    /// 每秒允许的命令数
+    pub rate: u32,
+    /// 允许的突发命令数，默认等于 rate
+    pub burst: Option<u32>,
+}
+
+impl RateLimitConfig {
+    pub fn burst(&self) -> u32 {
+        self.burst.unwrap_or(self.rate)
+    }
+}
+
+/// 和 ServerTlsConfig 一样，证书、私钥和 CA 可以是 PEM 内容，也可以是文件路径
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ClientTlsConfig {
     pub domain: String,
@@ -56,14 +321,318 @@ pub struct ClientTlsConfig {
     pub ca: Option<String>,
 }
 
+/// 指定服务器配置文件的环境变量
+pub const CONFIG_ENV: &str = "KV_SERVER_CONFIG";
+
+/// 覆盖单个配置项的环境变量的前缀
+const ENV_PREFIX: &str = "KV_";
+
+/// 覆盖配置文件中的一个配置项
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigOverride {
+    /// 用 '.' 分隔的路径，比如 general.addr
+    pub key: String,
+    pub value: toml::Value,
+    /// 这个配置的来源，比如 KV_GENERAL__ADDR 或者 --addr，用于错误信息
+    pub source: String,
+}
+
+impl ConfigOverride {
+    pub fn new(
+        key: impl Into<String>,
+        value: impl Into<toml::Value>,
+        source: impl Into<String>,
+    ) -> Self {
+        Self {
+            key: key.into(),
+            value: value.into(),
+            source: source.into(),
+        }
+    }
+
+    /// 从 KV_* 环境变量中获取要覆盖的配置，'__' 分隔路径，比如 KV_GENERAL__ADDR 覆盖 general.addr
+    /// 值按照 TOML 解析（比如数字、inline table），解析失败时作为字符串
+    ///
+    /// 所有的配置项都在某个 table 中，名字里没有 '__' 的变量不是配置，直接忽略
+    /// 比如 Kubernetes 为名为 kv 的 service 注入的 KV_PORT、KV_SERVICE_HOST
+    pub fn from_env(vars: impl IntoIterator<Item = (String, String)>) -> Vec<Self> {
+        let mut result: Vec<_> = vars
+            .into_iter()
+            .filter(|(name, _)| name != CONFIG_ENV)
+            .filter_map(|(name, raw)| {
+                let path = name.strip_prefix(ENV_PREFIX).filter(|path| path.contains("__"))?;
+                let key = path.to_lowercase().replace("__", ".");
+                let value = toml::from_str::<HashMap<String, toml::Value>>(&format!("v = {}", raw))
+                    .ok()
+                    .and_then(|mut v| v.remove("v"))
+                    .unwrap_or(toml::Value::String(raw));
+                Some(Self::new(key, value, name))
+            })
+            .collect();
+        // 环境变量的顺序是不确定的，排序后覆盖的结果才是确定的
+        result.sort_by(|a, b| a.key.cmp(&b.key));
+        result
+    }
+
+    /// 在 TOML 中设置这个配置项，中间缺少的 table 会被创建
+    fn apply(&self, root: &mut toml::Value) -> Result<(), KvError> {
+        let mut parts: Vec<_> = self.key.split('.').collect();
+        let last = parts.pop().unwrap_or_default();
+        let mut table = root;
+        for part in parts {
+            table = table
+                .as_table_mut()
+                .ok_or_else(|| self.invalid())?
+                .entry(part)
+                .or_insert_with(|| toml::Value::Table(Default::default()));
+        }
+        table
+            .as_table_mut()
+            .ok_or_else(|| self.invalid())?
+            .insert(last.into(), self.value.clone());
+        Ok(())
+    }
+
+    fn exists(&self, root: &toml::Value) -> bool {
+        self.key
+            .split('.')
+            .try_fold(root, |value, part| value.get(part))
+            .is_some()
+    }
+
+    fn invalid(&self) -> KvError {
+        KvError::InvalidConfig(format!("unknown config {} from {}", self.key, self.source))
+    }
+}
+
+/// 配置中的一个问题
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigProblem {
+    /// 出问题的配置项在 TOML 中的路径，比如 tls.cert
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
 impl ServerConfig {
 
     pub fn load(path: &str) -> Result<Self, KvError> {
+        Self::load_with_overrides(path, &[])
+    }
+
+    /// 读取配置文件，然后按顺序用 overrides 覆盖其中的配置项，后面的优先级更高
+    pub fn load_with_overrides(path: &str, overrides: &[ConfigOverride]) -> Result<Self, KvError> {
         let config = fs::read_to_string(path)?;
-        let config: Self = toml::from_str(&config)?;
-        Ok(config)
+        Self::parse_with_overrides(&config, overrides)
+    }
+
+    /// 解析 TOML 格式的配置，然后按顺序用 overrides 覆盖其中的配置项
+    pub fn parse_with_overrides(config: &str, overrides: &[ConfigOverride]) -> Result<Self, KvError> {
+        let mut value: toml::Value = toml::from_str(config)?;
+        for o in overrides {
+            o.apply(&mut value)?;
+        }
+        let config: Self = value.try_into()?;
+
+        // 不认识的配置项在解析时会被忽略，这里确保覆盖的配置项都生效了，避免拼写错误
+        let merged = config.to_toml()?;
+        match overrides.iter().find(|o| !o.exists(&merged)) {
+            Some(o) => Err(o.invalid()),
+            None => Ok(config),
+        }
+    }
+
+    /// 转换成 TOML 格式
+    pub fn to_toml(&self) -> Result<toml::Value, KvError> {
+        toml::Value::try_from(self).map_err(|e| KvError::InvalidConfig(e.to_string()))
+    }
+
+    /// 隐藏了 token 和私钥的配置，用于打印
+    pub fn redacted(&self) -> Self {
+        const REDACTED: &str = "<redacted>";
+        let mut config = self.clone();
+        if config.tls.key.contains("PRIVATE KEY") {
+            config.tls.key = REDACTED.into();
+        }
+        if let Some(auth) = config.auth.as_mut() {
+            for user in auth.users.iter_mut() {
+                user.token = REDACTED.into();
+            }
+        }
+        config
+    }
+
+    /// 和新的配置相比，哪些配置项改变了但是需要重启才能生效
+    ///
+    /// TLS 证书、认证、限制的数值和日志的过滤规则可以在运行时更新，其他的配置都需要重启
+    pub fn restart_required(&self, new: &Self) -> Vec<&'static str> {
+        let checks = [
+            ("general", self.general != new.general),
+            ("storage", self.storage != new.storage),
+            ("log.path", self.log.path != new.log.path),
+            ("log.rotation", self.log.rotation != new.log.rotation),
+            ("network", self.network != new.network),
+            ("namespace", self.namespace != new.namespace),
+            ("metrics", self.metrics != new.metrics),
+            // 限制需要在启动时开启，之后只能更新数值
+            ("limits", self.limits.is_none() && new.limits.is_some()),
+            ("memory", self.memory != new.memory),
+            ("keyspace", self.keyspace != new.keyspace),
+            ("durable", self.durable != new.durable),
+            ("groups", self.groups != new.groups),
+            ("backpressure", self.backpressure != new.backpressure),
+        ];
+        checks
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| name)
+            .collect()
     }
 
+    /// 检查所有的配置项，比如地址、证书、目录和 pattern，返回发现的所有问题
+    pub fn problems(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        let mut check = |key: &str, result: Result<(), String>| {
+            if let Err(message) = result {
+                problems.push(ConfigProblem {
+                    key: key.into(),
+                    message,
+                });
+            }
+        };
+        let positive = |value: u64| match value {
+            0 => Err("must be greater than 0".to_string()),
+            _ => Ok(()),
+        };
+
+        check("general.addr", check_addr(&self.general.addr));
+        if let StorageConfig::SledDb(path) = &self.storage {
+            // sled 会自己创建目录
+            check("storage.args", check_dir(path, true));
+        }
+        for (key, message) in crate::network::verify_server_tls(&self.tls) {
+            check(key, Err(message));
+        }
+        check("log.path", check_dir(&self.log.path, false));
+
+        if self.network.window_size < MIN_WINDOW_SIZE {
+            check("network.window_size", Err(format!("must be at least {}", MIN_WINDOW_SIZE)));
+        }
+        check("network.max_streams", positive(self.network.max_streams as u64));
+        let max_frame_size = self.network.max_frame_size;
+        if max_frame_size > crate::network::MAX_FRAME {
+            let message = format!("must be at most {}", crate::network::MAX_FRAME);
+            check("network.max_frame_size", Err(message));
+        } else {
+            check("network.max_frame_size", positive(max_frame_size as u64));
+        }
+        check(
+            "network.max_decompressed_size",
+            positive(self.network.max_decompressed_size as u64),
+        );
+
+        if let Some(auth) = &self.auth {
+            let mut tokens = HashSet::new();
+            for (i, user) in auth.users.iter().enumerate() {
+                let key = format!("auth.users.{}.token", i);
+                if user.token.is_empty() {
+                    check(&key, Err(format!("token of {} is empty", user.name)));
+                } else if !tokens.insert(&user.token) {
+                    check(&key, Err(format!("token of {} is used by another user", user.name)));
+                }
+            }
+            for (i, rule) in auth.rules.iter().enumerate() {
+                check(&format!("auth.rules.{}.pattern", i), check_pattern(&rule.pattern));
+            }
+        }
+        if let Some(metrics) = &self.metrics {
+            check("metrics.addr", check_addr(&metrics.addr));
+        }
+        if let Some(rate_limit) = self.limits.as_ref().and_then(|l| l.rate_limit.as_ref()) {
+            check("limits.rate_limit.rate", positive(rate_limit.rate.into()));
+            if let Some(burst) = rate_limit.burst {
+                check("limits.rate_limit.burst", positive(burst.into()));
+            }
+        }
+        if let Some(memory) = &self.memory {
+            check("memory.max_memory", positive(memory.max_memory));
+            if memory.policy == EvictionPolicy::TtlFirst {
+                check(
+                    "memory.policy",
+                    Err("TtlFirst is not supported, keys have no TTL".into()),
+                );
+            }
+        }
+        if let Some(keyspace) = &self.keyspace {
+            for (i, table) in keyspace.tables.iter().enumerate() {
+                check(&format!("keyspace.tables.{}", i), check_pattern(table));
+            }
+        }
+        if let Some(durable) = &self.durable {
+            for (i, topic) in durable.topics.iter().enumerate() {
+                check(&format!("durable.topics.{}", i), check_pattern(topic));
+            }
+            check("durable.max_messages", positive(durable.max_messages));
+        }
+        if let Some(groups) = &self.groups {
+            check("groups.ack_timeout_ms", positive(groups.ack_timeout_ms));
+        }
+        if let Some(backpressure) = &self.backpressure {
+            for (i, topic) in backpressure.topics.iter().enumerate() {
+                check(&format!("backpressure.topics.{}.topic", i), check_pattern(&topic.topic));
+            }
+        }
+
+        problems
+    }
+
+    /// 检查所有的配置项，有问题时返回的错误中包含所有的问题
+    pub fn validate(&self) -> Result<(), KvError> {
+        let problems: Vec<_> = self.problems().iter().map(|p| p.to_string()).collect();
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(KvError::InvalidConfig(problems.join("; "))),
+        }
+    }
+}
+
+fn check_addr(addr: &str) -> Result<(), String> {
+    addr.to_socket_addrs()
+        .map(|_| ())
+        .map_err(|e| format!("invalid address {}: {}", addr, e))
+}
+
+fn check_pattern(pattern: &str) -> Result<(), String> {
+    Pattern::new(pattern)
+        .map(|_| ())
+        .map_err(|e| format!("invalid pattern {}: {}", pattern, e))
+}
+
+/// 检查目录是否可写，create 为 true 时目录可以不存在，但是需要能被创建
+fn check_dir(path: &str, create: bool) -> Result<(), String> {
+    let path = Path::new(path);
+    let dir = match fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => path,
+        Ok(_) => return Err(format!("{} is not a directory", path.display())),
+        Err(_) if !create => return Err(format!("directory {} does not exist", path.display())),
+        Err(_) => path
+            .ancestors()
+            .skip(1)
+            .map(|p| if p.as_os_str().is_empty() { Path::new(".") } else { p })
+            .find(|p| p.is_dir())
+            .ok_or_else(|| format!("cannot create directory {}", path.display()))?,
+    };
+
+    // 权限位说明不了一切（比如 root 用户、只读的文件系统），直接写一个文件试试
+    let probe = dir.join(format!(".kv-write-check-{}", std::process::id()));
+    fs::write(&probe, b"").map_err(|e| format!("cannot write to {}: {}", dir.display(), e))?;
+    let _ = fs::remove_file(probe);
+    Ok(())
 }
 
 impl ClientConfig {
@@ -79,14 +648,128 @@ impl ClientConfig {
 mod tests {
 
     use super::*;
+    use crate::network::tls_utils::self_signed_cert;
 
     #[test]
     fn server_config_should_be_loaded() {
         let result: Result<ServerConfig, toml::de::Error> =
             toml::from_str(include_str!("../fixtures/server.conf"));
         assert!(result.is_ok());
+        // 没有 [network] 时使用默认值
+        assert_eq!(result.unwrap().network, NetworkConfig::default());
+    }
+
+
+    #[test]
+    fn restart_required_should_skip_live_settings() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        let mut new = config.clone();
+        new.tls.cert = "new cert".into();
+        new.log.filter = Some("debug".into());
+        new.auth = Some(AuthConfig::default());
+        assert!(config.restart_required(&new).is_empty());
+
+        new.general.addr = "0.0.0.0:9528".into();
+        new.limits = Some(LimitConfig::default());
+        assert_eq!(config.restart_required(&new), vec!["general", "limits"]);
+    }
+
+    #[test]
+    fn overrides_should_take_precedence() {
+        let env = vec![
+            ("KV_GENERAL__ADDR".to_string(), "0.0.0.0:9528".to_string()),
+            ("KV_LIMITS__MAX_CONNECTIONS_PER_IP".to_string(), "10".to_string()),
+            ("KV_SERVER_CONFIG".to_string(), "server.conf".to_string()),
+            ("PATH".to_string(), "/usr/bin".to_string()),
+            // Kubernetes 注入的 service 变量不是配置
+            ("KV_PORT".to_string(), "tcp://10.0.0.1:9527".to_string()),
+            ("KV_SERVICE_HOST".to_string(), "10.0.0.1".to_string()),
+        ];
+        let mut overrides = ConfigOverride::from_env(env);
+        assert_eq!(overrides.len(), 2);
+        // 命令行参数在环境变量之后，优先级更高
+        overrides.push(ConfigOverride::new("general.addr", "0.0.0.0:9529", "--addr"));
+        let storage = toml::Value::try_from(StorageConfig::from_str("memtable").unwrap()).unwrap();
+        overrides.push(ConfigOverride::new("storage", storage, "--storage"));
+
+        let config = include_str!("../fixtures/server.conf");
+        let config = ServerConfig::parse_with_overrides(config, &overrides).unwrap();
+        assert_eq!(config.general.addr, "0.0.0.0:9529");
+        assert_eq!(config.storage, StorageConfig::MemTable);
+        assert_eq!(config.limits.unwrap().max_connections_per_ip, Some(10));
+    }
+
+    #[test]
+    fn unknown_override_should_be_rejected() {
+        let overrides = ConfigOverride::from_env(vec![("KV_GENERAL__ADR".into(), "x".into())]);
+        let config = include_str!("../fixtures/server.conf");
+        let result = ServerConfig::parse_with_overrides(config, &overrides);
+        assert!(matches!(result, Err(KvError::InvalidConfig(_))));
+        assert!("sleddb:".parse::<StorageConfig>().is_err());
+    }
+
+    #[test]
+    fn validate_should_report_all_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        config.log.path = path("");
+        config.storage = StorageConfig::SledDb(path("db/kv"));
+        // 不依赖 fixtures 中证书的真实过期时间
+        let (cert, key) = self_signed_cert(2000, 3000);
+        config.tls = ServerTlsConfig {
+            cert: cert.clone(),
+            key,
+            ca: Some(cert),
+        };
+        assert_eq!(config.problems(), vec![]);
+        assert!(config.validate().is_ok());
+
+        config.general.addr = "127.0.0.1:95270".into();
+        config.tls.key = "fixtures/not-exist.key".into();
+        config.log.path = path("log");
+        config.keyspace = Some(KeyspaceConfig {
+            tables: vec!["t1".into(), "[".into()],
+            ..Default::default()
+        });
+        config.memory = Some(MemoryConfig {
+            max_memory: 1024,
+            policy: EvictionPolicy::TtlFirst,
+        });
+        config.durable = Some(DurableConfig {
+            topics: vec!["orders".into()],
+            max_messages: 0,
+        });
+        let keys: Vec<_> = config.problems().into_iter().map(|p| p.key).collect();
+        assert_eq!(
+            keys,
+            [
+                "general.addr",
+                "tls.key",
+                "log.path",
+                "memory.policy",
+                "keyspace.tables.1",
+                "durable.max_messages"
+            ]
+        );
+        assert!(matches!(config.validate(), Err(KvError::InvalidConfig(_))));
     }
 
+    #[test]
+    fn max_frame_size_should_be_checked() {
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        let has_problem = |config: &ServerConfig| {
+            config.problems().iter().any(|p| p.key == "network.max_frame_size")
+        };
+        config.network.max_frame_size = crate::network::MAX_FRAME;
+        assert!(!has_problem(&config));
+        config.network.max_frame_size = 0;
+        assert!(has_problem(&config));
+        config.network.max_frame_size = crate::network::MAX_FRAME + 1;
+        assert!(has_problem(&config));
+    }
 
     #[test]
     fn client_config_should_be_loaded() {
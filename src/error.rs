@@ -1,4 +1,4 @@
-use crate::Value;
+use crate::ErrorCode;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -10,9 +10,9 @@ pub enum KvError {
     #[error("Command is invalid: `{0}`")]
     InvalidCommand(String),
     #[error("Cannot convert value {0} to {1}")]
-    ConvertError(String, &'static str),
-    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {}")]
-    StorageError(&'static str, String, String, String),
+    ConvertError(String, String),
+    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {3}")]
+    StorageError(String, String, String, String),
     #[error("Certificate parse error: error to load {0} {0}")]
     CertifcateParseError(&'static str, &'static str),
 
@@ -30,7 +30,167 @@ pub enum KvError {
     YamuxConnectionError(#[from] yamux::ConnectionError),
     #[error("Parse config error")]
     ConfigError(#[from] toml::de::Error),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("Request timeout: {0}")]
+    Timeout(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Too many requests: {0}")]
+    RateLimited(String),
+    #[error("Out of memory: {0}")]
+    OutOfMemory(String),
 
     #[error("Internal error: {0}")]
     Internal(String),
-}
\ No newline at end of file
+    #[error("Server error: {1}")]
+    Remote(ErrorCode, String),
+}
+
+impl KvError {
+    /// 获取 error 对应的 ErrorCode，用于在 CommandResponse 中传给客户端
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvError::NotFound(_) => ErrorCode::NotFound,
+            KvError::InvalidCommand(_) => ErrorCode::InvalidCommand,
+            KvError::ConvertError(_, _) => ErrorCode::ConvertError,
+            KvError::StorageError(..) | KvError::SledError(_) => ErrorCode::StorageError,
+            KvError::FrameError => ErrorCode::FrameError,
+            KvError::Timeout(_) => ErrorCode::Timeout,
+            KvError::PermissionDenied(_) => ErrorCode::PermissionDenied,
+            KvError::QuotaExceeded(_) => ErrorCode::QuotaExceeded,
+            KvError::RateLimited(_) => ErrorCode::TooManyRequests,
+            KvError::OutOfMemory(_) => ErrorCode::OutOfMemory,
+            KvError::Remote(code, _) => *code,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// 从服务器返回的 ErrorCode 和 message 还原出 KvError
+    /// 对于没法在客户端重建的 error（比如 sled::Error、io::Error），使用 KvError::Remote
+    pub fn from_remote(code: ErrorCode, msg: String) -> Self {
+        match code {
+            ErrorCode::NotFound => KvError::NotFound(strip_message(msg, "Not found: ", "")),
+            ErrorCode::InvalidCommand => {
+                KvError::InvalidCommand(strip_message(msg, "Command is invalid: `", "`"))
+            }
+            ErrorCode::ConvertError => match parse_convert_error(&msg) {
+                Some((value, target)) => KvError::ConvertError(value, target),
+                None => KvError::Remote(code, msg),
+            },
+            ErrorCode::StorageError => match parse_storage_error(&msg) {
+                Some((cmd, table, key, err)) => KvError::StorageError(cmd, table, key, err),
+                None => KvError::Remote(code, msg),
+            },
+            ErrorCode::FrameError => KvError::FrameError,
+            ErrorCode::Timeout => KvError::Timeout(strip_message(msg, "Request timeout: ", "")),
+            ErrorCode::PermissionDenied => {
+                KvError::PermissionDenied(strip_message(msg, "Permission denied: ", ""))
+            }
+            ErrorCode::QuotaExceeded => {
+                KvError::QuotaExceeded(strip_message(msg, "Quota exceeded: ", ""))
+            }
+            ErrorCode::TooManyRequests => {
+                KvError::RateLimited(strip_message(msg, "Too many requests: ", ""))
+            }
+            ErrorCode::OutOfMemory => {
+                KvError::OutOfMemory(strip_message(msg, "Out of memory: ", ""))
+            }
+            ErrorCode::Internal => KvError::Internal(strip_message(msg, "Internal error: ", "")),
+            code => KvError::Remote(code, msg),
+        }
+    }
+}
+
+/// 去掉 Display 时加上的前后缀，拿回原始的错误信息
+fn strip_message(msg: String, prefix: &str, suffix: &str) -> String {
+    match msg.strip_prefix(prefix).and_then(|s| s.strip_suffix(suffix)) {
+        Some(s) => s.to_owned(),
+        None => msg,
+    }
+}
+
+/// 解析 "Cannot convert value {0} to {1}"
+fn parse_convert_error(msg: &str) -> Option<(String, String)> {
+    let (value, target) = msg
+        .strip_prefix("Cannot convert value ")?
+        .rsplit_once(" to ")?;
+    Some((value.to_owned(), target.to_owned()))
+}
+
+/// 解析 "Cannot process command {0} with table: {1}, key: {2}. Error: {3}"
+fn parse_storage_error(msg: &str) -> Option<(String, String, String, String)> {
+    let rest = msg.strip_prefix("Cannot process command ")?;
+    let (cmd, rest) = rest.split_once(" with table: ")?;
+    let (table, rest) = rest.split_once(", key: ")?;
+    let (key, err) = rest.split_once(". Error: ")?;
+    Some((cmd.to_owned(), table.to_owned(), key.to_owned(), err.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, CommandResponse};
+    use prost::Message;
+    use std::mem::discriminant;
+
+    fn round_trip(e: KvError) -> KvError {
+        let res: CommandResponse = e.into();
+        res.into_result().unwrap_err()
+    }
+
+    #[test]
+    fn errors_with_own_code_should_round_trip() {
+        let errors = vec![
+            KvError::NotFound("t:k".into()),
+            KvError::InvalidCommand("bad".into()),
+            KvError::ConvertError("1.5".into(), "Integer".into()),
+            KvError::StorageError("hget".into(), "t".into(), "k".into(), "io".into()),
+            KvError::FrameError,
+            KvError::Timeout("deadline".into()),
+            KvError::PermissionDenied("t".into()),
+            KvError::QuotaExceeded("ns".into()),
+            KvError::RateLimited("user".into()),
+            KvError::OutOfMemory("full".into()),
+            KvError::Internal("oops".into()),
+        ];
+        for e in errors {
+            let msg = e.to_string();
+            let d = discriminant(&e);
+            let got = round_trip(e);
+            assert_eq!(discriminant(&got), d, "{}", msg);
+            assert_eq!(got.to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn errors_without_own_code_should_keep_code_and_message() {
+        let io = std::io::Error::other("io");
+        let sled = sled::Error::Unsupported("sled".into());
+        let encode_error = CommandRequest::new_hget("t", "k")
+            .encode(&mut &mut [0u8; 0][..])
+            .unwrap_err();
+        let errors = vec![
+            KvError::CertifcateParseError("server", "cert"),
+            KvError::EncodeError(encode_error),
+            KvError::DecodeError(prost::DecodeError::new("bad")),
+            KvError::SledError(sled),
+            KvError::IoError(io),
+            KvError::TlsError(tokio_rustls::rustls::TLSError::HandshakeNotComplete),
+            KvError::YamuxConnectionError(yamux::ConnectionError::Closed),
+            KvError::ConfigError(toml::from_str::<toml::Value>("=").unwrap_err()),
+            KvError::InvalidConfig("addr".into()),
+            KvError::Remote(ErrorCode::NotFound, "remote".into()),
+        ];
+        for e in errors {
+            let code = e.code();
+            let msg = e.to_string();
+            let got = round_trip(e);
+            assert_eq!(got.code(), code);
+            assert!(got.to_string().contains(&msg) || msg.contains(&got.to_string()));
+        }
+    }
+}
@@ -2,15 +2,18 @@ extern crate core;
 
 mod error;
 mod config;
+mod metrics;
 mod network;
 mod pb;
 mod service;
 mod storage;
 
 
+use std::sync::Arc;
 use std::time::Duration;
 pub use error::KvError;
 pub use config::*;
+pub use metrics::start_metrics_server;
 pub use network::*;
 pub use pb::abi::*;
 pub use service::*;
@@ -18,24 +21,72 @@ pub use storage::*;
 
 use anyhow::Result;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::time;
+use tokio::sync::{mpsc, oneshot};
 use tokio_rustls::client;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tracing::{info, instrument, span};
+use tracing::{info, instrument, span, warn};
+use metrics::GaugeGuard;
 
 
 #[instrument(skip_all)]
 pub async fn start_server_with_config(config: &ServerConfig) -> Result<()> {
+    let listener = TcpListener::bind(&config.general.addr).await?;
+    start_server(config, listener, None).await
+}
+
+/// 在已经 bind 好的 listener 上启动服务器，忽略配置中的 general.addr
+#[instrument(skip_all)]
+pub async fn start_server_with_listener(config: &ServerConfig, listener: TcpListener) -> Result<()> {
+    start_server(config, listener, None).await
+}
+
+/// 运行时重新加载的配置，服务器处理完之后通过 applied 返回新的配置是否生效
+#[derive(Debug)]
+pub struct ConfigReload {
+    pub config: ServerConfig,
+    pub applied: oneshot::Sender<Result<(), KvError>>,
+}
+
+/// 启动服务器，每次从 reload 收到新的配置时，更新可以在运行时更新的配置
+#[instrument(skip_all)]
+pub async fn start_server_with_reload(
+    config: &ServerConfig,
+    reload: mpsc::Receiver<ConfigReload>,
+) -> Result<()> {
+    let listener = TcpListener::bind(&config.general.addr).await?;
+    start_server(config, listener, Some(reload)).await
+}
+
+async fn start_server(
+    config: &ServerConfig,
+    listener: TcpListener,
+    reload: Option<mpsc::Receiver<ConfigReload>>,
+) -> Result<()> {
     let acceptor = TlsServerAcceptor::new(
         &config.tls.cert,
         &config.tls.key,
         config.tls.ca.as_deref(),
     )?;
+    // 证书是文件路径时，文件变化后自动重新加载
+    acceptor.watch(CERT_WATCH_INTERVAL);
 
-    let addr = &config.general.addr;
+    let namespaces = config.namespace.clone().map(|c| Arc::new(Namespaces::new(c)));
     match &config.storage {
-        StorageConfig::MemTable => start_tls_server(addr, MemTable::new(), acceptor).await?,
-        StorageConfig::SledDb(path) => start_tls_server(addr, SledDb::new(path), acceptor).await?,
+        StorageConfig::MemTable => {
+            let mut store = match &config.memory {
+                Some(memory) => MemTable::with_limit(memory.clone()),
+                None => MemTable::new(),
+            };
+            // 被淘汰的 key 不再计入 namespace 的用量
+            if let Some(namespaces) = namespaces.clone() {
+                store = store.on_evict(move |table, size| namespaces.release(table, size));
+            }
+            start_tls_server(config, store, namespaces, listener, acceptor, reload).await?
+        }
+        StorageConfig::SledDb(path) => {
+            let store = SledDb::new(path);
+            start_tls_server(config, store, namespaces, listener, acceptor, reload).await?
+        }
     };
 
     Ok(())
@@ -53,18 +104,57 @@ pub async fn start_client_with_config(
     let stream = TcpStream::connect(addr).await?;
     let stream = connector.connect(stream).await?;
 
-    Ok(YamuxCtrl::new_client(stream, None))
+    Ok(YamuxCtrl::new_client(stream, Some(config.network.clone())))
 }
 
 
 async fn start_tls_server<Store: Storage>(
-    addr: &str,
+    config: &ServerConfig,
     store: Store,
+    namespaces: Option<Arc<Namespaces>>,
+    listener: TcpListener,
     acceptor: TlsServerAcceptor,
+    reload: Option<mpsc::Receiver<ConfigReload>>,
 ) -> Result<()> {
-    let service: Service<Store> = ServiceInner::new(store).into();
-    let listener = TcpListener::bind(addr).await?;
-    info!("Start listening on {}", addr);
+    let mut inner = ServiceInner::new(store);
+    if let Some(auth) = &config.auth {
+        inner = inner.acl(Acl::new(auth)?);
+    }
+    if let Some(namespaces) = namespaces {
+        inner = inner.namespaces(namespaces);
+    }
+    if let Some(limits) = &config.limits {
+        inner = inner.limits(Limits::new(limits.clone()));
+    }
+    if let Some(keyspace) = &config.keyspace {
+        inner = inner.keyspace(Keyspace::new(keyspace)?);
+    }
+    if let Some(durable) = &config.durable {
+        inner = inner.durable_topics(durable)?;
+    }
+    if let Some(backpressure) = &config.backpressure {
+        inner = inner.backpressure(backpressure)?;
+    }
+    if let Some(groups) = &config.groups {
+        inner = inner.ack_timeout(Duration::from_millis(groups.ack_timeout_ms));
+    }
+    let service: Service<Store> = inner.into();
+
+    if let Some(metrics) = &config.metrics {
+        let addr = metrics.addr.clone();
+        let svc = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = start_metrics_server(&addr, svc).await {
+                warn!("Metrics server exited: {:?}", e);
+            }
+        });
+    }
+
+    if let Some(reload) = reload {
+        tokio::spawn(reload_config(config.clone(), reload, acceptor.clone(), service.clone()));
+    }
+
+    info!("Start listening on {}", listener.local_addr()?);
     loop {
         let root = span!(tracing::Level::INFO, "server_process");
         let _enter = root.enter();
@@ -73,18 +163,118 @@ async fn start_tls_server<Store: Storage>(
         let (stream, addr) = listener.accept().await?;
         info!("Client {:?} connected", addr);
 
+        // 超过 IP 连接数限制的连接直接断开
+        let ip_permit = match service.limits().map(|l| l.acquire_ip(addr.ip())).transpose() {
+            Ok(permit) => permit,
+            Err(e) => {
+                warn!("Reject client {:?}: {:?}", addr, e);
+                continue;
+            }
+        };
+
         let svc = service.clone();
+        let network = config.network.clone();
         tokio::spawn(async move {
             let stream = tls.accept(stream).await.unwrap();
-            YamuxCtrl::new_server(stream, None, move |stream| {
+            // 连接上所有的 yamux stream 共享同一个 session
+            let session = match svc.create_session(Some(addr), client_identity(&stream)) {
+                Ok(session) => session,
+                Err(e) => {
+                    warn!("Reject client {:?}: {:?}", addr, e);
+                    return;
+                }
+            };
+
+            let mut config = network.clone();
+            if let Some(max) = svc.limits().and_then(|limits| limits.max_streams()) {
+                config.max_streams = max;
+            }
+            let stream_config = config.clone();
+
+            // guard 属于下面的闭包，连接断开时闭包被释放，连接数随之减一
+            let conn_guard = (GaugeGuard::new(&metrics::CONNECTIONS), ip_permit);
+            let closed = session.clone();
+            let on_close = move || {
+                info!("Client {:?} disconnected", addr);
+                closed.close();
+            };
+            YamuxCtrl::new_server_with_close(stream, Some(config), move |stream| {
+                let _conn_guard = &conn_guard;
                 let svc1 = svc.clone();
+                let session = session.clone();
+                let config = stream_config.clone();
                 async move {
-                    let stream = ProstServerStream::new(stream.compat(), svc1.clone());
+                    let _stream_guard = GaugeGuard::new(&metrics::STREAMS);
+                    let stream = ProstServerStream::new(stream.compat(), svc1.clone())
+                        .with_session(session)
+                        .with_config(config);
                     // time::sleep(Duration::from_millis(100)).await;
-                    stream.process().await.unwrap();
+                    if let Err(e) = stream.process().await {
+                        warn!("Stream of client {:?} exited: {:?}", addr, e);
+                    }
                     Ok(())
                 }
-            });
+            }, on_close);
         });
     }
-}
\ No newline at end of file
+}
+
+/// 收到新的配置时，更新 TLS 证书、认证和限制，需要重启才能生效的配置只打印警告
+///
+/// 新的配置中有不合法的部分时，整个配置都不会生效，applied 中返回错误
+async fn reload_config<Store: Storage>(
+    mut running: ServerConfig,
+    mut configs: mpsc::Receiver<ConfigReload>,
+    acceptor: TlsServerAcceptor,
+    service: Service<Store>,
+) {
+    while let Some(ConfigReload { config: new, applied }) = configs.recv().await {
+        let result = apply_config(&mut running, &new, &acceptor, &service);
+        match &result {
+            Ok(_) => {
+                let pending = running.restart_required(&new);
+                if !pending.is_empty() {
+                    warn!("Config {} changed, restart kvs to apply", pending.join(", "));
+                }
+            }
+            Err(e) => warn!("Ignore invalid new config: {:?}", e),
+        }
+        // 发送配置的一方可能不关心结果
+        let _ = applied.send(result);
+    }
+}
+
+/// 更新可以在运行时更新的配置，在修改任何配置之前检查新的认证和 TLS 配置
+fn apply_config<Store: Storage>(
+    running: &mut ServerConfig,
+    new: &ServerConfig,
+    acceptor: &TlsServerAcceptor,
+    service: &Service<Store>,
+) -> Result<(), KvError> {
+    let acl = new.auth.as_ref().map(Acl::new).transpose()?;
+
+    if new.tls != running.tls {
+        let tls = &new.tls;
+        acceptor.reload(&tls.cert, &tls.key, tls.ca.as_deref())?;
+        info!("TLS config is reloaded, it applies to new connections");
+        running.tls = new.tls.clone();
+    }
+
+    if new.auth != running.auth {
+        service.set_acl(acl);
+        info!("Auth config is reloaded");
+        running.auth = new.auth.clone();
+    }
+
+    // 没有配置的限制项不做限制
+    if let Some(limits) = service.limits() {
+        let config = new.limits.clone().unwrap_or_default();
+        if running.limits.as_ref() != Some(&config) {
+            limits.update(config.clone());
+            info!("Limits are reloaded");
+            running.limits = Some(config);
+        }
+    }
+    running.log.filter = new.log.filter.clone();
+    Ok(())
+}
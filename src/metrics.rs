@@ -0,0 +1,196 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Encoder, Histogram,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+use crate::{command_request::RequestData, CommandRequest, KvError, Service, Storage};
+
+lazy_static! {
+    /// 每种命令处理的次数
+    pub static ref COMMANDS: IntCounterVec = register_int_counter_vec!(
+        "kv_commands_total",
+        "Number of commands executed",
+        &["command", "status"]
+    )
+    .unwrap();
+    /// 每种命令从收到到产生第一个 response 的时间
+    pub static ref COMMAND_LATENCY: HistogramVec = register_histogram_vec!(
+        "kv_command_duration_seconds",
+        "Latency of commands until the first response",
+        &["command"],
+        exponential_buckets(0.0001, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    /// 当前的连接数
+    pub static ref CONNECTIONS: IntGauge =
+        register_int_gauge!("kv_connections", "Number of active connections").unwrap();
+    /// 当前的 yamux stream 数
+    pub static ref STREAMS: IntGauge =
+        register_int_gauge!("kv_yamux_streams", "Number of active yamux streams").unwrap();
+    /// 当前的 topic 数
+    pub static ref TOPICS: IntGauge =
+        register_int_gauge!("kv_topics", "Number of topics with subscribers").unwrap();
+    /// 当前的订阅数
+    pub static ref SUBSCRIPTIONS: IntGauge =
+        register_int_gauge!("kv_subscriptions", "Number of active subscriptions").unwrap();
+    /// publish 时发送给订阅者失败的次数
+    pub static ref PUBLISH_FAILURES: IntCounter = register_int_counter!(
+        "kv_publish_failures_total",
+        "Number of messages failed to be delivered to subscribers"
+    )
+    .unwrap();
+    /// 订阅者太慢被丢弃的消息数
+    pub static ref PUBLISH_DROPPED: IntCounter = register_int_counter!(
+        "kv_publish_dropped_total",
+        "Number of messages dropped for slow subscribers"
+    )
+    .unwrap();
+    /// 压缩后的 frame 和原始大小的比例
+    pub static ref FRAME_COMPRESSION_RATIO: Histogram = register_histogram!(
+        "kv_frame_compression_ratio",
+        "Compressed size / original size of compressed frames",
+        vec![0.05, 0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0]
+    )
+    .unwrap();
+    /// storage 的大小
+    pub static ref STORAGE_SIZE: IntGaugeVec = register_int_gauge_vec!(
+        "kv_storage_size",
+        "Size of the storage",
+        &["unit"]
+    )
+    .unwrap();
+}
+
+/// 在 drop 时把 gauge 减一，用于统计连接和 stream 的数量
+pub struct GaugeGuard(&'static IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &'static IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// 命令的名字，作为 metrics 的 label
+pub fn command_name(cmd: &CommandRequest) -> &'static str {
+    match cmd.request_data {
+        Some(RequestData::Hget(_)) => "hget",
+        Some(RequestData::Hgetall(_)) => "hgetall",
+        Some(RequestData::Hmget(_)) => "hmget",
+        Some(RequestData::Hset(_)) => "hset",
+        Some(RequestData::Hmset(_)) => "hmset",
+        Some(RequestData::Hdel(_)) => "hdel",
+        Some(RequestData::Hmdel(_)) => "hmdel",
+        Some(RequestData::Hexist(_)) => "hexist",
+        Some(RequestData::Hmexist(_)) => "hmexist",
+        Some(RequestData::Subscribe(_)) => "subscribe",
+        Some(RequestData::Unsubscribe(_)) => "unsubscribe",
+        Some(RequestData::Publish(_)) => "publish",
+        Some(RequestData::Psubscribe(_)) => "psubscribe",
+        Some(RequestData::Punsubscribe(_)) => "punsubscribe",
+        Some(RequestData::Ack(_)) => "ack",
+        Some(RequestData::ListSubscriptions(_)) => "list_subscriptions",
+        Some(RequestData::ListTopics(_)) => "list_topics",
+        Some(RequestData::TopicInfo(_)) => "topic_info",
+        Some(RequestData::NumSub(_)) => "num_sub",
+        Some(RequestData::Auth(_)) => "auth",
+        Some(RequestData::Select(_)) => "select",
+        None => "unknown",
+    }
+}
+
+/// 把所有 metrics 转换成 Prometheus 的文本格式
+pub fn gather() -> Result<String, KvError> {
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .map_err(|e| KvError::Internal(e.to_string()))?;
+    String::from_utf8(buf).map_err(|e| KvError::Internal(e.to_string()))
+}
+
+/// 启动 metrics 的 HTTP 服务，在 /metrics 上提供 Prometheus 格式的数据
+pub async fn start_metrics_server<Store: Storage>(
+    addr: &str,
+    service: Service<Store>,
+) -> Result<(), KvError> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Metrics listening on {}", addr);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let svc = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(stream, svc).await {
+                warn!("Failed to serve metrics: {:?}", e);
+            }
+        });
+    }
+}
+
+/// 一个最简单的 HTTP/1.0 处理，只支持 GET /metrics
+async fn serve_metrics<Store: Storage>(
+    mut stream: TcpStream,
+    service: Service<Store>,
+) -> Result<(), KvError> {
+    let mut buf = vec![0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+
+    let (status, body) = if request.starts_with("GET /metrics ") {
+        service.collect_metrics().await;
+        ("200 OK", gather()?)
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    let header = format!(
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+        status,
+        TextEncoder::new().format_type(),
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, ServiceInner};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn metrics_server_should_work() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        res.next().await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        tokio::spawn(async move { start_metrics_server(&addr.to_string(), service).await });
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut body = String::new();
+        stream.read_to_string(&mut body).await.unwrap();
+
+        assert!(body.starts_with("HTTP/1.0 200 OK"));
+        assert!(body.contains(r#"kv_commands_total{command="hset",status="200"}"#));
+        assert!(body.contains(r#"kv_storage_size{unit="keys"} 1"#));
+    }
+}
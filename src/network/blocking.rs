@@ -0,0 +1,102 @@
+use std::sync::Arc;
+use std::time::Duration;
+use futures::StreamExt;
+use tokio::net::TcpStream;
+use tokio::runtime::{Builder, Runtime};
+use tokio_rustls::client;
+use tokio_util::compat::Compat;
+use tracing::instrument;
+
+use crate::{
+    ClientConfig, CommandRequest, CommandResponse, KvError, ProstClientStream, StreamResult,
+    TlsClientConnector, YamuxCtrl,
+};
+
+/// 同步（阻塞）的 KV client，给不想引入 tokio runtime 的调用者使用
+/// 内部自己驱动一个 runtime，底层依旧是 TLS + yamux + frame 协议
+pub struct BlockingClient {
+    rt: Arc<Runtime>,
+    ctrl: YamuxCtrl<client::TlsStream<TcpStream>>,
+    stream: ProstClientStream<Compat<yamux::Stream>>,
+    timeout: Option<Duration>,
+}
+
+/// 阻塞版本的 StreamResult，可以直接当 Iterator 使用
+pub struct BlockingStreamResult {
+    pub id: u64,
+    rt: Arc<Runtime>,
+    inner: StreamResult,
+}
+
+impl BlockingClient {
+    #[instrument(name = "blocking_client_connect", skip_all)]
+    /// 根据客户端配置连接服务器
+    pub fn connect(config: &ClientConfig) -> Result<Self, KvError> {
+        // yamux 的连接需要在后台持续被 poll，所以用一个单独的 worker 线程
+        let rt = Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("kv-blocking-client")
+            .enable_all()
+            .build()?;
+
+        let tls = &config.tls;
+        let identity = tls.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
+        let connector = TlsClientConnector::new(&tls.domain, identity, tls.ca.as_deref())?;
+
+        let (ctrl, stream) = rt.block_on(async {
+            let stream = TcpStream::connect(&config.general.addr).await?;
+            let stream = connector.connect(stream).await?;
+            let mut ctrl = YamuxCtrl::new_client(stream, Some(config.network.clone()));
+            let stream = ctrl.open_stream().await?;
+            Ok::<_, KvError>((ctrl, stream))
+        })?;
+
+        Ok(Self {
+            rt: Arc::new(rt),
+            ctrl,
+            stream,
+            timeout: None,
+        })
+    }
+
+    /// 设置每个命令的超时时间，参见 ProstClientStream::with_timeout
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.stream = self.stream.with_timeout(timeout);
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// 发送一个命令，阻塞等待唯一的 response
+    pub fn execute_unary(&mut self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        let stream = &mut self.stream;
+        self.rt.block_on(stream.execute_unary(cmd))
+    }
+
+    /// 发送一个流式命令（比如 subscribe），每个 streaming 命令使用一个新的 yamux stream
+    pub fn execute_streaming(&mut self, cmd: &CommandRequest) -> Result<BlockingStreamResult, KvError> {
+        let ctrl = &mut self.ctrl;
+        let timeout = self.timeout;
+        let inner = self.rt.block_on(async {
+            let mut stream = ctrl.open_stream().await?;
+            if let Some(timeout) = timeout {
+                stream = stream.with_timeout(timeout);
+            }
+            stream.execute_streaming(cmd).await
+        })?;
+
+        Ok(BlockingStreamResult {
+            id: inner.id,
+            rt: self.rt.clone(),
+            inner,
+        })
+    }
+}
+
+impl Iterator for BlockingStreamResult {
+    type Item = Result<CommandResponse, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let inner = &mut self.inner;
+        self.rt.block_on(inner.next())
+    }
+}
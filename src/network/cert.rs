@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::sync::Weak;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_rustls::rustls::internal::pemfile;
+use tracing::{error, info, warn};
+use x509_parser::parse_x509_certificate;
+
+use crate::KvError;
+
+/// 默认检查证书文件是否变化的间隔
+pub const CERT_WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 证书剩余的有效期少于这个时间时打印警告
+const EXPIRY_WARNING: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// 定期检查证书有效期的间隔
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// 配置中的证书、私钥可以直接是 PEM 内容，也可以是文件路径
+fn is_inline(source: &str) -> bool {
+    source.contains("-----BEGIN")
+}
+
+/// 获取 PEM 内容，source 是文件路径时读取文件
+pub(crate) fn load_pem(source: &str) -> Result<String, KvError> {
+    if is_inline(source) {
+        return Ok(source.to_owned());
+    }
+    Ok(fs::read_to_string(source)?)
+}
+
+/// 和 load_pem 一样，但是返回的错误信息中包含文件路径，用于检查配置
+fn read_pem(source: &str) -> Result<String, String> {
+    if is_inline(source) {
+        return Ok(source.to_owned());
+    }
+    fs::read_to_string(source).map_err(|e| format!("cannot read {}: {}", source, e))
+}
+
+/// 检查 PEM 中的证书都能解析，并且在有效期内
+pub(crate) fn verify_certs(source: &str) -> Result<(), String> {
+    let pem = read_pem(source)?;
+    let certs = pemfile::certs(&mut Cursor::new(&pem)).unwrap_or_default();
+    if certs.is_empty() {
+        return Err("no certificate found".into());
+    }
+    for cert in certs {
+        let (_, cert) =
+            parse_x509_certificate(&cert.0).map_err(|e| format!("invalid certificate: {}", e))?;
+        if cert.validity().time_to_expiration().is_none() {
+            return Err(format!(
+                "certificate {} is not valid now, expires at {}",
+                cert.subject(),
+                cert.validity().not_after.to_rfc2822()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 检查私钥能被加载
+pub(crate) fn verify_key(source: &str) -> Result<(), String> {
+    let pem = read_pem(source)?;
+    super::tls::load_key(&pem)
+        .map(|_| ())
+        .map_err(|_| "no PKCS8 or RSA private key found".into())
+}
+
+/// 打印 PEM 中所有证书的过期时间，快要过期或者已经失效时打印警告
+pub(crate) fn check_expiry(pem: &str) {
+    let certs = pemfile::certs(&mut Cursor::new(pem)).unwrap_or_default();
+    for cert in certs {
+        let cert = match parse_x509_certificate(&cert.0) {
+            Ok((_, cert)) => cert,
+            Err(_) => continue,
+        };
+        let subject = cert.subject().to_string();
+        let not_after = cert.validity().not_after.to_rfc2822();
+        match cert.validity().time_to_expiration() {
+            None => error!("Certificate {} is not valid now, expires at {}", subject, not_after),
+            Some(left) if left < EXPIRY_WARNING => {
+                warn!("Certificate {} expires soon at {}", subject, not_after)
+            }
+            Some(_) => info!("Certificate {} expires at {}", subject, not_after),
+        }
+    }
+}
+
+/// 定期检查 sources 中的文件，有变化时调用 reload，target 被释放后任务退出
+///
+/// 重新加载失败时（比如文件只写了一半），下一次检查时重试
+pub(crate) fn watch<T, S, R>(target: Weak<T>, interval: Duration, sources: S, reload: R) -> JoinHandle<()>
+where
+    T: Send + Sync + 'static,
+    S: Fn(&T) -> Vec<String> + Send + 'static,
+    R: Fn(&T) -> Result<(), KvError> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = time::interval(interval);
+        let mut modified: Option<HashMap<String, Option<SystemTime>>> = None;
+        let mut last_check = Instant::now();
+        loop {
+            interval.tick().await;
+            let target = match target.upgrade() {
+                Some(target) => target,
+                None => break,
+            };
+
+            let sources = sources(&target);
+            let current: HashMap<_, _> = sources
+                .iter()
+                .filter(|s| !is_inline(s))
+                .map(|path| {
+                    let time = fs::metadata(path).and_then(|m| m.modified()).ok();
+                    (path.clone(), time)
+                })
+                .collect();
+            match &modified {
+                Some(last) if *last != current => match reload(&target) {
+                    Ok(()) => {
+                        info!("Certificates are reloaded from files");
+                        modified = Some(current);
+                    }
+                    Err(e) => warn!("Failed to reload certificates: {:?}", e),
+                },
+                Some(_) => {}
+                None => modified = Some(current),
+            }
+
+            if last_check.elapsed() >= EXPIRY_CHECK_INTERVAL {
+                last_check = Instant::now();
+                for pem in sources.iter().filter_map(|s| load_pem(s).ok()) {
+                    check_expiry(&pem);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tls::tls_utils::self_signed_cert;
+
+    #[test]
+    fn load_pem_should_accept_inline_and_path() {
+        let pem = include_str!("../../fixtures/server.cert");
+        assert_eq!(load_pem(pem).unwrap(), pem);
+        assert_eq!(load_pem("fixtures/server.cert").unwrap(), pem);
+        assert!(load_pem("fixtures/not-exist.cert").is_err());
+    }
+
+    #[test]
+    fn verify_should_report_invalid_pem() {
+        let (cert, key) = self_signed_cert(2000, 3000);
+        assert!(verify_certs(&cert).is_ok());
+        assert!(verify_key(&key).is_ok());
+        let (expired, _) = self_signed_cert(2000, 2001);
+        assert!(verify_certs(&expired).unwrap_err().contains("not valid now"));
+        let (not_yet_valid, _) = self_signed_cert(2900, 3000);
+        assert!(verify_certs(&not_yet_valid).unwrap_err().contains("not valid now"));
+
+        assert!(verify_certs("fixtures/server.key").unwrap_err().contains("no certificate"));
+        assert!(verify_key("fixtures/server.cert").is_err());
+        assert!(verify_key("fixtures/not-exist.key").unwrap_err().contains("cannot read"));
+    }
+}
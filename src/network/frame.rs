@@ -1,5 +1,5 @@
 use std::io::{Read, Write};
-use crate::{CommandRequest, CommandResponse, KvError};
+use crate::{metrics, CommandRequest, CommandResponse, KvError, NetworkConfig};
 use bytes::{Buf, BufMut, BytesMut};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use tokio::io::{AsyncRead, AsyncReadExt};
@@ -10,10 +10,13 @@ use tracing::debug;
 pub const LEN_LEN: usize = 4;
 
 /// 长度占 31 bit，所以最大 frame 是 2G
-const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
+pub(crate) const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
 
-/// 如果 payload 超过了 1436 字节，就做压缩
-const COMPRESSION_LIMIT: usize = 1436;
+/// 默认 payload 超过了 1436 字节，就做压缩
+pub(crate) const COMPRESSION_LIMIT: usize = 1436;
+
+/// 默认 frame 压缩前和压缩后都不能超过 64M
+pub(crate) const FRAME_LIMIT: usize = 64 * 1024 * 1024;
 
 /// 代表压缩的 bit （整个长度 4 字节的最高位）
 const COMPRESSION_BIT: usize = 1 << 31;
@@ -24,41 +27,47 @@ pub trait FrameCoder
 {
     // 把一个 Message encode 变成一个 frame
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
+        self.encode_frame_with(buf, &NetworkConfig::default())
+    }
+
+    /// 使用 config 中的压缩阈值和最大 frame 把 Message encode 成一个 frame
+    ///
+    /// 压缩前的大小不能超过 max_decompressed_size，否则对端解压时会拒绝
+    /// 实际写入的大小（压缩后的大小）不能超过 max_frame_size。出错时 buf 不会被修改
+    fn encode_frame_with(&self, buf: &mut BytesMut, config: &NetworkConfig) -> Result<(), KvError> {
         let size = self.encoded_len();
 
-        if size >= MAX_FRAME {
+        if size >= MAX_FRAME || size > config.max_decompressed_size {
             return Err(KvError::FrameError);
         }
 
-        // 我们先写入长度，如果需要压缩，再重写压缩后的长度
-        buf.put_u32(size as _);
-
-        if size > COMPRESSION_LIMIT {
+        if size > config.compression_threshold {
             let mut buf1 = Vec::with_capacity(size);
             self.encode(&mut buf1)?;
 
-            // BytesMut支持逻辑上的 split （之后还能unsplit）
-            // 所以我们先把长度这 4 个字节拿走，清除
-            let payload = buf.split_off(LEN_LEN);
-            buf.clear();
-
             // 处理 gzip 压缩，具体可以参考 flate2 文档
-            let mut encoder = GzEncoder::new(payload.writer(), Compression::default());
+            let mut encoder = GzEncoder::new(BytesMut::new().writer(), Compression::default());
             encoder.write_all(&buf1[..])?;
 
-
             // 压缩完成后，从 gzip encoder 中把 BytesMut 再拿回来
             let payload = encoder.finish()?.into_inner();
             debug!("Encode a frame: size {}({})", size, payload.len());
+            metrics::FRAME_COMPRESSION_RATIO.observe(payload.len() as f64 / size as f64);
 
-            // 写入压缩后的长度
-            buf.put_u32((payload.len() | COMPRESSION_BIT) as _);
-
-            // 把 BytesMut 再合并回来
-            buf.unsplit(payload);
+            if payload.len() > config.max_frame_size {
+                return Err(KvError::FrameError);
+            }
 
+            // 写入压缩后的长度和数据
+            buf.put_u32((payload.len() | COMPRESSION_BIT) as _);
+            buf.extend_from_slice(&payload);
             Ok(())
         } else {
+            if size > config.max_frame_size {
+                return Err(KvError::FrameError);
+            }
+
+            buf.put_u32(size as _);
             self.encode(buf)?;
             Ok(())
         }
@@ -66,19 +75,33 @@ pub trait FrameCoder
 
     /// 把一个完整的 frame decode 成一个 Message
     fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
+        Self::decode_frame_with(buf, &NetworkConfig::default())
+    }
+
+    /// 把一个完整的 frame decode 成一个 Message，解压后超过 config.max_decompressed_size 时返回 FrameError
+    fn decode_frame_with(buf: &mut BytesMut, config: &NetworkConfig) -> Result<Self, KvError> {
         let header = buf.get_u32() as usize;
         let (len, compressed) = decode_header(header);
         debug!("Got a frame: msg len {}, compressed {}", len, compressed);
 
+        let limit = config.max_decompressed_size;
         if compressed {
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            let mut buf1 = Vec::with_capacity(len * 2);
+            // 最多解压出 limit + 1 个字节，防止 gzip 炸弹耗尽内存
+            let mut decoder = GzDecoder::new(&buf[..len]).take(limit as u64 + 1);
+            let mut buf1 = Vec::with_capacity((len * 2).min(limit));
             decoder.read_to_end(&mut buf1)?;
             buf.advance(len);
+            if buf1.len() > limit {
+                return Err(KvError::FrameError);
+            }
 
             // decode 成相应的信息
             Ok(Self::decode(&buf1[..buf1.len()])?)
         } else {
+            if len > limit {
+                buf.advance(len);
+                return Err(KvError::FrameError);
+            }
             let msg = Self::decode(&buf[..len])?;
             buf.advance(len);
             Ok(msg)
@@ -99,9 +122,23 @@ fn decode_header(header: usize) -> (usize, bool) {
 /// 从 stream 中读取一个完整的 frame
 pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<(), KvError>
     where S: AsyncRead + Unpin + Send,
+{
+    read_frame_with(stream, buf, &NetworkConfig::default()).await
+}
+
+/// 从 stream 中读取一个完整的 frame，长度超过 config.max_frame_size 时不分配内存，直接返回 FrameError
+pub async fn read_frame_with<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    config: &NetworkConfig,
+) -> Result<(), KvError>
+    where S: AsyncRead + Unpin + Send,
 {
     let header = stream.read_u32().await? as usize;
     let (len, _compressed) = decode_header(header);
+    if len > config.max_frame_size {
+        return Err(KvError::FrameError);
+    }
 
     // 如果没有这么大的内存，就至少分配一个 frame 的内存，保存它可用
     buf.reserve(LEN_LEN + len);
@@ -168,6 +205,79 @@ mod tests {
         assert_eq!(res, res1);
     }
 
+    #[test]
+    fn encode_frame_should_follow_config() {
+        let config = NetworkConfig {
+            compression_threshold: 64,
+            max_frame_size: 256,
+            ..Default::default()
+        };
+        let mut buf = BytesMut::new();
+
+        let value: Value = Bytes::from(vec![0u8; 100]).into();
+        let res: CommandResponse = value.into();
+        res.encode_frame_with(&mut buf, &config).unwrap();
+        assert!(is_compressed(&buf));
+        assert_eq!(CommandResponse::decode_frame(&mut buf).unwrap(), res);
+
+        // 压缩后不超过 max_frame_size 就可以发送
+        let value: Value = Bytes::from(vec![0u8; 300]).into();
+        let res: CommandResponse = value.into();
+        res.encode_frame_with(&mut buf, &config).unwrap();
+        assert!(is_compressed(&buf));
+        assert_eq!(CommandResponse::decode_frame(&mut buf).unwrap(), res);
+
+        // 压缩后仍然超过 max_frame_size
+        let data: Vec<u8> = (0..300).map(|_| rand::random()).collect();
+        let res: CommandResponse = Value::from(Bytes::from(data)).into();
+        let result = res.encode_frame_with(&mut buf, &config);
+        assert!(matches!(result, Err(KvError::FrameError)));
+        assert!(buf.is_empty());
+
+        // 压缩前超过了对端能解压的大小
+        let config = NetworkConfig {
+            max_decompressed_size: 256,
+            ..config
+        };
+        let value: Value = Bytes::from(vec![0u8; 300]).into();
+        let res: CommandResponse = value.into();
+        let result = res.encode_frame_with(&mut buf, &config);
+        assert!(matches!(result, Err(KvError::FrameError)));
+    }
+
+    #[test]
+    fn decode_frame_should_reject_gzip_bomb() {
+        let config = NetworkConfig {
+            max_decompressed_size: 64 * 1024,
+            ..Default::default()
+        };
+        let value: Value = Bytes::from(vec![0u8; 1024 * 1024]).into();
+        let res: CommandResponse = value.into();
+        let mut buf = BytesMut::new();
+        res.encode_frame(&mut buf).unwrap();
+        assert!(buf.len() < 64 * 1024);
+
+        let result = CommandResponse::decode_frame_with(&mut buf, &config);
+        assert!(matches!(result, Err(KvError::FrameError)));
+    }
+
+    #[tokio::test]
+    async fn read_frame_should_reject_oversized_frame() {
+        let config = NetworkConfig {
+            max_frame_size: 1024,
+            ..Default::default()
+        };
+        // 只有 header，声称后面有 1G 的数据
+        let mut buf = BytesMut::new();
+        buf.put_u32(1 << 30);
+
+        let mut stream = DummyStream{ buf };
+        let mut data = BytesMut::new();
+        let result = read_frame_with(&mut stream, &mut data, &config).await;
+        assert!(matches!(result, Err(KvError::FrameError)));
+        assert!(data.capacity() < 1024);
+    }
+
     #[tokio::test]
     async fn read_frame_should_work() {
         let mut buf = BytesMut::new();
@@ -1,32 +1,44 @@
+mod cert;
 mod frame;
 mod tls;
 mod stream;
 mod multiplex;
 mod stream_result;
+mod blocking;
 
-pub use frame::{read_frame, FrameCoder};
+pub use cert::CERT_WATCH_INTERVAL;
+pub use frame::{read_frame, read_frame_with, FrameCoder};
+pub(crate) use frame::{COMPRESSION_LIMIT, FRAME_LIMIT, MAX_FRAME};
 pub use multiplex::YamuxCtrl;
 pub use stream::ProstStream;
 pub use stream_result::StreamResult;
-pub use tls::{TlsClientConnector, TlsServerAcceptor};
+pub use tls::{client_identity, TlsClientConnector, TlsServerAcceptor};
+pub(crate) use tls::verify_server_tls;
+#[cfg(test)]
+pub(crate) use tls::tls_utils;
+pub use blocking::{BlockingClient, BlockingStreamResult};
 
-use bytes::BytesMut;
-use futures::{SinkExt, StreamExt};
-use prost::encoding::group::encode;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tracing::info;
+use futures::{Future, SinkExt, StreamExt};
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time;
+use tracing::{info, warn};
 
-use crate::{CommandRequest, CommandResponse, KvError, Service, Storage};
+use crate::{CommandRequest, CommandResponse, KvError, NetworkConfig, Service, Session, Storage};
 
 /// 处理服务器端的某个 accept 下来的 socket 的读写
 pub struct ProstServerStream<S, Store> {
     inner: ProstStream<S, CommandRequest, CommandResponse>,
     service: Service<Store>,
+    session: Arc<Session>,
 }
 
 /// 处理客户端的 socket 读写
 pub struct ProstClientStream<S> {
     inner: ProstStream<S, CommandResponse, CommandRequest>,
+    timeout: Option<Duration>,
 }
 
 impl<S, Store> ProstServerStream<S, Store> where
@@ -37,16 +49,62 @@ impl<S, Store> ProstServerStream<S, Store> where
         Self {
             inner: ProstStream::new(stream),
             service,
+            session: Default::default(),
         }
     }
 
+    /// 使用连接级别的 Session，同一个连接上的 stream 共享身份等状态
+    pub fn with_session(mut self, session: Arc<Session>) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// 使用 config 中的 frame 参数
+    pub fn with_config(mut self, config: NetworkConfig) -> Self {
+        self.inner = self.inner.with_config(config);
+        self
+    }
+
     pub async fn process(mut self) -> Result<(), KvError> {
         let stream = &mut self.inner;
-        while let Some(Ok(cmd)) = stream.next().await {
+        while let Some(cmd) = stream.next().await {
+            let cmd = match cmd {
+                Ok(cmd) => cmd,
+                // frame 超过了限制，关闭这个 stream
+                Err(e @ KvError::FrameError) => {
+                    warn!("Close stream after oversized frame");
+                    return Err(e);
+                }
+                Err(_) => break,
+            };
             info!("Got a new command: {:?}", cmd);
-            let mut res = self.service.execute(cmd);
-            while let Some(data) = res.next().await {
-                stream.send(&data).await.unwrap();
+            let mut res = self.service.execute_with_session(cmd, &self.session);
+            loop {
+                // 连接断开后不再等待订阅的数据，释放 res 让订阅被清理
+                let data = tokio::select! {
+                    data = res.next() => data,
+                    _ = self.session.closed() => None,
+                };
+                let data = match data {
+                    Some(data) => data,
+                    None => break,
+                };
+                let result = match stream.send(&data).await {
+                    Ok(()) => {
+                        self.service.after_send(&data);
+                        Ok(())
+                    }
+                    // response 超过了 frame 的限制，返回错误给客户端，stream 可以继续使用
+                    Err(KvError::FrameError) => {
+                        warn!("Response is larger than max frame size");
+                        stream.send(&KvError::FrameError.into()).await
+                    }
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    warn!("Failed to send response: {:?}", e);
+                    return Err(e);
+                }
             }
         }
         // info!("Client {:?} disconnected", self.addr);
@@ -60,24 +118,72 @@ impl<S> ProstClientStream<S> where
     pub fn new(stream: S) -> Self {
         Self {
             inner: ProstStream::new(stream),
+            timeout: None,
         }
     }
 
+    /// 使用 config 中的 frame 参数
+    pub fn with_config(mut self, config: NetworkConfig) -> Self {
+        self.inner = self.inner.with_config(config);
+        self
+    }
+
+    /// 设置客户端超时，同时会作为 deadline 发给服务器
+    /// 超时之后这个 stream 上可能还会收到迟到的 response，调用者应该丢弃这个 stream
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     pub async fn execute_unary(&mut self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        let timeout = self.timeout;
+        let cmd = with_deadline(cmd, timeout);
         let stream = &mut self.inner;
-        stream.send(cmd).await?;
+        let fut = async move {
+            stream.send(&*cmd).await?;
 
-        match stream.next().await {
-            Some(v) => v,
-            None => Err(KvError::Internal("Didn't get any response".into())),
-        }
+            match stream.next().await {
+                Some(v) => v,
+                None => Err(KvError::Internal("Didn't get any response".into())),
+            }
+        };
+
+        with_timeout(fut, timeout).await
     }
 
     pub async fn execute_streaming(self, cmd: &CommandRequest) -> Result<StreamResult, KvError> {
+        let timeout = self.timeout;
+        let cmd = with_deadline(cmd, timeout);
         let mut stream = self.inner;
-        stream.send(cmd).await?;
-        stream.close().await?;
-        StreamResult::new(stream).await
+        let fut = async move {
+            stream.send(&*cmd).await?;
+            stream.close().await?;
+            StreamResult::new(stream).await
+        };
+
+        // 超时只作用于拿到 subscription id 之前
+        with_timeout(fut, timeout).await
+    }
+}
+
+/// 如果客户端设置了超时，而命令自己没有 deadline，就用超时作为 deadline
+fn with_deadline(cmd: &CommandRequest, timeout: Option<Duration>) -> Cow<'_, CommandRequest> {
+    match timeout {
+        Some(timeout) if cmd.deadline_ms == 0 => Cow::Owned(cmd.clone().with_deadline(timeout)),
+        _ => Cow::Borrowed(cmd),
+    }
+}
+
+async fn with_timeout<T>(
+    fut: impl Future<Output = Result<T, KvError>>,
+    timeout: Option<Duration>,
+) -> Result<T, KvError> {
+    match timeout {
+        Some(timeout) => match time::timeout(timeout, fut).await {
+            Ok(v) => v,
+            Err(_) => Err(KvError::Timeout(format!("no response in {:?}", timeout))),
+        },
+        None => fut.await,
     }
 }
 
@@ -87,7 +193,7 @@ mod test {
     use std::net::SocketAddr;
     use bytes::Bytes;
     use tokio::net::{TcpListener, TcpStream};
-    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
+    use crate::{assert_res_ok, ErrorCode, MemTable, ServiceInner, Value};
     use super::*;
 
     #[tokio::test]
@@ -113,6 +219,26 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn client_should_get_structured_error() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        // HGET 不存在的 key，客户端应该能还原出 KvError::NotFound
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = client.execute_unary(&cmd).await?;
+        assert_eq!(res.code(), ErrorCode::NotFound);
+
+        match res.into_result() {
+            Err(KvError::NotFound(msg)) => assert_eq!(msg, "table t1, key k1"),
+            v => panic!("Expect NotFound, got {:?}", v),
+        }
+
+        Ok(())
+    }
+
     async fn client_server_compression_should_work() -> anyhow::Result<()> {
         let addr = start_server().await?;
 
@@ -131,7 +257,91 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn server_should_close_stream_on_oversized_frame() -> anyhow::Result<()> {
+        let addr = start_server_with(NetworkConfig {
+            max_frame_size: 1024,
+            ..Default::default()
+        })
+        .await?;
+
+        // 客户端不压缩，frame 超过了服务器的限制
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream).with_config(NetworkConfig {
+            compression_threshold: usize::MAX,
+            ..Default::default()
+        });
+        let v: Value = Bytes::from(vec![0u8; 4096]).into();
+        let cmd = CommandRequest::new_hset("t1", "k1", v);
+        assert!(client.execute_unary(&cmd).await.is_err());
+
+        // 其他连接不受影响
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_res_ok(&client.execute_unary(&cmd).await?, &[Value::default()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_reply_error_on_oversized_response() -> anyhow::Result<()> {
+        let addr = start_server_with(NetworkConfig {
+            max_frame_size: 1024,
+            ..Default::default()
+        })
+        .await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        // 压缩后不超过限制的 response 可以正常返回
+        let v: Value = Bytes::from(vec![0u8; 16384]).into();
+        let cmd = CommandRequest::new_hset("t1", "k1", v.clone());
+        client.execute_unary(&cmd).await?;
+        let res = client.execute_unary(&CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(&res, &[v], &[]);
+
+        // 无法压缩的数据，每个 request 都不超过限制，合在一起的 response 超过了限制
+        for key in ["k2", "k3", "k4"] {
+            let data: Vec<u8> = (0..800).map(|_| rand::random()).collect();
+            let cmd = CommandRequest::new_hset("t1", key, Bytes::from(data).into());
+            client.execute_unary(&cmd).await?;
+        }
+        let res = client.execute_unary(&CommandRequest::new_hgetall("t1")).await?;
+        assert!(matches!(res.into_result(), Err(KvError::FrameError)));
+
+        // stream 仍然可以使用
+        let cmd = CommandRequest::new_hset("t1", "k5", "v5".into());
+        assert_res_ok(&client.execute_unary(&cmd).await?, &[Value::default()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_timeout_should_work() -> anyhow::Result<()> {
+        // 一个只接受连接，但从不回应的服务器
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream).with_timeout(Duration::from_millis(10));
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let result = client.execute_unary(&cmd).await;
+        assert!(matches!(result, Err(KvError::Timeout(_))));
+
+        Ok(())
+    }
+
     async fn start_server() -> Result<SocketAddr> {
+        start_server_with(NetworkConfig::default()).await
+    }
+
+    async fn start_server_with(config: NetworkConfig) -> Result<SocketAddr> {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
 
@@ -139,7 +349,7 @@ mod test {
             loop {
                 let (stream, _) = listener.accept().await.unwrap();
                 let service: Service = ServiceInner::new(MemTable::new()).into();
-                let server = ProstServerStream::new(stream, service);
+                let server = ProstServerStream::new(stream, service).with_config(config.clone());
                 tokio::spawn(server.process());
             }
         });
@@ -2,15 +2,17 @@ use std::future::Future;
 use std::marker::PhantomData;
 use tokio::io::{AsyncRead, AsyncWrite};
 use yamux::{Config, Connection, ConnectionError, Control, Mode, WindowUpdateMode};
-use futures::{future, TryStreamExt};
+use futures::{future, stream, StreamExt, TryStreamExt};
 use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
-use crate::ProstClientStream;
+use crate::{NetworkConfig, ProstClientStream};
 use tracing::instrument;
 
 /// Yamux 控制结构
 pub struct YamuxCtrl<S> {
     /// yamux control, 用于创建新的 stream
     ctrl: Control,
+    /// 新的 stream 使用的 frame 参数
+    config: NetworkConfig,
     _conn: PhantomData<S>,
 }
 
@@ -19,23 +21,44 @@ where S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
 
     // 创建 yamux 客户端
-    pub fn new_client(stream: S, config: Option<Config>) -> Self {
-        Self::new(stream, config, true, |_stream| future::ready(Ok(())))
+    pub fn new_client(stream: S, config: Option<NetworkConfig>) -> Self {
+        Self::new(stream, config, true, |_stream| future::ready(Ok(())), || {})
     }
 
     // 创建 yamux 服务端， 服务端我们需要具体处理 stream
-    pub fn new_server<F, Fut>(stream: S, config: Option<Config>, f: F) -> Self
+    pub fn new_server<F, Fut>(stream: S, config: Option<NetworkConfig>, f: F) -> Self
     where
         F: FnMut(yamux::Stream) -> Fut,
         F: Send + 'static,
         Fut: Future<Output = Result<(), ConnectionError>> + Send + 'static,
     {
-        Self::new(stream, config, false, f)
+        Self::new(stream, config, false, f, || {})
+    }
+
+    // 创建 yamux 服务端，连接断开时调用 on_close
+    pub fn new_server_with_close<F, Fut>(
+        stream: S,
+        config: Option<NetworkConfig>,
+        f: F,
+        on_close: impl FnOnce() + Send + 'static,
+    ) -> Self
+    where
+        F: FnMut(yamux::Stream) -> Fut,
+        F: Send + 'static,
+        Fut: Future<Output = Result<(), ConnectionError>> + Send + 'static,
+    {
+        Self::new(stream, config, false, f, on_close)
     }
 
     #[instrument(name = "yamux_ctrl_new", skip_all)]
     // 创建 YamuxCtrl
-    fn new<F, Fut>(stream: S, config: Option<Config>, is_client: bool, f: F) -> Self
+    fn new<F, Fut>(
+        stream: S,
+        config: Option<NetworkConfig>,
+        is_client: bool,
+        f: F,
+        on_close: impl FnOnce() + Send + 'static,
+    ) -> Self
     where
         F: FnMut(yamux::Stream) -> Fut,
         F: Send + 'static,
@@ -48,20 +71,31 @@ where S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
         };
 
         // 创建 config
-        let mut config = config.unwrap_or_default();
-        config.set_window_update_mode(WindowUpdateMode::OnRead);
+        let config = config.unwrap_or_default();
+        let mut yamux_config = Config::default();
+        yamux_config
+            .set_receive_window(config.window_size)
+            .set_max_num_streams(config.max_streams)
+            .set_window_update_mode(WindowUpdateMode::OnRead);
 
         // 创建 config, yamux::Stream 使用的是 futures 的 trait 所以需要 compat()
-        let conn = Connection::new(stream.compat(), config, mode);
+        let conn = Connection::new(stream.compat(), yamux_config, mode);
 
         // 创建 yamux ctrl
         let ctrl = conn.control();
 
+        // 连接断开时 inbound stream 结束，这时调用 on_close，让还在处理中的 stream 退出
+        // 否则 try_for_each_concurrent 会一直等待还在推送订阅数据的 stream
+        // 连接出错时 try_for_each_concurrent 直接返回，guard 没有被 poll 就被释放，同样会调用 on_close
+        let guard = CloseGuard(Some(on_close));
+        let closed = stream::once(async move { drop(guard) }).filter_map(|_| future::ready(None));
+
         // pull 所有 stream 下的数据
-        tokio::spawn(yamux::into_stream(conn).try_for_each_concurrent(None, f));
+        tokio::spawn(yamux::into_stream(conn).chain(closed).try_for_each_concurrent(None, f));
 
         Self {
             ctrl,
+            config,
             _conn: PhantomData::default(),
         }
     }
@@ -70,11 +104,26 @@ where S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     // 打开一个新的 stream
     pub async fn open_stream(&mut self) -> Result<ProstClientStream<Compat<yamux::Stream>>, ConnectionError>  {
         let stream = self.ctrl.open_stream().await?;
-        Ok(ProstClientStream::new(stream.compat()))
+        Ok(ProstClientStream::new(stream.compat()).with_config(self.config.clone()))
+    }
+
+    // 关闭连接，释放 YamuxCtrl 并不会断开连接
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.ctrl.close().await
     }
 
 }
 
+/// 释放时调用 on_close，保证连接无论正常关闭还是出错都只调用一次
+struct CloseGuard<F: FnOnce()>(Option<F>);
+
+impl<F: FnOnce()> Drop for CloseGuard<F> {
+    fn drop(&mut self) {
+        if let Some(on_close) = self.0.take() {
+            on_close();
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -82,6 +131,7 @@ mod tests {
     use super::*;
     use anyhow::Result;
     use futures::future::ok;
+    use tokio::io::AsyncWriteExt;
     use tokio::net::{TcpListener, TcpStream};
     use tokio_rustls::server;
     use tracing::warn;
@@ -168,4 +218,19 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn on_close_should_be_called_on_connection_error() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let _ctrl = YamuxCtrl::new_server_with_close(server, None, |_| ok(()), move || {
+            tx.send(()).unwrap();
+        });
+
+        // 不是合法的 yamux frame，连接出错
+        client.write_all(&[0xff; 12]).await?;
+        tokio::time::timeout(std::time::Duration::from_secs(1), rx).await??;
+
+        Ok(())
+    }
+
 }
\ No newline at end of file
@@ -7,7 +7,7 @@ use std::{
 };
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::{read_frame, FrameCoder, KvError};
+use crate::{read_frame_with, FrameCoder, KvError, NetworkConfig};
 
 /// 处理 KV server prost frame 的 stream
 pub struct ProstStream<S, In, Out> {
@@ -20,6 +20,8 @@ pub struct ProstStream<S, In, Out> {
     written: usize,
     // 读缓存
     rbuf: BytesMut,
+    // frame 的参数
+    config: NetworkConfig,
 
     // 类型占位符
     _in: PhantomData<In>,
@@ -34,19 +36,23 @@ impl<S, In, Out> Stream for ProstStream<S, In, Out> where
     type Item = Result<In, KvError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
         // 上一次调用结束后 rbuf 应该为空
-        assert!(self.rbuf.len() == 0);
+        assert!(this.rbuf.is_empty());
 
         // 从 rbuf 中分离出 rest
-        let mut rest = self.rbuf.split_off(0);
+        let mut rest = this.rbuf.split_off(0);
 
         // 使用 read_frame 来获取数据
-        let fut = read_frame(&mut self.stream, &mut rest);
+        let fut = read_frame_with(&mut this.stream, &mut rest, &this.config);
         ready!(Box::pin(fut).poll_unpin(cx))?;
 
-        self.rbuf.unsplit(rest);
+        this.rbuf.unsplit(rest);
 
-        Poll::Ready(Some(In::decode_frame(&mut self.rbuf)))
+        let result = In::decode_frame_with(&mut this.rbuf, &this.config);
+        // decode 失败时丢弃剩下的数据
+        this.rbuf.clear();
+        Poll::Ready(Some(result))
     }
 }
 
@@ -63,7 +69,7 @@ impl<S, In, Out> Sink<&Out> for ProstStream<S, In, Out> where
 
     fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
         let this = self.get_mut();
-        item.encode_frame(&mut this.wbuf)?;
+        item.encode_frame_with(&mut this.wbuf, &this.config)?;
         Ok(())
     }
 
@@ -103,10 +109,17 @@ impl<S, In, Out> ProstStream<S, In, Out> where
             written: 0,
             wbuf: BytesMut::new(),
             rbuf: BytesMut::new(),
+            config: NetworkConfig::default(),
             _in: PhantomData::default(),
             _out: PhantomData::default(),
         }
     }
+
+    /// 使用 config 中的压缩阈值和 frame 大小的限制
+    pub fn with_config(mut self, config: NetworkConfig) -> Self {
+        self.config = config;
+        self
+    }
 }
 
 
@@ -10,7 +10,7 @@ use crate::{CommandResponse, KvError};
 
 /// 创建时之间取得 subscription id，并使用 Deref/DerefMut 使其用起来和 Stream 一致
 pub struct StreamResult {
-    pub id: u32,
+    pub id: u64,
     inner: Pin<Box<dyn Stream<Item = Result<CommandResponse, KvError>> + Send>>,
 }
 
@@ -29,9 +29,15 @@ impl StreamResult {
                     return Err(KvError::Internal("Invalid stream".into()));
                 }
                 let id: i64 = (&v[0]).try_into().unwrap();
-                Ok(id as u32)
+                Ok(id as u64)
             }
-            _ => Err(KvError::Internal("Invalid stream".into())),
+            // 服务器返回了错误，还原成对应的 KvError
+            Some(Ok(res)) => match res.into_result() {
+                Err(e) => Err(e),
+                Ok(_) => Err(KvError::Internal("Invalid stream".into())),
+            },
+            Some(Err(e)) => Err(e),
+            None => Err(KvError::Internal("Invalid stream".into())),
         };
 
         Ok(StreamResult {
@@ -1,53 +1,112 @@
 use std::io::Cursor;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::JoinHandle;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::rustls::{internal::pemfile, Certificate, ClientConfig, ServerConfig};
-use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore};
+use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore, Session};
 use tokio_rustls::webpki::DNSNameRef;
 use tokio_rustls::TlsConnector;
 use tokio_rustls::{client::TlsStream as ClientTlsStream, server::TlsStream as ServerTlsStream, TlsAcceptor};
 use tokio_rustls::TlsStream::Server;
-use crate::KvError;
+use crate::{KvError, ServerTlsConfig};
+use super::cert;
 use tracing::instrument;
+use x509_parser::parse_x509_certificate;
 
 /// KV Server 自己的 ALPN
 const ALPN_KV: &str = "kv";
 
 /// 存放 Tls Client 并提供方法 connect 把底层协议转换成 TLS
+/// clone 出来的 connector 共享同一个 ClientConfig，reload 之后对所有的 connector 生效
+/// 证书是文件路径时只在 new / reload 时读取，start_client_with_config 每次连接都会重新读取
 #[derive(Clone)]
 pub struct TlsClientConnector {
-    pub config: Arc<ClientConfig>,
+    inner: Arc<RwLock<ClientTls>>,
     pub domain: Arc<String>,
 }
 
 /// 存放 TLS ServerConfig 并提供方法 accept 把底层协议转换成 TLS
+/// clone 出来的 acceptor 共享同一个 ServerConfig，reload 之后对所有的 acceptor 生效
 #[derive(Clone)]
 pub struct TlsServerAcceptor {
-    inner: Arc<ServerConfig>,
+    inner: Arc<RwLock<ServerTls>>,
+}
+
+/// 由客户端证书和 CA 生成的 ClientConfig
+struct ClientTls {
+    config: Arc<ClientConfig>,
+}
+
+/// 服务器证书和 CA 的来源（PEM 内容或者文件路径），以及由它们生成的 ServerConfig
+struct ServerTls {
+    cert: String,
+    key: String,
+    ca: Option<String>,
+    config: Arc<ServerConfig>,
 }
 
 impl TlsClientConnector {
 
     #[instrument(name = "tls_connector_new", skip_all)]
+    /// identity 和 server_ca 可以是 PEM 内容，也可以是文件路径
     pub fn new(
         domain: impl Into<String> + std::fmt::Debug,
         identity: Option<(&str, &str)>,
         server_ca: Option<&str>,
     ) -> Result<Self, KvError> {
+        let tls = ClientTls::new(identity, server_ca)?;
+        Ok(Self {
+            inner: Arc::new(RwLock::new(tls)),
+            domain: Arc::new(domain.into()),
+        })
+    }
+
+    #[instrument(name = "tls_connector_reload", skip_all)]
+    /// 重新加载证书，只对之后的新连接生效，加载失败时继续使用原来的证书
+    pub fn reload(&self, identity: Option<(&str, &str)>, server_ca: Option<&str>) -> Result<(), KvError> {
+        let tls = ClientTls::new(identity, server_ca)?;
+        *self.inner.write().unwrap() = tls;
+        Ok(())
+    }
+
+    #[instrument(name = "tls_client_connect", skip_all)]
+    pub async fn connect<S>(&self, stream: S) -> Result<ClientTlsStream<S>, KvError>
+        where S: AsyncRead + AsyncWrite + Unpin + Send, {
+        let dns = DNSNameRef::try_from_ascii_str(self.domain.as_str())
+            .map_err(|_| KvError::Internal("Invalid DNS name".into()))?;
+        let config = self.inner.read().unwrap().config.clone();
+        let stream = TlsConnector::from(config)
+            .connect(dns, stream)
+            .await?;
+
+        Ok(stream)
+    }
+}
+
+impl ClientTls {
+    fn new(identity: Option<(&str, &str)>, server_ca: Option<&str>) -> Result<Self, KvError> {
         let mut config = ClientConfig::new();
 
         // 有客户端证书，就加载
         if let Some((cert, key)) = identity {
-            let certs = load_certs(cert)?;
-            let key = load_key(key)?;
+            let cert = cert::load_pem(cert)?;
+            cert::check_expiry(&cert);
+            let certs = load_certs(&cert)?;
+            let key = load_key(&cert::load_pem(key)?)?;
             config.set_single_client_cert(certs, key)?;
         }
 
         // 如果有签署服务器的 CA 证书，则加载它，这样服务器证书不存在根证链
         // 但是这个 CA 证书能去验证它，也可以
         if let Some(cert) = server_ca {
+            let cert = cert::load_pem(cert)?;
+            cert::check_expiry(&cert);
             let mut buf = Cursor::new(cert);
-            config.root_store.add_pem_file(&mut buf).unwrap();
+            config
+                .root_store
+                .add_pem_file(&mut buf)
+                .map_err(|_| KvError::CertifcateParseError("CA", "cert"))?;
         } else {
             // 加载本地信任的根证书链
             config.root_store = match rustls_native_certs::load_native_certs() {
@@ -58,38 +117,72 @@ impl TlsClientConnector {
 
         Ok(Self {
             config: Arc::new(config),
-            domain: Arc::new(domain.into()),
         })
     }
-
-    #[instrument(name = "tls_client_connect", skip_all)]
-    pub async fn connect<S>(&self, stream: S) -> Result<ClientTlsStream<S>, KvError>
-        where S: AsyncRead + AsyncWrite + Unpin + Send, {
-        let dns = DNSNameRef::try_from_ascii_str(self.domain.as_str())
-            .map_err(|_| KvError::Internal("Invalid DNS name".into()))?;
-        let stream = TlsConnector::from(self.config.clone())
-            .connect(dns, stream)
-            .await?;
-
-        Ok(stream)
-    }
 }
 
 impl TlsServerAcceptor {
 
     #[instrument(name = "tls_acceptor_new", skip_all)]
-    /// 加载 server cert / CA cert，生成 ServerConfig
+    /// 加载 server cert / CA cert，生成 ServerConfig，它们可以是 PEM 内容，也可以是文件路径
     pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self, KvError> {
-        let certs = load_certs(cert)?;
-        let key = load_key(key)?;
+        let tls = ServerTls::new(cert, key, client_ca)?;
+        Ok(Self {
+            inner: Arc::new(RwLock::new(tls)),
+        })
+    }
+
+    #[instrument(name = "tls_acceptor_reload", skip_all)]
+    /// 重新加载证书，只对之后的新连接生效，加载失败时继续使用原来的证书
+    pub fn reload(&self, cert: &str, key: &str, client_ca: Option<&str>) -> Result<(), KvError> {
+        let tls = ServerTls::new(cert, key, client_ca)?;
+        *self.inner.write().unwrap() = tls;
+        Ok(())
+    }
+
+    /// 定期检查证书文件，文件变化时重新加载，acceptor 都被释放后停止检查
+    pub fn watch(&self, interval: Duration) -> JoinHandle<()> {
+        cert::watch(
+            Arc::downgrade(&self.inner),
+            interval,
+            |tls: &RwLock<ServerTls>| tls.read().unwrap().sources(),
+            |tls: &RwLock<ServerTls>| {
+                let new = {
+                    let tls = tls.read().unwrap();
+                    ServerTls::new(&tls.cert, &tls.key, tls.ca.as_deref())?
+                };
+                *tls.write().unwrap() = new;
+                Ok(())
+            },
+        )
+    }
+
+    #[instrument(name = "tls_server_accept", skip_all)]
+    /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
+    pub async fn accept<S>(&self, stream: S) -> Result<ServerTlsStream<S>, KvError>
+        where S: AsyncRead + AsyncWrite + Unpin + Send, {
+        let config = self.inner.read().unwrap().config.clone();
+        let acceptor = TlsAcceptor::from(config);
+        Ok(acceptor.accept(stream).await?)
+    }
+}
+
+impl ServerTls {
+    fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self, KvError> {
+        let cert_pem = cert::load_pem(cert)?;
+        cert::check_expiry(&cert_pem);
+        let certs = load_certs(&cert_pem)?;
+        let private_key = load_key(&cert::load_pem(key)?)?;
 
         let mut config = match client_ca {
             None => ServerConfig::new(NoClientAuth::new()),
-            Some(cert) => {
-                let mut cert = Cursor::new(cert);
+            Some(ca) => {
+                let ca = cert::load_pem(ca)?;
+                cert::check_expiry(&ca);
+                let mut ca = Cursor::new(ca);
                 let mut client_root_cert_store = RootCertStore::empty();
                 client_root_cert_store
-                    .add_pem_file(&mut cert)
+                    .add_pem_file(&mut ca)
                     .map_err(|_| KvError::CertifcateParseError("CA", "cert"))?;
 
                 let client_auth = AllowAnyAuthenticatedClient::new(client_root_cert_store);
@@ -98,22 +191,51 @@ impl TlsServerAcceptor {
         };
 
         config
-            .set_single_cert(certs, key)
+            .set_single_cert(certs, private_key)
             .map_err(|_| KvError::CertifcateParseError("server", "cert"))?;
         config.set_protocols(&[Vec::from(&ALPN_KV[..])]);
 
         Ok(Self {
-            inner: Arc::new(config),
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+            ca: client_ca.map(|ca| ca.to_owned()),
+            config: Arc::new(config),
         })
     }
 
-    #[instrument(name = "tls_server_accept", skip_all)]
-    /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
-    pub async fn accept<S>(&self, stream: S) -> Result<ServerTlsStream<S>, KvError>
-        where S: AsyncRead + AsyncWrite + Unpin + Send, {
-        let acceptor = TlsAcceptor::from(self.inner.clone());
-        Ok(acceptor.accept(stream).await?)
+    fn sources(&self) -> Vec<String> {
+        [self.cert.clone(), self.key.clone()].into_iter().chain(self.ca.clone()).collect()
+    }
+}
+
+/// 检查服务器的证书、私钥和 CA，返回有问题的配置项和原因
+pub(crate) fn verify_server_tls(tls: &ServerTlsConfig) -> Vec<(&'static str, String)> {
+    let mut problems = vec![];
+    if let Err(e) = cert::verify_certs(&tls.cert) {
+        problems.push(("tls.cert", e));
+    }
+    if let Err(e) = cert::verify_key(&tls.key) {
+        problems.push(("tls.key", e));
     }
+    if let Some(Err(e)) = tls.ca.as_deref().map(cert::verify_certs) {
+        problems.push(("tls.ca", e));
+    }
+
+    // 每一项单独都没有问题时，再检查它们能否一起使用
+    if problems.is_empty() {
+        if let Err(e) = ServerTls::new(&tls.cert, &tls.key, tls.ca.as_deref()) {
+            problems.push(("tls", e.to_string()));
+        }
+    }
+    problems
+}
+
+/// 从客户端证书的 subject 中获取 CN 作为客户端的身份
+pub fn client_identity<S>(stream: &ServerTlsStream<S>) -> Option<String> {
+    let certs = stream.get_ref().1.get_peer_certificates()?;
+    let (_, cert) = parse_x509_certificate(&certs.first()?.0).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(|s| s.to_owned())
 }
 
 fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
@@ -121,7 +243,7 @@ fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
     pemfile::certs(&mut cert).map_err(|_| KvError::CertifcateParseError("server", "cert"))
 }
 
-fn load_key(key: &str) -> Result<PrivateKey, KvError> {
+pub(super) fn load_key(key: &str) -> Result<PrivateKey, KvError> {
     let mut cursor = Cursor::new(key);
 
     // 先尝试使用 PKC8 加载私钥
@@ -148,11 +270,21 @@ fn load_key(key: &str) -> Result<PrivateKey, KvError> {
 pub mod tls_utils {
     use crate::{KvError, TlsClientConnector, TlsServerAcceptor};
 
-    const CA_CERT: &str = include_str!("../../fixtures/ca.cert");
+    pub const CA_CERT: &str = include_str!("../../fixtures/ca.cert");
     const CLIENT_CERT: &str = include_str!("../../fixtures/client.cert");
     const CLIENT_KEY: &str = include_str!("../../fixtures/client.key");
-    const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
-    const SERVER_KEY: &str = include_str!("../../fixtures/server.key");
+    pub const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
+    pub const SERVER_KEY: &str = include_str!("../../fixtures/server.key");
+
+    /// 生成从 not_before 年到 not_after 年有效的自签名证书，返回 PEM 格式的证书和私钥
+    /// 检查有效期的测试使用它，不依赖 fixtures 中证书的真实过期时间
+    pub fn self_signed_cert(not_before: i32, not_after: i32) -> (String, String) {
+        let mut params = rcgen::CertificateParams::new(vec!["kvserver.acme.inc".to_string()]);
+        params.not_before = rcgen::date_time_ymd(not_before, 1, 1);
+        params.not_after = rcgen::date_time_ymd(not_after, 1, 1);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        (cert.serialize_pem().unwrap(), cert.serialize_private_key_pem())
+    }
 
     pub fn tls_connector(client_cert: bool) -> Result<TlsClientConnector, KvError> {
         let ca = Some(CA_CERT);
@@ -185,6 +317,7 @@ mod tests {
     use tokio::{
         io::{AsyncReadExt, AsyncWriteExt},
         net::{TcpListener, TcpStream},
+        time,
     };
 
 
@@ -230,15 +363,73 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn tls_acceptor_reload_should_apply_to_new_connections() -> Result<()> {
+        use super::tls_utils::{CA_CERT, SERVER_CERT, SERVER_KEY};
+
+        let acceptor = tls_acceptor(false)?;
+        // 证书不合法时继续使用原来的配置
+        assert!(acceptor.reload("bad cert", "bad key", None).is_err());
+
+        // 改成要求客户端证书之后，没有客户端证书的连接会失败
+        acceptor.reload(SERVER_CERT, SERVER_KEY, Some(CA_CERT))?;
+        let addr = start_server_with(acceptor).await?;
+        let result = async {
+            let stream = TcpStream::connect(addr).await?;
+            let mut stream = tls_connector(false)?.connect(stream).await?;
+            stream.write_all(b"hello world!").await?;
+            let mut buf = [0; 12];
+            stream.read_exact(&mut buf).await?;
+            Ok::<_, anyhow::Error>(())
+        };
+        assert!(result.await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_acceptor_should_reload_changed_files() -> Result<()> {
+        use super::tls_utils::{SERVER_CERT, SERVER_KEY};
+        use std::time::SystemTime;
+
+        let dir = tempfile::tempdir()?;
+        let cert = dir.path().join("server.cert");
+        let key = dir.path().join("server.key");
+        std::fs::write(&cert, SERVER_CERT)?;
+        std::fs::write(&key, SERVER_KEY)?;
+
+        let acceptor = TlsServerAcceptor::new(cert.to_str().unwrap(), key.to_str().unwrap(), None)?;
+        let old = acceptor.inner.read().unwrap().config.clone();
+        acceptor.watch(Duration::from_millis(10));
+        time::sleep(Duration::from_millis(50)).await;
+
+        // 证书文件被替换后，acceptor 使用新的 ServerConfig
+        std::fs::write(&cert, SERVER_CERT)?;
+        let file = std::fs::File::options().write(true).open(&cert)?;
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))?;
+        for _ in 0..100 {
+            if !Arc::ptr_eq(&old, &acceptor.inner.read().unwrap().config) {
+                return Ok(());
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("certificate is not reloaded");
+    }
+
     async fn start_server(client_cert: bool) -> Result<SocketAddr> {
-        let acceptor = tls_acceptor(client_cert)?;
+        start_server_with(tls_acceptor(client_cert)?).await
+    }
 
+    async fn start_server_with(acceptor: TlsServerAcceptor) -> Result<SocketAddr> {
         let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = echo.local_addr().unwrap();
 
         tokio::spawn(async move {
             let (stream, _) = echo.accept().await.unwrap();
-            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
             let mut buf = [0; 12];
             stream.read_exact(&mut buf).await.unwrap();
             stream.write_all(&buf).await.unwrap();
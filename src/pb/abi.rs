@@ -1,7 +1,11 @@
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12")]
+    /// 请求的 deadline（相对于服务器收到请求的时间，单位毫秒），0 表示不限制
+    /// Subscribe / Psubscribe 返回的是长期的 stream，不支持 deadline
+    #[prost(uint32, tag="100")]
+    pub deadline_ms: u32,
+    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21")]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
@@ -33,6 +37,24 @@ pub mod command_request {
         Unsubscribe(super::Unsubscribe),
         #[prost(message, tag="12")]
         Publish(super::Publish),
+        #[prost(message, tag="13")]
+        Auth(super::Auth),
+        #[prost(message, tag="14")]
+        Select(super::Select),
+        #[prost(message, tag="15")]
+        Psubscribe(super::Psubscribe),
+        #[prost(message, tag="16")]
+        Punsubscribe(super::Punsubscribe),
+        #[prost(message, tag="17")]
+        Ack(super::Ack),
+        #[prost(message, tag="18")]
+        ListSubscriptions(super::ListSubscriptions),
+        #[prost(message, tag="19")]
+        ListTopics(super::ListTopics),
+        #[prost(message, tag="20")]
+        TopicInfo(super::TopicInfo),
+        #[prost(message, tag="21")]
+        NumSub(super::NumSub),
     }
 }
 #[derive(PartialOrd)]
@@ -46,6 +68,20 @@ pub struct CommandResponse {
     pub values: ::prost::alloc::vec::Vec<Value>,
     #[prost(message, repeated, tag="4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    #[prost(enumeration="ErrorCode", tag="5")]
+    pub code: i32,
+    /// 订阅收到的数据所属的 topic
+    #[prost(string, tag="6")]
+    pub topic: ::prost::alloc::string::String,
+    /// 持久化 topic 中消息的 offset
+    #[prost(uint64, tag="7")]
+    pub offset: u64,
+    /// 消费组中的消息 id，处理完之后用 Ack 确认
+    #[prost(uint64, tag="8")]
+    pub message_id: u64,
+    /// 订阅者太慢，在这条消息之前被丢弃的消息数量
+    #[prost(uint64, tag="9")]
+    pub dropped: u64,
 }
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -132,6 +168,28 @@ pub struct Kvpair {
 pub struct Subscribe {
     #[prost(string, tag="1")]
     pub topic: ::prost::alloc::string::String,
+    /// 以 consumer 的名字加入消费组，组里每条消息只会投递给一个消费者
+    #[prost(string, tag="4")]
+    pub group: ::prost::alloc::string::String,
+    #[prost(string, tag="5")]
+    pub consumer: ::prost::alloc::string::String,
+    /// 从哪里开始接收，不设置时只接收新的消息
+    #[prost(oneof="subscribe::Start", tags="2, 3")]
+    pub start: ::core::option::Option<subscribe::Start>,
+}
+/// Nested message and enum types in `Subscribe`.
+pub mod subscribe {
+    /// 从哪里开始接收，不设置时只接收新的消息
+    #[derive(PartialOrd)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Start {
+        /// 从 offset 开始重放持久化 topic 的历史消息，然后接收新的消息
+        #[prost(uint64, tag="2")]
+        FromOffset(u64),
+        /// 只接收新的消息
+        #[prost(bool, tag="3")]
+        FromLatest(bool),
+    }
 }
 /// 取消对某个主题的订阅
 #[derive(PartialOrd)]
@@ -139,10 +197,67 @@ pub struct Subscribe {
 pub struct Unsubscribe {
     #[prost(string, tag="1")]
     pub topic: ::prost::alloc::string::String,
-    #[prost(uint32, tag="2")]
-    pub id: u32,
+    #[prost(uint64, tag="2")]
+    pub id: u64,
+}
+/// 订阅所有匹配 glob 模式的主题，比如 orders.*
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Psubscribe {
+    #[prost(string, tag="1")]
+    pub pattern: ::prost::alloc::string::String,
+}
+/// 取消模式订阅
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Punsubscribe {
+    #[prost(string, tag="1")]
+    pub pattern: ::prost::alloc::string::String,
+    #[prost(uint64, tag="2")]
+    pub id: u64,
+}
+/// 确认消费组中的消息已经处理完，没有确认的消息超时后会重新投递
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ack {
+    #[prost(string, tag="1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub group: ::prost::alloc::string::String,
+    #[prost(uint64, repeated, tag="3")]
+    pub ids: ::prost::alloc::vec::Vec<u64>,
+}
+/// 列出名字以 prefix 开头的 topic / 模式上所有的订阅，返回的 pairs 中 key 是 topic 或者模式，value 是 subscription id
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListSubscriptions {
+    #[prost(string, tag="1")]
+    pub prefix: ::prost::alloc::string::String,
+}
+/// 列出名字以 prefix 开头的 topic，返回的 pairs 中 key 是 topic，value 是订阅数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListTopics {
+    #[prost(string, tag="1")]
+    pub prefix: ::prost::alloc::string::String,
+}
+/// 查看 topic 的统计信息，返回的 pairs 中 key 是 subscribers / patterns / groups / published / dropped
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TopicInfo {
+    #[prost(string, tag="1")]
+    pub topic: ::prost::alloc::string::String,
+}
+/// 查看多个 topic 的订阅数（不包括模式订阅），返回的 pairs 中 key 是 topic，value 是订阅数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct NumSub {
+    #[prost(string, repeated, tag="1")]
+    pub topics: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
-/// 发布数据到某个主题
+/// 发布数据到某个主题，返回的 values 中是收到消息的订阅者数量（包括消费组）
+/// wait 为 true 时，等所有的订阅者发送完成后再返回，只统计实际放进队列的订阅者
+/// 等待超过请求的 deadline（没有设置时为 10 秒）时返回 408
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Publish {
@@ -150,6 +265,22 @@ pub struct Publish {
     pub topic: ::prost::alloc::string::String,
     #[prost(message, repeated, tag="2")]
     pub data: ::prost::alloc::vec::Vec<Value>,
+    #[prost(bool, tag="3")]
+    pub wait: bool,
+}
+/// 使用 token 认证当前连接，之后连接上所有的 stream 都使用这个身份
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Auth {
+    #[prost(string, tag="1")]
+    pub token: ::prost::alloc::string::String,
+}
+/// 切换当前连接使用的 namespace，空字符串表示默认的 namespace
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Select {
+    #[prost(string, tag="1")]
+    pub namespace: ::prost::alloc::string::String,
 }
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -174,3 +305,21 @@ pub mod value {
         Bool(bool),
     }
 }
+/// 错误类型，客户端可以据此还原出对应的 KvError
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ErrorCode {
+    Ok = 0,
+    NotFound = 1,
+    InvalidCommand = 2,
+    ConvertError = 3,
+    StorageError = 4,
+    FrameError = 5,
+    Internal = 6,
+    Timeout = 7,
+    PermissionDenied = 8,
+    QuotaExceeded = 9,
+    TooManyRequests = 10,
+    OutOfMemory = 11,
+}
@@ -4,6 +4,7 @@ use bytes::Bytes;
 use http::StatusCode;
 use abi::{command_request::RequestData, *};
 use std::convert::TryFrom;
+use std::time::Duration;
 use prost::Message;
 
 use crate::KvError;
@@ -15,6 +16,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -23,6 +25,7 @@ impl CommandRequest {
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -32,6 +35,7 @@ impl CommandRequest {
                 table: table.into(),
                 pair: Some(Kvpair::new(key, value)),
             })),
+            ..Default::default()
         }
     }
 
@@ -41,6 +45,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -50,6 +55,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -59,6 +65,7 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
@@ -68,6 +75,7 @@ impl CommandRequest {
                 table: table.into(),
                 pairs,
             })),
+            ..Default::default()
         }
     }
 
@@ -77,6 +85,7 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
@@ -86,21 +95,68 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
     pub fn new_subscribe(name: impl Into<String>) -> Self {
         Self {
-            request_data: Some(RequestData::Subscribe(Subscribe { topic: name.into() })),
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: name.into(),
+                ..Default::default()
+            })),
+            ..Default::default()
         }
     }
 
-    pub fn new_unsubscribe(name: impl Into<String>, id: u32) -> Self {
+    /// 从 offset 开始订阅持久化的 topic
+    pub fn new_subscribe_from(name: impl Into<String>, offset: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: name.into(),
+                start: Some(subscribe::Start::FromOffset(offset)),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 以 consumer 的名字加入 topic 的消费组
+    pub fn new_subscribe_group(
+        name: impl Into<String>,
+        group: impl Into<String>,
+        consumer: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: name.into(),
+                group: group.into(),
+                consumer: consumer.into(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 确认消费组中已经处理完的消息
+    pub fn new_ack(name: impl Into<String>, group: impl Into<String>, ids: Vec<u64>) -> Self {
+        Self {
+            request_data: Some(RequestData::Ack(Ack {
+                topic: name.into(),
+                group: group.into(),
+                ids,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_unsubscribe(name: impl Into<String>, id: u64) -> Self {
         Self {
             request_data: Some(RequestData::Unsubscribe(Unsubscribe {
                 topic: name.into(),
                 id,
             })),
+            ..Default::default()
         }
     }
 
@@ -109,7 +165,143 @@ impl CommandRequest {
             request_data: Some(RequestData::Publish(Publish {
                 topic: name.into(),
                 data,
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 发布数据，等所有的订阅者发送完成后再返回
+    pub fn new_publish_wait(name: impl Into<String>, data: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Publish(Publish {
+                topic: name.into(),
+                data,
+                wait: true,
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_psubscribe(pattern: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Psubscribe(Psubscribe {
+                pattern: pattern.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_punsubscribe(pattern: impl Into<String>, id: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Punsubscribe(Punsubscribe {
+                pattern: pattern.into(),
+                id,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 列出名字以 prefix 开头的 topic 上所有的订阅
+    pub fn new_list_subscriptions(prefix: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::ListSubscriptions(ListSubscriptions {
+                prefix: prefix.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 列出名字以 prefix 开头的 topic
+    pub fn new_list_topics(prefix: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::ListTopics(ListTopics {
+                prefix: prefix.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 查看 topic 的统计信息
+    pub fn new_topic_info(name: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::TopicInfo(TopicInfo { topic: name.into() })),
+            ..Default::default()
+        }
+    }
+
+    /// 查看多个 topic 的订阅数
+    pub fn new_num_sub(names: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::NumSub(NumSub { topics: names })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_auth(token: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                token: token.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_select(namespace: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Select(Select {
+                namespace: namespace.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 设置请求的 deadline，超时前还没有开始执行的命令和超时的只读命令返回 408
+    ///
+    /// 已经开始执行的写命令不会被取消，服务器会等它执行完，返回真实的结果
+    /// 订阅命令不支持 deadline，会返回 400
+    pub fn with_deadline(mut self, timeout: Duration) -> Self {
+        self.deadline_ms = timeout.as_millis().min(u32::MAX as u128) as _;
+        self
+    }
+
+    /// 获取请求的 deadline
+    pub fn deadline(&self) -> Option<Duration> {
+        match self.deadline_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms as _)),
+        }
+    }
+
+    /// 是否是只读的命令，只读的命令超时后可以直接返回，不用等它执行完
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(RequestData::Hget(_))
+                | Some(RequestData::Hgetall(_))
+                | Some(RequestData::Hmget(_))
+                | Some(RequestData::Hexist(_))
+                | Some(RequestData::Hmexist(_))
+                | Some(RequestData::ListSubscriptions(_))
+                | Some(RequestData::ListTopics(_))
+                | Some(RequestData::TopicInfo(_))
+                | Some(RequestData::NumSub(_))
+        )
+    }
+
+    /// 访问 table 的命令中的 table 名字
+    pub fn table(&self) -> Option<&str> {
+        match &self.request_data {
+            Some(RequestData::Hget(v)) => Some(&v.table),
+            Some(RequestData::Hgetall(v)) => Some(&v.table),
+            Some(RequestData::Hmget(v)) => Some(&v.table),
+            Some(RequestData::Hset(v)) => Some(&v.table),
+            Some(RequestData::Hmset(v)) => Some(&v.table),
+            Some(RequestData::Hdel(v)) => Some(&v.table),
+            Some(RequestData::Hmdel(v)) => Some(&v.table),
+            Some(RequestData::Hexist(v)) => Some(&v.table),
+            Some(RequestData::Hmexist(v)) => Some(&v.table),
+            _ => None,
         }
     }
 
@@ -131,10 +323,19 @@ impl CommandResponse {
         CommandResponse {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: msg,
+            code: ErrorCode::Internal as _,
             ..Default::default()
         }
     }
 
+    /// 如果 response 带有错误，则还原成相应的 KvError
+    pub fn into_result(self) -> Result<Self, KvError> {
+        match self.code() {
+            ErrorCode::Ok => Ok(self),
+            code => Err(KvError::from_remote(code, self.message)),
+        }
+    }
+
     /// 转换成 string 做错误处理
     pub fn format(&self) -> String {
         format!("{:?}", self)
@@ -226,13 +427,18 @@ impl From<KvError> for CommandResponse {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+            code: e.code() as _,
+            ..Default::default()
         };
 
         match e {
             KvError::NotFound(_) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
             KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::Timeout(_) => result.status = StatusCode::REQUEST_TIMEOUT.as_u16() as _,
+            KvError::PermissionDenied(_) => result.status = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::QuotaExceeded(_) => result.status = StatusCode::INSUFFICIENT_STORAGE.as_u16() as _,
+            KvError::OutOfMemory(_) => result.status = StatusCode::INSUFFICIENT_STORAGE.as_u16() as _,
+            KvError::RateLimited(_) => result.status = StatusCode::TOO_MANY_REQUESTS.as_u16() as _,
             _ => {}
         }
 
@@ -275,7 +481,7 @@ impl TryFrom<Value> for i64 {
     fn try_from(v: Value) -> Result<Self, Self::Error> {
         match v.value {
             Some(value::Value::Integer(i)) => Ok(i),
-            _ => Err(KvError::ConvertError(v.format(), "Integer")),
+            _ => Err(KvError::ConvertError(v.format(), "Integer".into())),
         }
     }
 }
@@ -286,7 +492,7 @@ impl TryFrom<&Value> for i64 {
     fn try_from(v: &Value) -> Result<Self, Self::Error> {
         match v.value {
             Some(value::Value::Integer(i)) => Ok(i),
-            _ => Err(KvError::ConvertError(v.format(), "Integer")),
+            _ => Err(KvError::ConvertError(v.format(), "Integer".into())),
         }
     }
 }
@@ -297,7 +503,7 @@ impl TryFrom<Value> for f64 {
     fn try_from(v: Value) -> Result<Self, Self::Error> {
         match v.value {
             Some(value::Value::Float(f)) => Ok(f),
-            _ => Err(KvError::ConvertError(v.format(), "Float")),
+            _ => Err(KvError::ConvertError(v.format(), "Float".into())),
         }
     }
 }
@@ -308,7 +514,7 @@ impl TryFrom<Value> for Bytes {
     fn try_from(v: Value) -> Result<Self, Self::Error> {
         match v.value {
             Some(value::Value::Binary(b)) => Ok(b),
-            _ => Err(KvError::ConvertError(v.format(), "Binary")),
+            _ => Err(KvError::ConvertError(v.format(), "Binary".into())),
         }
     }
 }
@@ -320,7 +526,7 @@ impl TryFrom<Value> for bool {
     fn try_from(v: Value) -> Result<Self, Self::Error> {
         match v.value {
             Some(value::Value::Bool(b)) => Ok(b),
-            _ => Err(KvError::ConvertError(v.format(), "Boolean")),
+            _ => Err(KvError::ConvertError(v.format(), "Boolean".into())),
         }
     }
 }
@@ -350,11 +556,11 @@ impl TryFrom<&CommandResponse> for i64 {
 
     fn try_from(value: &CommandResponse) -> Result<Self, Self::Error> {
         if value.status != StatusCode::OK.as_u16() as u32 {
-            return Err(KvError::ConvertError(value.format(), "CommandResponse"));
+            return Err(KvError::ConvertError(value.format(), "CommandResponse".into()));
         }
         match value.values.get(0) {
             Some(v) => v.try_into(),
-            None => Err(KvError::ConvertError(value.format(), "CommandResponse")),
+            None => Err(KvError::ConvertError(value.format(), "CommandResponse".into())),
         }
     }
 }
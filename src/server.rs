@@ -1,23 +1,57 @@
 use std::env;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use clap::{App, Arg, ArgMatches};
 use toml::toml;
-use tracing::span;
-use tokio::fs;
+use tracing::{info, span, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
 use tracing_subscriber::{
    fmt::{self, format},
    layer::SubscriberExt,
     prelude::*,
-    EnvFilter,
+    reload, EnvFilter, Registry,
+};
+use mini_kv::{
+    ConfigOverride, ConfigProblem, ConfigReload, LogConfig, RotationConfig, ServerConfig,
+    StorageConfig, CONFIG_ENV, start_server_with_reload,
 };
-use mini_kv::{RotationConfig, ServerConfig, start_server_with_config};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = match env::var("KV_SERVER_CONFIG") {
-        Ok(path) => fs::read_to_string(&path).await?,
-        Err(_) => include_str!("../fixtures/server.conf").to_string(),
+    let matches = cli().get_matches();
+    let path = match matches.value_of("config") {
+        Some(path) => path.to_owned(),
+        None => env::var(CONFIG_ENV)
+            .map_err(|_| anyhow!("No config file, use --config <FILE> or set {}", CONFIG_ENV))?,
     };
-    let config: ServerConfig = toml::from_str(&config)?;
+
+    // 优先级从低到高：配置文件、KV_* 环境变量、命令行参数
+    let mut overrides = ConfigOverride::from_env(env::vars());
+    overrides.extend(cli_overrides(&matches)?);
+    let config = ServerConfig::load_with_overrides(&path, &overrides)?;
+
+    if matches.is_present("print-config") {
+        print!("{}", toml::to_string(&config.redacted().to_toml()?)?);
+        return Ok(());
+    }
+
+    let mut problems = config.problems();
+    if let Err(e) = log_filter(&config.log) {
+        problems.push(ConfigProblem {
+            key: "log.filter".into(),
+            message: e.to_string(),
+        });
+    }
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    if !problems.is_empty() {
+        return Err(anyhow!("Found {} problems in config {}", problems.len(), path));
+    }
+    if matches.is_present("check-config") {
+        println!("Config {} is valid", path);
+        return Ok(());
+    }
 
     let tracer= opentelemetry_jaeger::new_pipeline()
         .with_service_name("kv_server")
@@ -36,15 +70,135 @@ async fn main() -> Result<()> {
     let fmt_layer = fmt::layer()
         .event_format(format().compact())
         .with_writer(non_blocking);
+    // 过滤规则可以在 reload 配置时更新
+    let (filter, filter_handle) = reload::Layer::new(log_filter(log)?);
      tracing_subscriber::registry()
-         .with(EnvFilter::from_default_env())
+         .with(filter)
          .with(fmt_layer)
          .with(opentelemetry)
          .init();
     let root = span!(tracing::Level::INFO, "app_start",  work_units = 2);
     let _enter = root.enter();
 
-    start_server_with_config(&config).await?;
+    // 收到 SIGHUP 时重新读取配置文件，环境变量和命令行参数依旧覆盖其中的配置
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        if let Err(e) = reload_on_sighup(path, overrides, tx, filter_handle).await {
+            warn!("Config reload is disabled: {:?}", e);
+        }
+    });
+
+    start_server_with_reload(&config, rx).await?;
+
+    Ok(())
+}
+
+fn cli() -> App<'static, 'static> {
+    App::new("kvs")
+        .about("mini kv server")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Config file, defaults to $KV_SERVER_CONFIG"),
+        )
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("ADDR")
+                .help("Listen address, overrides general.addr"),
+        )
+        .arg(
+            Arg::with_name("storage")
+                .long("storage")
+                .value_name("STORAGE")
+                .help("memtable or sleddb:<path>, overrides storage"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("FILTER")
+                .help("Log filter like info or mini_kv=debug, overrides log.filter"),
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .long("print-config")
+                .help("Print the effective config with secrets redacted and exit"),
+        )
+        .arg(
+            Arg::with_name("check-config")
+                .long("check-config")
+                .help("Check the effective config, report all problems and exit"),
+        )
+}
+
+/// 命令行参数覆盖的配置项
+fn cli_overrides(matches: &ArgMatches) -> Result<Vec<ConfigOverride>> {
+    let mut overrides = Vec::new();
+    if let Some(addr) = matches.value_of("addr") {
+        overrides.push(ConfigOverride::new("general.addr", addr, "--addr"));
+    }
+    if let Some(storage) = matches.value_of("storage") {
+        let storage: StorageConfig = storage.parse()?;
+        overrides.push(ConfigOverride::new("storage", toml::Value::try_from(storage)?, "--storage"));
+    }
+    if let Some(level) = matches.value_of("log-level") {
+        overrides.push(ConfigOverride::new("log.filter", level, "--log-level"));
+    }
+    Ok(overrides)
+}
+
+/// 配置了过滤规则时使用它，否则使用 RUST_LOG 环境变量
+fn log_filter(log: &LogConfig) -> Result<EnvFilter> {
+    match &log.filter {
+        Some(filter) => Ok(EnvFilter::try_new(filter)?),
+        None => Ok(EnvFilter::from_default_env()),
+    }
+}
+
+async fn reload_on_sighup(
+    path: String,
+    overrides: Vec<ConfigOverride>,
+    tx: mpsc::Sender<ConfigReload>,
+    filter: reload::Handle<EnvFilter, Registry>,
+) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        info!("Got SIGHUP, reload config from {}", path);
+        let config = match ServerConfig::load_with_overrides(&path, &overrides) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Failed to load config {}: {:?}", path, e);
+                continue;
+            }
+        };
+        if let Err(e) = config.validate() {
+            warn!("Ignore invalid config {}: {}", path, e);
+            continue;
+        }
+        let new_filter = match log_filter(&config.log) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Ignore config {} with invalid log filter: {:?}", path, e);
+                continue;
+            }
+        };
 
+        // 服务器应用了新的配置之后再更新日志的过滤规则，配置不合法时什么都不改变
+        let (applied, result) = oneshot::channel();
+        if tx.send(ConfigReload { config, applied }).await.is_err() {
+            break;
+        }
+        match result.await {
+            Ok(Ok(())) => {
+                if let Err(e) = filter.reload(new_filter) {
+                    warn!("Failed to reload log filter: {:?}", e);
+                }
+            }
+            Ok(Err(_)) => {}
+            Err(_) => break,
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
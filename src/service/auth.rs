@@ -0,0 +1,230 @@
+use glob::Pattern;
+use std::collections::HashMap;
+
+use crate::{command_request::RequestData, AuthConfig, CommandRequest, KvError, Permission};
+
+/// 根据 AuthConfig 做认证和权限检查
+#[derive(Debug)]
+pub struct Acl {
+    /// token -> 用户名
+    users: HashMap<String, String>,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    identity: String,
+    pattern: Pattern,
+    permissions: Vec<Permission>,
+}
+
+impl Acl {
+    pub fn new(config: &AuthConfig) -> Result<Self, KvError> {
+        let users = config
+            .users
+            .iter()
+            .map(|u| (u.token.clone(), u.name.clone()))
+            .collect();
+
+        let rules = config
+            .rules
+            .iter()
+            .map(|r| {
+                let pattern = Pattern::new(&r.pattern).map_err(|e| {
+                    KvError::InvalidConfig(format!("Invalid ACL pattern {}: {}", r.pattern, e))
+                })?;
+                Ok(Rule {
+                    identity: r.identity.clone(),
+                    pattern,
+                    permissions: r.permissions.clone(),
+                })
+            })
+            .collect::<Result<_, KvError>>()?;
+
+        Ok(Self { users, rules })
+    }
+
+    /// 使用 token 认证，返回对应的用户名
+    pub fn authenticate(&self, token: &str) -> Option<&str> {
+        self.users.get(token).map(|name| name.as_str())
+    }
+
+    /// 检查 identity 是否有权限执行 cmd
+    pub fn check(&self, identity: Option<&str>, cmd: &CommandRequest) -> Result<(), KvError> {
+        let (permission, resources) = match required_permission(cmd) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let identity = match identity {
+            Some(v) => v,
+            None => return Err(KvError::PermissionDenied("client is not authenticated".into())),
+        };
+
+        let is_pattern = matches!(
+            cmd.request_data,
+            Some(RequestData::Psubscribe(_)) | Some(RequestData::Punsubscribe(_))
+        );
+
+        // 命令涉及多个 topic 时，每个 topic 都需要有权限
+        for resource in resources {
+            let allowed = self.rules.iter().any(|rule| {
+                (rule.identity == "*" || rule.identity == identity)
+                    && rule.permissions.contains(&permission)
+                    && match is_pattern {
+                        true => covers(&rule.pattern, resource),
+                        false => rule.pattern.matches(resource),
+                    }
+            });
+
+            if !allowed {
+                return Err(KvError::PermissionDenied(format!(
+                    "{} has no {:?} permission on {}",
+                    identity, permission, resource
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// rule 是否能匹配 pattern 可能匹配到的所有 topic
+///
+/// 判断一个 glob 是否包含另一个 glob 比较复杂，这里只接受两种情况：
+/// pattern 不含通配符；或者 rule 是 "前缀*" 的形式，并且 pattern 以这个前缀开头
+fn covers(rule: &Pattern, pattern: &str) -> bool {
+    let has_wildcard = |s: &str| s.contains(['*', '?', '[']);
+    if !has_wildcard(pattern) {
+        return rule.matches(pattern);
+    }
+
+    let prefix = rule.as_str().trim_end_matches('*');
+    rule.as_str().ends_with('*') && !has_wildcard(prefix) && pattern.starts_with(prefix)
+}
+
+/// 执行命令需要的权限，以及作用的 table / topic。不需要权限的命令返回 None
+fn required_permission(cmd: &CommandRequest) -> Option<(Permission, Vec<&str>)> {
+    match &cmd.request_data {
+        Some(RequestData::Hget(v)) => Some((Permission::Read, vec![&v.table])),
+        Some(RequestData::Hgetall(v)) => Some((Permission::Read, vec![&v.table])),
+        Some(RequestData::Hmget(v)) => Some((Permission::Read, vec![&v.table])),
+        Some(RequestData::Hexist(v)) => Some((Permission::Read, vec![&v.table])),
+        Some(RequestData::Hmexist(v)) => Some((Permission::Read, vec![&v.table])),
+        Some(RequestData::Hset(v)) => Some((Permission::Write, vec![&v.table])),
+        Some(RequestData::Hmset(v)) => Some((Permission::Write, vec![&v.table])),
+        Some(RequestData::Hdel(v)) => Some((Permission::Write, vec![&v.table])),
+        Some(RequestData::Hmdel(v)) => Some((Permission::Write, vec![&v.table])),
+        Some(RequestData::Subscribe(v)) => Some((Permission::Subscribe, vec![&v.topic])),
+        Some(RequestData::Unsubscribe(v)) => Some((Permission::Subscribe, vec![&v.topic])),
+        Some(RequestData::Publish(v)) => Some((Permission::Publish, vec![&v.topic])),
+        Some(RequestData::Ack(v)) => Some((Permission::Subscribe, vec![&v.topic])),
+        Some(RequestData::ListSubscriptions(v)) => Some((Permission::Admin, vec![&v.prefix])),
+        Some(RequestData::ListTopics(v)) => Some((Permission::Admin, vec![&v.prefix])),
+        Some(RequestData::TopicInfo(v)) => Some((Permission::Admin, vec![&v.topic])),
+        Some(RequestData::NumSub(v)) => {
+            Some((Permission::Admin, v.topics.iter().map(|t| t.as_str()).collect()))
+        }
+        // 模式订阅要求 pattern 能匹配到的所有 topic 都有权限，见 covers
+        Some(RequestData::Psubscribe(v)) => Some((Permission::Subscribe, vec![&v.pattern])),
+        Some(RequestData::Punsubscribe(v)) => Some((Permission::Subscribe, vec![&v.pattern])),
+        Some(RequestData::Auth(_)) | Some(RequestData::Select(_)) | None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AclRule, UserConfig};
+
+    #[test]
+    fn acl_should_check_permissions() {
+        let acl = Acl::new(&auth_config()).unwrap();
+
+        let cmd = CommandRequest::new_hget("orders", "k1");
+        assert!(acl.check(Some("alice"), &cmd).is_ok());
+        assert!(acl.check(Some("bob"), &cmd).is_ok());
+
+        // 未认证的客户端不能执行任何需要权限的命令
+        assert!(matches!(acl.check(None, &cmd), Err(KvError::PermissionDenied(_))));
+
+        let cmd = CommandRequest::new_hset("orders", "k1", "v1".into());
+        assert!(acl.check(Some("alice"), &cmd).is_ok());
+        assert!(acl.check(Some("bob"), &cmd).is_err());
+
+        let cmd = CommandRequest::new_hset("users", "k1", "v1".into());
+        assert!(acl.check(Some("alice"), &cmd).is_err());
+
+        let cmd = CommandRequest::new_subscribe("orders.created");
+        assert!(acl.check(Some("bob"), &cmd).is_ok());
+        let cmd = CommandRequest::new_publish("orders.created", vec![]);
+        assert!(acl.check(Some("bob"), &cmd).is_err());
+
+        // Auth 命令不需要权限
+        assert!(acl.check(None, &CommandRequest::new_auth("token")).is_ok());
+    }
+
+    #[test]
+    fn acl_should_check_pattern_subscriptions() {
+        let acl = Acl::new(&auth_config()).unwrap();
+        let check = |pattern: &str| acl.check(Some("bob"), &CommandRequest::new_psubscribe(pattern));
+
+        assert!(check("orders.*").is_ok());
+        assert!(check("orders.created").is_ok());
+        assert!(check("orders.[ab]*").is_ok());
+
+        assert!(check("*").is_err());
+        assert!(check("order?").is_err());
+        assert!(check("users.*").is_err());
+
+        // 这些 pattern 作为字符串能被 rule 匹配，但是也能匹配到没有权限的 topic
+        let mut config = auth_config();
+        for pattern in ["orders.?", "[!u]*"] {
+            config.rules.push(AclRule {
+                identity: "carol".into(),
+                pattern: pattern.into(),
+                permissions: vec![Permission::Subscribe],
+            });
+        }
+        let acl = Acl::new(&config).unwrap();
+        let check = |pattern: &str| acl.check(Some("carol"), &CommandRequest::new_psubscribe(pattern));
+        assert!(check("orders.*").is_ok());
+        assert!(check("orders.a").is_ok());
+        assert!(check("?sers").is_err());
+        assert!(check("users").is_err());
+    }
+
+    #[test]
+    fn acl_with_invalid_pattern_should_be_rejected() {
+        let mut config = auth_config();
+        config.rules[0].pattern = "orders[".into();
+        assert!(matches!(Acl::new(&config), Err(KvError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn acl_should_authenticate_token() {
+        let acl = Acl::new(&auth_config()).unwrap();
+        assert_eq!(acl.authenticate("alice-token"), Some("alice"));
+        assert_eq!(acl.authenticate("bad-token"), None);
+    }
+
+    pub fn auth_config() -> AuthConfig {
+        AuthConfig {
+            users: vec![UserConfig {
+                name: "alice".into(),
+                token: "alice-token".into(),
+            }],
+            rules: vec![
+                AclRule {
+                    identity: "alice".into(),
+                    pattern: "orders*".into(),
+                    permissions: vec![Permission::Read, Permission::Write],
+                },
+                AclRule {
+                    identity: "*".into(),
+                    pattern: "orders*".into(),
+                    permissions: vec![Permission::Read, Permission::Subscribe],
+                },
+            ],
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::{self, Instant};
+use tracing::{debug, warn};
+
+use crate::CommandResponse;
+
+/// 消息发出后，超过这个时间没有 Ack 就重新投递
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 每个消费组最多保留的没有 Ack 的消息数，超过时丢弃最旧的消息
+const MAX_PENDING: usize = 10_000;
+
+/// 消费组：topic 的每条消息只投递给组里的一个消费者，没有 Ack 的消息超时后重新投递
+///
+/// 投递时使用 try_send，消费者的 channel 满了就换下一个消费者，都投递不了的消息留到下次重试
+pub struct ConsumerGroup {
+    ack_timeout: Duration,
+    consumers: Mutex<Vec<Consumer>>,
+    /// 轮询消费者的位置
+    cursor: AtomicUsize,
+    next_message_id: AtomicU64,
+    /// 还没有 Ack 的消息，按照 message id 排序
+    pending: Mutex<BTreeMap<u64, Pending>>,
+}
+
+struct Consumer {
+    name: String,
+    id: u64,
+    tx: mpsc::Sender<Arc<CommandResponse>>,
+}
+
+struct Pending {
+    /// 最后一次投递给的消费者
+    consumer: Option<String>,
+    deadline: Instant,
+    res: Arc<CommandResponse>,
+}
+
+impl ConsumerGroup {
+    /// 创建消费组，同时启动一个定时重新投递超时消息的任务，消费组被释放后任务退出
+    pub fn new(ack_timeout: Duration) -> Arc<Self> {
+        let group = Arc::new(Self {
+            ack_timeout,
+            consumers: Mutex::new(Vec::new()),
+            cursor: AtomicUsize::new(0),
+            next_message_id: AtomicU64::new(1),
+            pending: Mutex::new(BTreeMap::new()),
+        });
+
+        let weak = Arc::downgrade(&group);
+        let period = (ack_timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        tokio::spawn(redeliver_loop(weak, period));
+        group
+    }
+
+    /// 加入一个消费者，同名消费者之前没有 Ack 的消息立刻重新投递给它
+    pub fn join(&self, name: String, id: u64, tx: mpsc::Sender<Arc<CommandResponse>>) {
+        let now = Instant::now();
+        for pending in self.pending.lock().unwrap().values_mut() {
+            if pending.consumer.as_deref() == Some(name.as_str()) {
+                pending.deadline = now;
+            }
+        }
+        self.consumers.lock().unwrap().push(Consumer { name, id, tx });
+        self.redeliver();
+    }
+
+    /// 移除一个消费者，它没有 Ack 的消息会在超时后投递给其他消费者
+    pub fn leave(&self, id: u64) -> bool {
+        let mut consumers = self.consumers.lock().unwrap();
+        let len = consumers.len();
+        consumers.retain(|c| c.id != id);
+        consumers.len() != len
+    }
+
+    /// 组里是否已经没有消费者了
+    pub fn is_empty(&self) -> bool {
+        self.consumers.lock().unwrap().is_empty()
+    }
+
+    /// 组里所有消费者的 subscription id
+    pub fn ids(&self) -> Vec<u64> {
+        self.consumers.lock().unwrap().iter().map(|c| c.id).collect()
+    }
+
+    /// 给消息分配 message id，并投递给一个消费者，返回是否投递成功
+    /// 没有投递成功的消息留在组里，之后重试；组里没有消费者时直接丢弃消息
+    pub fn publish(&self, res: &CommandResponse) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+
+        let message_id = self.next_message_id.fetch_add(1, Ordering::Relaxed);
+        let mut res = res.clone();
+        res.message_id = message_id;
+        let res = Arc::new(res);
+
+        // 先记录下来再投递，避免消费者在记录之前就 Ack 了
+        {
+            let mut pending = self.pending.lock().unwrap();
+            pending.insert(
+                message_id,
+                Pending {
+                    consumer: None,
+                    deadline: Instant::now(),
+                    res: res.clone(),
+                },
+            );
+            if pending.len() > MAX_PENDING {
+                if let Some((id, _)) = pending.pop_first() {
+                    warn!("Too many pending messages, drop message {}", id);
+                }
+            }
+        }
+        let consumer = self.deliver(&res);
+        let delivered = consumer.is_some();
+        self.delivered(message_id, consumer);
+        delivered
+    }
+
+    /// Ack 消息，返回确认成功的数量
+    pub fn ack(&self, ids: &[u64]) -> usize {
+        let mut pending = self.pending.lock().unwrap();
+        ids.iter().filter(|id| pending.remove(id).is_some()).count()
+    }
+
+    /// 重新投递所有超时的消息
+    fn redeliver(&self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(id, p)| (*id, p.res.clone()))
+            .collect();
+
+        for (message_id, res) in expired {
+            debug!("Redeliver message {}", message_id);
+            let consumer = self.deliver(&res);
+            self.delivered(message_id, consumer);
+        }
+    }
+
+    /// 轮询消费者，返回成功投递的消费者名字
+    fn deliver(&self, res: &Arc<CommandResponse>) -> Option<String> {
+        let mut consumers = self.consumers.lock().unwrap();
+        let mut tries = consumers.len();
+        while tries > 0 && !consumers.is_empty() {
+            tries -= 1;
+            let index = self.cursor.fetch_add(1, Ordering::Relaxed) % consumers.len();
+            match consumers[index].tx.try_send(res.clone()) {
+                Ok(_) => return Some(consumers[index].name.clone()),
+                Err(TrySendError::Full(_)) => continue,
+                Err(TrySendError::Closed(_)) => {
+                    warn!("Consumer {} is closed", consumers[index].name);
+                    consumers.remove(index);
+                }
+            }
+        }
+        None
+    }
+
+    /// 更新投递结果，没有投递成功的消息在下一次重试时再投递
+    fn delivered(&self, message_id: u64, consumer: Option<String>) {
+        if let Some(pending) = self.pending.lock().unwrap().get_mut(&message_id) {
+            pending.deadline = match consumer {
+                Some(_) => Instant::now() + self.ack_timeout,
+                None => Instant::now(),
+            };
+            pending.consumer = consumer.or_else(|| pending.consumer.take());
+        }
+    }
+}
+
+async fn redeliver_loop(group: Weak<ConsumerGroup>, period: Duration) {
+    let mut interval = time::interval(period);
+    loop {
+        interval.tick().await;
+        match group.upgrade() {
+            Some(group) => group.redeliver(),
+            None => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    #[tokio::test]
+    async fn consumer_group_should_deliver_to_one_consumer() {
+        let group = ConsumerGroup::new(Duration::from_millis(50));
+        let (tx1, mut rx1) = mpsc::channel(8);
+        let (tx2, mut rx2) = mpsc::channel(8);
+        group.join("c1".into(), 1, tx1);
+        group.join("c2".into(), 2, tx2);
+
+        let v: Value = "hello".into();
+        group.publish(&v.clone().into());
+        group.publish(&v.into());
+
+        // 两条消息分别投递给两个消费者
+        let m1 = rx1.recv().await.unwrap();
+        let m2 = rx2.recv().await.unwrap();
+        assert_ne!(m1.message_id, m2.message_id);
+
+        // c1 Ack 了，c2 没有 Ack 的消息超时后重新投递
+        assert_eq!(group.ack(&[m1.message_id]), 1);
+        let m = tokio::select! {
+            Some(m) = rx1.recv() => m,
+            Some(m) = rx2.recv() => m,
+        };
+        assert_eq!(m.message_id, m2.message_id);
+        assert_eq!(group.ack(&[m2.message_id, m2.message_id]), 1);
+    }
+
+    #[tokio::test]
+    async fn consumer_group_should_redeliver_to_reconnected_consumer() {
+        let group = ConsumerGroup::new(Duration::from_secs(60));
+        let (tx, rx) = mpsc::channel(8);
+        group.join("c1".into(), 1, tx);
+        let v: Value = "hello".into();
+        assert!(group.publish(&v.into()));
+        drop(rx);
+        assert!(group.leave(1));
+
+        // 同名的消费者重新加入后，立刻收到之前没有 Ack 的消息
+        let (tx, mut rx) = mpsc::channel(8);
+        group.join("c1".into(), 2, tx);
+        assert_eq!(rx.recv().await.unwrap().message_id, 1);
+    }
+
+    #[tokio::test]
+    async fn consumer_group_should_bound_pending_messages() {
+        let group = ConsumerGroup::new(Duration::from_secs(60));
+        let (tx, _rx) = mpsc::channel(8);
+        group.join("c1".into(), 1, tx);
+
+        // 消费者一直不 Ack，最多保留 MAX_PENDING 条消息
+        let v: Value = "hello".into();
+        let res = v.into();
+        for _ in 0..MAX_PENDING + 10 {
+            group.publish(&res);
+        }
+        let pending = group.pending.lock().unwrap();
+        assert_eq!(pending.len(), MAX_PENDING);
+        assert_eq!(pending.keys().next(), Some(&11));
+        drop(pending);
+
+        // 没有消费者时不再保存新的消息
+        assert!(group.leave(1));
+        assert!(!group.publish(&res));
+        assert_eq!(group.pending.lock().unwrap().len(), MAX_PENDING);
+    }
+}
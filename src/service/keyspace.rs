@@ -0,0 +1,156 @@
+use glob::Pattern;
+use http::StatusCode;
+
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, KeyspaceConfig, KvError, Value,
+};
+
+use super::namespace::SEPARATOR;
+
+/// keyspace 事件 topic 的前缀，完整的 topic 是 `__keyspace__:{table}`
+pub const KEYSPACE_PREFIX: &str = "__keyspace__:";
+
+/// table 中的 key 被修改时，生成发布到 `__keyspace__:{table}` 的事件
+///
+/// 事件的数据是 ["set", key, value] 或者 ["del", key]，不包含 value 时只有前两项
+/// 和 Publish 一样，事件是异步发布的，同一个 key 上并发的修改不保证事件的顺序
+#[derive(Debug)]
+pub struct Keyspace {
+    tables: Vec<Pattern>,
+    include_value: bool,
+}
+
+impl Keyspace {
+    pub fn new(config: &KeyspaceConfig) -> Result<Self, KvError> {
+        let tables = config
+            .tables
+            .iter()
+            .map(|t| {
+                Pattern::new(t).map_err(|e| {
+                    KvError::InvalidConfig(format!("Invalid keyspace table {}: {}", t, e))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            tables,
+            include_value: config.include_value,
+        })
+    }
+
+    /// 根据执行成功的写命令，生成 (topic, 事件数据) 的列表
+    pub fn events(&self, cmd: &CommandRequest, res: &CommandResponse) -> Vec<(String, Vec<Value>)> {
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return vec![];
+        }
+
+        let mut events = vec![];
+        match &cmd.request_data {
+            Some(RequestData::Hset(v)) => {
+                if let Some(pair) = &v.pair {
+                    self.push_set(&mut events, &v.table, &pair.key, pair.value.as_ref());
+                }
+            }
+            Some(RequestData::Hmset(v)) => {
+                for pair in v.pairs.iter() {
+                    self.push_set(&mut events, &v.table, &pair.key, pair.value.as_ref());
+                }
+            }
+            // 删除返回旧的 value，只有 key 存在时才产生事件
+            Some(RequestData::Hdel(v)) if res.values.iter().any(|v| v != &Value::default()) => {
+                self.push_del(&mut events, &v.table, &v.key);
+            }
+            Some(RequestData::Hmdel(v)) => {
+                for (key, old) in v.keys.iter().zip(res.values.iter()) {
+                    if old != &Value::default() {
+                        self.push_del(&mut events, &v.table, key);
+                    }
+                }
+            }
+            _ => {}
+        }
+        events
+    }
+
+    fn push_set(
+        &self,
+        events: &mut Vec<(String, Vec<Value>)>,
+        table: &str,
+        key: &str,
+        value: Option<&Value>,
+    ) {
+        if let Some(topic) = self.topic(table) {
+            let mut data = vec!["set".into(), key.into()];
+            if self.include_value {
+                data.push(value.cloned().unwrap_or_default());
+            }
+            events.push((topic, data));
+        }
+    }
+
+    fn push_del(&self, events: &mut Vec<(String, Vec<Value>)>, table: &str, key: &str) {
+        if let Some(topic) = self.topic(table) {
+            events.push((topic, vec!["del".into(), key.into()]));
+        }
+    }
+
+    /// 开启了通知的 table 对应的 topic，namespace 的前缀放在 topic 的最前面
+    /// 这样同一个 namespace 里的客户端可以直接订阅 `__keyspace__:{table}`
+    fn topic(&self, table: &str) -> Option<String> {
+        let (namespace, name) = match table.split_once(SEPARATOR) {
+            Some((ns, name)) => (Some(ns), name),
+            None => (None, table),
+        };
+
+        if !self.tables.iter().any(|p| p.matches(name)) {
+            return None;
+        }
+
+        Some(match namespace {
+            Some(ns) => format!("{}{}{}{}", ns, SEPARATOR, KEYSPACE_PREFIX, name),
+            None => format!("{}{}", KEYSPACE_PREFIX, name),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyspace_should_generate_events() {
+        let keyspace = Keyspace::new(&KeyspaceConfig {
+            tables: vec!["orders*".into()],
+            include_value: true,
+        })
+        .unwrap();
+
+        let cmd = CommandRequest::new_hset("orders", "k1", "v1".into());
+        let events = keyspace.events(&cmd, &Value::default().into());
+        assert_eq!(
+            events,
+            vec![("__keyspace__:orders".into(), vec!["set".into(), "k1".into(), "v1".into()])]
+        );
+
+        // 没有开启通知的 table 没有事件
+        let cmd = CommandRequest::new_hset("users", "k1", "v1".into());
+        assert!(keyspace.events(&cmd, &Value::default().into()).is_empty());
+
+        // 只有存在的 key 被删除时才有事件
+        let cmd = CommandRequest::new_hmdel("team-a/orders", vec!["k1".into(), "k2".into()]);
+        let res: CommandResponse = vec!["v1".into(), Value::default()].into();
+        assert_eq!(
+            keyspace.events(&cmd, &res),
+            vec![("team-a/__keyspace__:orders".into(), vec!["del".into(), "k1".into()])]
+        );
+    }
+
+    #[test]
+    fn keyspace_with_invalid_pattern_should_be_rejected() {
+        let config = KeyspaceConfig {
+            tables: vec!["orders[".into()],
+            include_value: false,
+        };
+        assert!(matches!(Keyspace::new(&config), Err(KvError::InvalidConfig(_))));
+    }
+}
@@ -0,0 +1,143 @@
+use futures::{stream, StreamExt};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::debug;
+
+use crate::{metrics, CommandRequest, CommandResponse, Storage};
+
+use super::{
+    Hook, HookMut, InterceptHook, Notify, NotifyMut, Service, Session, StreamingResponse,
+};
+
+/// 包裹在命令处理流程外面的中间件
+///
+/// layer 可以查看或修改 CommandRequest，直接返回 response 而不调用 next，
+/// 或者对 next 返回的 StreamingResponse 做进一步处理
+pub trait Layer<Store>: Send + Sync + 'static {
+    fn call(
+        &self,
+        cmd: CommandRequest,
+        session: &Arc<Session>,
+        next: Next<Store>,
+    ) -> StreamingResponse;
+}
+
+impl<Store, F> Layer<Store> for F
+where
+    F: Fn(CommandRequest, &Arc<Session>, Next<Store>) -> StreamingResponse + Send + Sync + 'static,
+{
+    fn call(
+        &self,
+        cmd: CommandRequest,
+        session: &Arc<Session>,
+        next: Next<Store>,
+    ) -> StreamingResponse {
+        self(cmd, session, next)
+    }
+}
+
+/// 调用链中剩下的部分，可以被 move 到 future 里异步调用
+pub struct Next<Store> {
+    service: Service<Store>,
+    index: usize,
+}
+
+impl<Store: Storage> Next<Store> {
+    pub(super) fn new(service: Service<Store>) -> Self {
+        Self { service, index: 0 }
+    }
+
+    /// 调用下一个 layer，所有 layer 都调用过后执行命令
+    pub fn run(self, cmd: CommandRequest, session: &Arc<Session>) -> StreamingResponse {
+        match self.service.inner.layers.get(self.index) {
+            Some(layer) => {
+                let next = Self {
+                    service: self.service.clone(),
+                    index: self.index + 1,
+                };
+                layer.call(cmd, session, next)
+            }
+            None => self.service.process(cmd, session),
+        }
+    }
+}
+
+/// 记录每种命令的次数，以及产生第一个 response 的时间
+pub(super) struct MetricsLayer;
+
+impl<Store: Storage> Layer<Store> for MetricsLayer {
+    fn call(
+        &self,
+        cmd: CommandRequest,
+        session: &Arc<Session>,
+        next: Next<Store>,
+    ) -> StreamingResponse {
+        let name = metrics::command_name(&cmd);
+        let start = Instant::now();
+        let mut recorded = false;
+        Box::pin(next.run(cmd, session).inspect(move |data| {
+            if !recorded {
+                recorded = true;
+                let status = data.status.to_string();
+                metrics::COMMANDS.with_label_values(&[name, &status]).inc();
+                metrics::COMMAND_LATENCY
+                    .with_label_values(&[name])
+                    .observe(start.elapsed().as_secs_f64());
+            }
+        }))
+    }
+}
+
+/// 通过 ServiceInner::fn_xxx 注册的回调
+#[derive(Default)]
+pub(super) struct Hooks {
+    pub on_received: Vec<Hook<CommandRequest>>,
+    pub on_intercept: Vec<InterceptHook>,
+    pub on_executed: Vec<Hook<CommandResponse>>,
+    pub on_before_send: Vec<HookMut<CommandResponse>>,
+}
+
+/// 在命令处理流程中触发 Hooks 的 layer
+pub(super) struct HookLayer(Arc<Hooks>);
+
+impl HookLayer {
+    pub fn new(hooks: Hooks) -> Self {
+        Self(Arc::new(hooks))
+    }
+}
+
+impl<Store: Storage> Layer<Store> for HookLayer {
+    fn call(
+        &self,
+        cmd: CommandRequest,
+        session: &Arc<Session>,
+        next: Next<Store>,
+    ) -> StreamingResponse {
+        self.0.on_received.notify(&cmd);
+
+        // intercept hook 可以直接返回 response，跳过后续的处理
+        let res: StreamingResponse = match self.0.on_intercept.iter().find_map(|f| f(&cmd)) {
+            Some(res) => {
+                debug!("Intercepted response: {:?}", res);
+                Box::pin(stream::once(async { Arc::new(res) }))
+            }
+            None => next.run(cmd, session),
+        };
+
+        if self.0.on_executed.is_empty() && self.0.on_before_send.is_empty() {
+            return res;
+        }
+
+        // stream 里的每个 response 都会触发 on_executed 和 on_before_send
+        let hooks = Arc::clone(&self.0);
+        Box::pin(res.map(move |mut res| {
+            hooks.on_executed.notify(&res);
+            if !hooks.on_before_send.is_empty() {
+                // 订阅的 response 可能被多个订阅者共享，修改时需要 clone 一份
+                hooks.on_before_send.notify(Arc::make_mut(&mut res));
+                debug!("Modified response: {:?}", res);
+            }
+            res
+        }))
+    }
+}
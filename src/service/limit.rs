@@ -0,0 +1,238 @@
+use dashmap::DashMap;
+use futures::stream;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use crate::{CommandRequest, KvError, LimitConfig, RateLimitConfig, Storage};
+
+use super::{Layer, Next, Session, StreamingResponse};
+
+/// 超过这个数量的 token bucket 时，清理掉已经填满的 bucket
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// 连接数限制和命令的速率限制
+#[derive(Debug, Default)]
+pub struct Limits {
+    config: RwLock<LimitConfig>,
+    ips: Arc<DashMap<IpAddr, usize>>,
+    identities: Arc<DashMap<String, usize>>,
+    buckets: DashMap<String, TokenBucket>,
+}
+
+/// 连接占用的名额，drop 时归还
+#[derive(Debug)]
+pub struct ConnectionPermit<K: Eq + Hash> {
+    counter: Arc<DashMap<K, usize>>,
+    key: K,
+}
+
+impl Limits {
+    pub fn new(config: LimitConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            ..Default::default()
+        }
+    }
+
+    /// 更新限制，已经建立的连接不受影响，令牌桶中剩余的 token 保留
+    pub fn update(&self, config: LimitConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// 每个连接上最多同时打开的 yamux stream 数
+    pub fn max_streams(&self) -> Option<usize> {
+        self.config.read().unwrap().max_streams_per_connection
+    }
+
+    /// 新的 TCP 连接进来时，检查这个 IP 的连接数
+    pub fn acquire_ip(&self, ip: IpAddr) -> Result<ConnectionPermit<IpAddr>, KvError> {
+        let max = self.config.read().unwrap().max_connections_per_ip;
+        acquire(&self.ips, ip, max)
+    }
+
+    /// 确定客户端身份后，检查这个身份的连接数
+    pub fn acquire_identity(&self, identity: &str) -> Result<ConnectionPermit<String>, KvError> {
+        let max = self.config.read().unwrap().max_connections_per_identity;
+        acquire(&self.identities, identity.to_owned(), max)
+    }
+
+    /// 从客户端的 token bucket 中取一个 token，取不到说明请求太频繁
+    pub fn check_rate(&self, client: &str) -> Result<(), KvError> {
+        let config = self.config.read().unwrap();
+        let config = match &config.rate_limit {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        if self.buckets.len() > MAX_IDLE_BUCKETS {
+            self.buckets.retain(|_, bucket| !bucket.is_full(config));
+        }
+
+        let mut bucket = self
+            .buckets
+            .entry(client.to_owned())
+            .or_insert_with(|| TokenBucket::new(config));
+        if bucket.take(config) {
+            Ok(())
+        } else {
+            Err(KvError::RateLimited(format!(
+                "{} exceeds {} commands per second",
+                client, config.rate
+            )))
+        }
+    }
+}
+
+fn acquire<K: Eq + Hash + Clone + std::fmt::Display>(
+    counter: &Arc<DashMap<K, usize>>,
+    key: K,
+    max: Option<usize>,
+) -> Result<ConnectionPermit<K>, KvError> {
+    let mut count = counter.entry(key.clone()).or_default();
+    if matches!(max, Some(max) if *count >= max) {
+        drop(count);
+        counter.remove_if(&key, |_, count| *count == 0);
+        return Err(KvError::RateLimited(format!("too many connections from {}", key)));
+    }
+    *count += 1;
+    drop(count);
+
+    Ok(ConnectionPermit {
+        counter: Arc::clone(counter),
+        key,
+    })
+}
+
+impl<K: Eq + Hash> Drop for ConnectionPermit<K> {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.counter.get_mut(&self.key) {
+            *count -= 1;
+        }
+        self.counter.remove_if(&self.key, |_, count| *count == 0);
+    }
+}
+
+/// 令牌桶，每秒补充 rate 个 token，最多攒 burst 个
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            tokens: config.burst() as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, config: &RateLimitConfig) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.rate as f64).min(config.burst() as f64);
+        self.last = now;
+    }
+
+    fn take(&mut self, config: &RateLimitConfig) -> bool {
+        self.refill(config);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&self, config: &RateLimitConfig) -> bool {
+        let elapsed = self.last.elapsed().as_secs_f64();
+        self.tokens + elapsed * config.rate as f64 >= config.burst() as f64
+    }
+}
+
+/// 按客户端做速率限制的 layer，被限制的请求返回 429
+pub(super) struct RateLimitLayer(pub Arc<Limits>);
+
+impl<Store: Storage> Layer<Store> for RateLimitLayer {
+    fn call(
+        &self,
+        cmd: CommandRequest,
+        session: &Arc<Session>,
+        next: Next<Store>,
+    ) -> StreamingResponse {
+        // 认证过的客户端按身份限制，否则按 IP 限制
+        let client = match (session.identity(), session.peer()) {
+            (Some(identity), _) => Some(format!("identity:{}", identity)),
+            (None, Some(peer)) => Some(format!("ip:{}", peer.ip())),
+            (None, None) => None,
+        };
+
+        if let Some(client) = client {
+            if let Err(e) = self.0.check_rate(&client) {
+                let res = e.into();
+                return Box::pin(stream::once(async { Arc::new(res) }));
+            }
+        }
+        next.run(cmd, session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_limit_should_work() {
+        let limits = Limits::new(LimitConfig {
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        });
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        let p1 = limits.acquire_ip(ip).unwrap();
+        let _p2 = limits.acquire_ip(ip).unwrap();
+        assert!(matches!(limits.acquire_ip(ip), Err(KvError::RateLimited(_))));
+
+        // 其他 IP 不受影响
+        assert!(limits.acquire_ip("127.0.0.2".parse().unwrap()).is_ok());
+
+        // 连接断开后归还名额
+        drop(p1);
+        assert!(limits.acquire_ip(ip).is_ok());
+    }
+
+    #[test]
+    fn rate_limit_should_work() {
+        let limits = Limits::new(LimitConfig {
+            rate_limit: Some(RateLimitConfig {
+                rate: 1,
+                burst: Some(2),
+            }),
+            ..Default::default()
+        });
+
+        assert!(limits.check_rate("alice").is_ok());
+        assert!(limits.check_rate("alice").is_ok());
+        assert!(matches!(limits.check_rate("alice"), Err(KvError::RateLimited(_))));
+        assert!(limits.check_rate("bob").is_ok());
+    }
+
+    #[test]
+    fn updated_limits_should_apply_immediately() {
+        let limits = Limits::new(LimitConfig {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        });
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let _p1 = limits.acquire_ip(ip).unwrap();
+        assert!(limits.acquire_ip(ip).is_err());
+
+        limits.update(LimitConfig {
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        });
+        assert!(limits.acquire_ip(ip).is_ok());
+    }
+}
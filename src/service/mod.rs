@@ -1,15 +1,41 @@
 use crate::{
-    command_request::RequestData, CommandRequest, CommandResponse, KvError, MemTable, Storage,
+    command_request::RequestData, metrics, BackpressureConfig, CommandRequest, CommandResponse,
+    DurableConfig, KvError, MemTable,
+    Storage,
 };
-use futures::stream;
-use std::sync::Arc;
-use tracing::{debug, instrument};
+use futures::{future, stream, StreamExt};
+use http::StatusCode;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+use tokio::{task, time};
+use tracing::{debug, instrument, warn};
 
+mod auth;
 mod command_service;
+mod consumer_group;
+mod keyspace;
+mod layer;
+mod limit;
+mod namespace;
+mod session;
+mod subscriber;
 mod topic;
+mod topic_log;
 mod topic_service;
 
-pub use topic::{Broadcaster, Topic};
+pub use auth::Acl;
+pub use keyspace::{Keyspace, KEYSPACE_PREFIX};
+pub use layer::{Layer, Next};
+use layer::{HookLayer, Hooks, MetricsLayer};
+pub use limit::{ConnectionPermit, Limits};
+use limit::RateLimitLayer;
+pub use namespace::{Namespaces, UsageDelta};
+pub use session::Session;
+pub use subscriber::Backpressure;
+pub use topic::{Broadcaster, Delivery, Topic};
+pub(crate) use topic_log::is_log_table;
+pub use topic_log::TopicLog;
 pub use topic_service::{StreamingResponse, TopicService};
 
 /// 对 Command 的对象处理
@@ -34,72 +60,516 @@ impl<Store> Clone for Service<Store> {
 
 impl<Store: Storage> Service<Store> {
 
-    #[instrument(name = "service_execute", skip_all)]
+    /// 使用一个匿名的 Session 执行命令
     pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
+        self.execute_with_session(cmd, &Default::default())
+    }
+
+    /// 为新的连接创建 Session，如果 identity 绑定了 namespace，则直接使用它
+    /// 如果 identity 的连接数超过限制，返回错误
+    pub fn create_session(
+        &self,
+        peer: Option<SocketAddr>,
+        identity: Option<String>,
+    ) -> Result<Arc<Session>, KvError> {
+        let mut session = Session::new(identity.clone());
+        if let Some(peer) = peer {
+            session = session.with_peer(peer);
+        }
+        if let Some(identity) = identity {
+            if let Some(limits) = &self.inner.limits {
+                session.set_permit(limits.acquire_identity(&identity)?);
+            }
+            if let Some(namespaces) = &self.inner.namespaces {
+                session.set_namespace(namespaces.binding(&identity).map(|ns| ns.to_owned()));
+            }
+        }
+        Ok(Arc::new(session))
+    }
+
+    /// 连接数和速率限制
+    pub fn limits(&self) -> Option<&Limits> {
+        self.inner.limits.as_deref()
+    }
+
+    /// 替换认证和权限配置，None 表示关闭认证
+    /// 已经认证过的连接保留原来的身份，之后的命令使用新的权限规则检查
+    pub fn set_acl(&self, acl: Option<Acl>) {
+        *self.inner.acl.write().unwrap() = acl.map(Arc::new);
+    }
+
+    /// 依次经过所有的 layer 后执行命令
+    #[instrument(name = "service_execute", skip_all)]
+    pub fn execute_with_session(&self, cmd: CommandRequest, session: &Arc<Session>) -> StreamingResponse {
+        Next::new(self.clone()).run(cmd, session)
+    }
+
+    /// 更新需要在采集时才计算的 metrics
+    pub async fn collect_metrics(&self) {
+        metrics::TOPICS.set(self.broadcaster.topic_count() as _);
+        metrics::SUBSCRIPTIONS.set(self.broadcaster.subscription_count() as _);
+
+        // 有的 storage 计算大小需要遍历（比如 sled），不能阻塞 runtime
+        let store = self.inner.store.clone();
+        let size = task::spawn_blocking(move || store.size())
+            .await
+            .map_err(|e| KvError::Internal(e.to_string()))
+            .and_then(|v| v);
+        match size {
+            Ok(size) => {
+                metrics::STORAGE_SIZE.with_label_values(&["keys"]).set(size.keys as _);
+                metrics::STORAGE_SIZE.with_label_values(&["bytes"]).set(size.bytes as _);
+            }
+            Err(e) => warn!("Failed to get storage size: {:?}", e),
+        }
+    }
+
+    fn process(&self, mut cmd: CommandRequest, session: &Arc<Session>) -> StreamingResponse {
         debug!("Got request: {:?}", cmd);
-        self.inner.on_received.notify(&cmd);
-        let mut res = dispatch(cmd.clone(), &self.inner.store);
 
+        // Auth / Select 命令直接在这里处理，它们需要修改 session
+        match &cmd.request_data {
+            Some(RequestData::Auth(param)) => {
+                let res = self.authenticate(&param.token, session);
+                return self.respond(cmd, res);
+            }
+            Some(RequestData::Select(param)) => {
+                let res = self.select(&param.namespace, session);
+                return self.respond(cmd, res);
+            }
+            _ => {}
+        }
+
+        // 没有权限的命令在 dispatch 之前直接返回 403
+        if let Some(acl) = self.inner.current_acl() {
+            if let Err(e) = acl.check(session.identity().as_deref(), &cmd) {
+                return self.respond(cmd, e.into());
+            }
+        }
+
+        // topic 的消息日志保存在 storage 中，不能被客户端直接读写
+        if let Some(table) = cmd.table().filter(|t| is_log_table(t)) {
+            let res = KvError::PermissionDenied(format!("table {} is reserved", table)).into();
+            return self.respond(cmd, res);
+        }
+
+        // 给 table / topic 加上 namespace 的前缀
+        let namespace = session.namespace();
+        if let Some(namespaces) = &self.inner.namespaces {
+            if let Err(e) = namespaces.apply(&mut cmd, namespace.as_deref()) {
+                return self.respond(cmd, e.into());
+            }
+        }
+
+        // 只能取消这个连接自己创建的订阅，不存在和不属于自己的订阅返回同样的错误
+        let unsubscribe = match &cmd.request_data {
+            Some(RequestData::Unsubscribe(v)) => Some(v.id),
+            Some(RequestData::Punsubscribe(v)) => Some(v.id),
+            _ => None,
+        };
+        if let Some(id) = unsubscribe.filter(|id| !session.owns_subscription(*id)) {
+            let res = KvError::NotFound(format!("subscription {}", id)).into();
+            return self.respond(cmd, res);
+        }
+        let subscribe = matches!(
+            cmd.request_data,
+            Some(RequestData::Subscribe(_)) | Some(RequestData::Psubscribe(_))
+        );
+        // 订阅返回的是长期的 stream，deadline 没有意义
+        if subscribe && cmd.deadline().is_some() {
+            let res = KvError::InvalidCommand("subscription doesn't support deadline".into());
+            return self.respond(cmd, res.into());
+        }
+        // 由 dispatch_stream 处理的命令，deadline 在拿到结果时检查
+        let streaming = cmd.table().is_none();
+
+        let prefix = namespace.as_ref().map(|ns| format!("{}{}", ns, namespace::SEPARATOR));
+        // 这些命令返回的 pairs 的 key 是 topic
+        let list = matches!(
+            cmd.request_data,
+            Some(RequestData::ListSubscriptions(_))
+                | Some(RequestData::ListTopics(_))
+                | Some(RequestData::NumSub(_))
+        );
+        // 开启 namespace 后，没有 namespace 的客户端看不到其他 namespace 中的 topic
+        let hide_namespaced = list && self.inner.namespaces.is_some() && namespace.is_none();
+        let res = match cmd.deadline() {
+            // 有 deadline 的请求放到 blocking 线程里执行，开始执行前已经超时的请求返回 408
+            // 开始执行后命令不会被取消：只读命令超时直接返回 408，写命令等它执行完返回真实的结果
+            Some(timeout) => {
+                let svc = self.clone();
+                let deadline = time::Instant::now() + timeout;
+                let streaming = streaming.then_some(deadline);
+                let read_only = cmd.is_read_only();
+                let fut = async move {
+                    let inner = Arc::clone(&svc.inner);
+                    let cmd1 = cmd.clone();
+                    let mut task = task::spawn_blocking(move || {
+                        if time::Instant::now() >= deadline {
+                            return None;
+                        }
+                        Some(inner.dispatch(cmd1, namespace.as_deref()))
+                    });
+                    let result = match time::timeout_at(deadline, &mut task).await {
+                        Ok(result) => result,
+                        Err(_) if read_only => Ok(None),
+                        Err(_) => task.await,
+                    };
+                    let res = match result {
+                        Ok(Some(res)) => res,
+                        Ok(None) => KvError::Timeout(format!("deadline {:?} exceeded", timeout)).into(),
+                        Err(e) => KvError::Internal(e.to_string()).into(),
+                    };
+                    match streaming {
+                        Some(deadline) => with_deadline(svc.finish(cmd, res), deadline, timeout),
+                        None => svc.finish(cmd, res),
+                    }
+                };
+                Box::pin(stream::once(fut).flatten())
+            }
+            None => {
+                let res = self.inner.dispatch(cmd.clone(), namespace.as_deref());
+                self.finish(cmd, res)
+            }
+        };
+
+        let res = if subscribe {
+            track_subscription(res, session)
+        } else {
+            res
+        };
+
+        match prefix {
+            Some(prefix) => strip_topic_prefix(res, prefix, list),
+            None if hide_namespaced => hide_namespaced_topics(res),
+            None => res,
+        }
+    }
+
+    fn authenticate(&self, token: &str, session: &Session) -> CommandResponse {
+        let acl = match self.inner.current_acl() {
+            Some(acl) => acl,
+            None => return KvError::InvalidCommand("Authentication is not enabled".into()).into(),
+        };
+
+        match acl.authenticate(token) {
+            Some(name) => {
+                // 切换身份时占用新身份的连接名额
+                if let Some(limits) = &self.inner.limits {
+                    if session.identity().as_deref() != Some(name) {
+                        match limits.acquire_identity(name) {
+                            Ok(permit) => session.set_permit(permit),
+                            Err(e) => return e.into(),
+                        }
+                    }
+                }
+                session.set_identity(name);
+                if let Some(namespaces) = &self.inner.namespaces {
+                    session.set_namespace(namespaces.binding(name).map(|ns| ns.to_owned()));
+                }
+                CommandResponse::ok()
+            }
+            None => KvError::PermissionDenied("invalid token".into()).into(),
+        }
+    }
+
+    fn select(&self, namespace: &str, session: &Session) -> CommandResponse {
+        let namespaces = match &self.inner.namespaces {
+            Some(namespaces) => namespaces,
+            None => return KvError::InvalidCommand("Namespace is not enabled".into()).into(),
+        };
+
+        // 绑定了 namespace 的客户端不能切换到其他 namespace
+        let identity = session.identity();
+        if let Some(bound) = identity.as_deref().and_then(|id| namespaces.binding(id)) {
+            if bound != namespace {
+                let msg = format!("{} is bound to namespace {}", identity.unwrap_or_default(), bound);
+                return KvError::PermissionDenied(msg).into();
+            }
+        }
+
+        if let Err(e) = namespaces.validate_name(namespace) {
+            return e.into();
+        }
+
+        match namespace {
+            "" => session.set_namespace(None),
+            ns => session.set_namespace(Some(ns.into())),
+        }
+        CommandResponse::ok()
+    }
+
+    /// response 发送给客户端之后，由网络层调用，触发 on_after_send
+    pub fn after_send(&self, res: &CommandResponse) {
+        self.inner.on_after_send.notify(res);
+    }
+
+    /// 命令执行完成后，发布 keyspace 事件，然后返回结果
+    fn finish(&self, cmd: CommandRequest, res: CommandResponse) -> StreamingResponse {
+        if let Some(keyspace) = &self.inner.keyspace {
+            for (topic, data) in keyspace.events(&cmd, &res) {
+                Arc::clone(&self.broadcaster).publish(topic, Arc::new(data.into()));
+            }
+        }
+        self.respond(cmd, res)
+    }
+
+    /// 处理 dispatch 的结果，dispatch 处理不了的交给 dispatch_stream
+    fn respond(&self, cmd: CommandRequest, res: CommandResponse) -> StreamingResponse {
         if res == CommandResponse::default() {
             dispatch_stream(cmd, Arc::clone(&self.broadcaster))
         } else {
             debug!("Executed response: {:?}", res);
-            self.inner.on_executed.notify(&res);
-            self.inner.on_before_send.notify(&mut res);
-            if !self.inner.on_before_send.is_empty() {
-                debug!("Modified response: {:?}", res);
-            }
             Box::pin(stream::once(async { Arc::new(res) }))
         }
     }
 }
 
 
+/// 去掉推送给客户端的 topic 中 namespace 的前缀，pairs 为 true 时 pairs 的 key 也是 topic
+fn strip_topic_prefix(res: StreamingResponse, prefix: String, pairs: bool) -> StreamingResponse {
+    Box::pin(res.map(move |mut res| {
+        if let Some(topic) = res.topic.strip_prefix(&prefix) {
+            let topic = topic.to_owned();
+            Arc::make_mut(&mut res).topic = topic;
+        }
+        if pairs {
+            for pair in Arc::make_mut(&mut res).pairs.iter_mut() {
+                if let Some(key) = pair.key.strip_prefix(&prefix) {
+                    pair.key = key.to_owned();
+                }
+            }
+        }
+        res
+    }))
+}
+
+/// 等待 stream 命令的结果（比如 wait 模式的 Publish），超过 deadline 返回 408
+fn with_deadline(
+    mut res: StreamingResponse,
+    deadline: time::Instant,
+    timeout: Duration,
+) -> StreamingResponse {
+    let fut = async move {
+        match time::timeout_at(deadline, res.next()).await {
+            Ok(res) => res,
+            Err(_) => {
+                let e = KvError::Timeout(format!("deadline {:?} exceeded", timeout));
+                Some(Arc::new(e.into()))
+            }
+        }
+    };
+    Box::pin(stream::once(fut).filter_map(future::ready))
+}
+
+/// 去掉 pairs 中属于某个 namespace 的 topic
+fn hide_namespaced_topics(res: StreamingResponse) -> StreamingResponse {
+    Box::pin(res.map(|mut res| {
+        if res.pairs.iter().any(|pair| pair.key.contains(namespace::SEPARATOR)) {
+            Arc::make_mut(&mut res)
+                .pairs
+                .retain(|pair| !pair.key.contains(namespace::SEPARATOR));
+        }
+        res
+    }))
+}
+
+/// 把订阅 stream 第一个消息里的 subscription id 记录到 session 中，stream 结束后删除
+fn track_subscription(res: StreamingResponse, session: &Arc<Session>) -> StreamingResponse {
+    let mut guard = SubscriptionGuard {
+        session: Arc::downgrade(session),
+        id: None,
+    };
+    let mut first = true;
+    Box::pin(res.map(move |res| {
+        if std::mem::take(&mut first) && res.status == StatusCode::OK.as_u16() as u32 {
+            if let Some(id) = res.values.first().and_then(|v| i64::try_from(v).ok()) {
+                guard.track(id as u64);
+            }
+        }
+        res
+    }))
+}
+
+struct SubscriptionGuard {
+    session: Weak<Session>,
+    id: Option<u64>,
+}
+
+impl SubscriptionGuard {
+    fn track(&mut self, id: u64) {
+        if let Some(session) = self.session.upgrade() {
+            session.add_subscription(id);
+            self.id = Some(id);
+        }
+    }
+}
+
+impl Drop for SubscriptionGuard {
+    fn drop(&mut self) {
+        if let (Some(session), Some(id)) = (self.session.upgrade(), self.id) {
+            session.remove_subscription(id);
+        }
+    }
+}
+
 pub struct ServiceInner<Store> {
-    store: Store,
-    on_received: Vec<fn(&CommandRequest)>,
-    on_executed: Vec<fn(&CommandResponse)>,
-    on_before_send: Vec<fn(&mut CommandResponse)>,
-    on_after_send: Vec<fn()>,
+    store: Arc<Store>,
+    acl: RwLock<Option<Arc<Acl>>>,
+    namespaces: Option<Arc<Namespaces>>,
+    limits: Option<Arc<Limits>>,
+    keyspace: Option<Keyspace>,
+    topic_log: Option<TopicLog>,
+    ack_timeout: Option<Duration>,
+    backpressure: Option<Backpressure>,
+    layers: Vec<Arc<dyn Layer<Store>>>,
+    hooks: Hooks,
+    on_after_send: Vec<Hook<CommandResponse>>,
 }
 
+/// 事件的回调，可以是捕获了状态的闭包
+pub type Hook<Arg> = Box<dyn Fn(&Arg) + Send + Sync>;
+
+/// 可以修改事件的回调
+pub type HookMut<Arg> = Box<dyn Fn(&mut Arg) + Send + Sync>;
+
+/// 在命令执行前调用，返回 Some(response) 时直接把它返回给客户端，不再执行命令
+pub type InterceptHook = Box<dyn Fn(&CommandRequest) -> Option<CommandResponse> + Send + Sync>;
+
 impl<Store: Storage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
-            store,
-            on_received: Vec::new(),
-            on_executed: Vec::new(),
-            on_before_send: Vec::new(),
+            store: Arc::new(store),
+            acl: RwLock::new(None),
+            namespaces: None,
+            limits: None,
+            keyspace: None,
+            topic_log: None,
+            ack_timeout: None,
+            backpressure: None,
+            layers: Vec::new(),
+            hooks: Hooks::default(),
             on_after_send: Vec::new(),
         }
     }
 
-    pub fn fn_received(mut self, f: fn(&CommandRequest)) -> Self {
-        self.on_received.push(f);
+    /// 开启认证和权限检查
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.acl = RwLock::new(Some(Arc::new(acl)));
         self
     }
 
-    pub fn fn_executed(mut self, f: fn(&CommandResponse)) -> Self {
-        self.on_executed.push(f);
+    /// 开启多租户 namespace，存储淘汰 key 时需要共享同一个 Namespaces 来更新用量
+    pub fn namespaces(mut self, namespaces: impl Into<Arc<Namespaces>>) -> Self {
+        self.namespaces = Some(namespaces.into());
         self
     }
 
-    pub fn fn_before_send(mut self, f: fn(&mut CommandResponse)) -> Self {
-        self.on_before_send.push(f);
+    /// 开启连接数和速率限制
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = Some(Arc::new(limits));
         self
     }
 
-    pub fn fn_after_send(mut self, f: fn()) -> Self {
-        self.on_after_send.push(f);
+    /// 开启 keyspace 事件通知
+    pub fn keyspace(mut self, keyspace: Keyspace) -> Self {
+        self.keyspace = Some(keyspace);
+        self
+    }
+
+    /// 持久化匹配的 topic，消息保存在 store 中
+    pub fn durable_topics(mut self, config: &DurableConfig) -> Result<Self, KvError> {
+        let store: Arc<dyn Storage> = self.store.clone();
+        self.topic_log = Some(TopicLog::new(store, config)?);
+        Ok(self)
+    }
+
+    /// 设置消费组中消息的 Ack 超时时间
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// 设置订阅者太慢时每个 topic 的处理策略
+    pub fn backpressure(mut self, config: &BackpressureConfig) -> Result<Self, KvError> {
+        self.backpressure = Some(Backpressure::new(config)?);
+        Ok(self)
+    }
+
+    /// 添加一个 layer，先添加的 layer 在外层，先看到请求
+    pub fn layer(mut self, layer: impl Layer<Store>) -> Self {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+
+    /// 当前使用的认证和权限配置，可能在运行时被替换
+    fn current_acl(&self) -> Option<Arc<Acl>> {
+        self.acl.read().unwrap().clone()
+    }
+
+    /// 执行非 stream 的命令，如果使用了 namespace，同时检查和更新配额
+    fn dispatch(&self, cmd: CommandRequest, namespace: Option<&str>) -> CommandResponse {
+        let (namespaces, namespace) = match (&self.namespaces, namespace) {
+            (Some(namespaces), Some(namespace)) => (namespaces, namespace),
+            _ => return dispatch(cmd, self.store.as_ref()),
+        };
+
+        namespaces.execute(namespace, cmd, self.store.as_ref(), dispatch)
+    }
+
+    pub fn fn_received(mut self, f: impl Fn(&CommandRequest) + Send + Sync + 'static) -> Self {
+        self.hooks.on_received.push(Box::new(f));
+        self
+    }
+
+    /// 注册一个可以拒绝或者直接应答请求的回调
+    pub fn fn_intercept(
+        mut self,
+        f: impl Fn(&CommandRequest) -> Option<CommandResponse> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_intercept.push(Box::new(f));
+        self
+    }
+
+    pub fn fn_executed(mut self, f: impl Fn(&CommandResponse) + Send + Sync + 'static) -> Self {
+        self.hooks.on_executed.push(Box::new(f));
+        self
+    }
+
+    pub fn fn_before_send(
+        mut self,
+        f: impl Fn(&mut CommandResponse) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_before_send.push(Box::new(f));
+        self
+    }
+
+    pub fn fn_after_send(mut self, f: impl Fn(&CommandResponse) + Send + Sync + 'static) -> Self {
+        self.on_after_send.push(Box::new(f));
         self
     }
 }
 
 impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
-    fn from(inner: ServiceInner<Store>) -> Self {
+    fn from(mut inner: ServiceInner<Store>) -> Self {
+        // metrics 在最外层，统计所有的命令；回调在最内层，紧挨着命令的执行
+        let hooks = std::mem::take(&mut inner.hooks);
+        if let Some(limits) = &inner.limits {
+            inner.layers.insert(0, Arc::new(RateLimitLayer(Arc::clone(limits))));
+        }
+        inner.layers.insert(0, Arc::new(MetricsLayer));
+        inner.layers.push(Arc::new(HookLayer::new(hooks)));
+        let mut broadcaster = Broadcaster::new(inner.topic_log.take());
+        if let Some(timeout) = inner.ack_timeout {
+            broadcaster = broadcaster.with_ack_timeout(timeout);
+        }
+        if let Some(backpressure) = inner.backpressure.take() {
+            broadcaster = broadcaster.with_backpressure(backpressure);
+        }
+        let broadcaster = Arc::new(broadcaster);
         Self {
             inner: Arc::new(inner),
-            broadcaster: Default::default(),
+            broadcaster,
         }
     }
 }
@@ -114,7 +584,7 @@ pub trait NotifyMut<Arg> {
     fn notify(&self, arg: &mut Arg);
 }
 
-impl<Arg> Notify<Arg> for Vec<fn(&Arg)> {
+impl<Arg> Notify<Arg> for Vec<Hook<Arg>> {
     #[inline]
     fn notify(&self, arg: &Arg) {
         for f in self {
@@ -123,7 +593,7 @@ impl<Arg> Notify<Arg> for Vec<fn(&Arg)> {
     }
 }
 
-impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)> {
+impl<Arg> NotifyMut<Arg> for Vec<HookMut<Arg>> {
     #[inline]
     fn notify(&self, arg: &mut Arg) {
         for f in self {
@@ -144,18 +614,29 @@ pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
         Some(RequestData::Hmdel(param)) => param.execute(store),
         Some(RequestData::Hexist(param)) => param.execute(store),
         Some(RequestData::Hmexist(param)) => param.execute(store),
+        // Auth / Select 需要修改 session，只能由 Service 处理
+        Some(RequestData::Auth(_)) | Some(RequestData::Select(_)) => {
+            KvError::InvalidCommand("Command must be executed by Service".into()).into()
+        }
         None => KvError::InvalidCommand("Request has no data".into()).into(),
         // 处理不了的返回一个啥都不包括的 Response，这样后续可以用 dispatch_stream 处理
         _ => CommandResponse::default(),
     }
 }
 
-/// 从 Request 中得到 Response，目前处理所有 PUBLISH/SUBSCRIBE/UNSUBSCRIBE
+/// 从 Request 中得到 Response，目前处理所有 pub/sub 相关的命令
 pub fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingResponse {
     match cmd.request_data {
         Some(RequestData::Publish(param)) => param.execute(topic),
         Some(RequestData::Subscribe(param)) => param.execute(topic),
         Some(RequestData::Unsubscribe(param)) => param.execute(topic),
+        Some(RequestData::Psubscribe(param)) => param.execute(topic),
+        Some(RequestData::Punsubscribe(param)) => param.execute(topic),
+        Some(RequestData::Ack(param)) => param.execute(topic),
+        Some(RequestData::ListSubscriptions(param)) => param.execute(topic),
+        Some(RequestData::ListTopics(param)) => param.execute(topic),
+        Some(RequestData::TopicInfo(param)) => param.execute(topic),
+        Some(RequestData::NumSub(param)) => param.execute(topic),
         // 如果走到这里，就是代码逻辑的问题，直接 crash 出来
         _ => unreachable!(),
     }
@@ -166,9 +647,15 @@ pub fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingRespo
 mod tests {
     use http::StatusCode;
     use tokio_stream::StreamExt;
+    use std::convert::TryInto;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
     use tracing::info;
     use super::*;
-    use crate::{MemTable, Value};
+    use crate::{
+        AclRule, AuthConfig, ErrorCode, KeyspaceConfig, LimitConfig, MemTable, NamespaceConfig, Permission,
+        RateLimitConfig, StorageSize, UserConfig, Value,
+    };
 
     #[tokio::test]
     async fn service_should_works() {
@@ -203,8 +690,8 @@ mod tests {
             res.status = StatusCode::CREATED.as_u16() as _;
         }
 
-        fn e() {
-            info!("Data is sent");
+        fn e(res: &CommandResponse) {
+            info!("Data is sent: {:?}", res);
         }
 
         let service: Service = ServiceInner::new(MemTable::default())
@@ -221,6 +708,482 @@ mod tests {
         assert_eq!(data.message, "");
         assert_eq!(data.values, vec![Value::default()]);
     }
+
+    #[tokio::test]
+    async fn closure_hooks_should_work() {
+        let received = Arc::new(AtomicUsize::new(0));
+        let executed = Arc::new(AtomicUsize::new(0));
+        let received1 = received.clone();
+        let executed1 = executed.clone();
+
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_received(move |_| {
+                received1.fetch_add(1, Ordering::SeqCst);
+            })
+            .fn_intercept(|cmd| match &cmd.request_data {
+                Some(RequestData::Hdel(_)) => {
+                    Some(KvError::PermissionDenied("hdel is disabled".into()).into())
+                }
+                _ => None,
+            })
+            .fn_executed(move |_| {
+                executed1.fetch_add(1, Ordering::SeqCst);
+            })
+            .into();
+
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        assert_res_ok(&res.next().await.unwrap(), &[Value::default()], &[]);
+
+        // 被拦截的命令不会执行
+        let mut res = service.execute(CommandRequest::new_hdel("t1", "k1"));
+        assert_res_error(&res.next().await.unwrap(), 403, "hdel is disabled");
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(&res.next().await.unwrap(), &["v1".into()], &[]);
+
+        // stream 类的命令也会触发 on_executed
+        let mut res = service.execute(CommandRequest::new_publish("lobby", vec!["hello".into()]));
+        assert_res_ok(&res.next().await.unwrap(), &[0.into()], &[]);
+
+        assert_eq!(received.load(Ordering::SeqCst), 4);
+        assert_eq!(executed.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn layers_should_run_in_order() {
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (order1, order2) = (order.clone(), order.clone());
+
+        let service: Service = ServiceInner::new(MemTable::default())
+            .layer(move |cmd, session: &Arc<Session>, next: Next<MemTable>| {
+                order1.lock().unwrap().push("outer");
+                next.run(cmd, session)
+            })
+            .layer(move |cmd: CommandRequest, session: &Arc<Session>, next: Next<MemTable>| {
+                order2.lock().unwrap().push("inner");
+                // 拒绝写 readonly table 的请求
+                match &cmd.request_data {
+                    Some(RequestData::Hset(v)) if v.table == "readonly" => {
+                        let res = KvError::PermissionDenied("readonly".into()).into();
+                        Box::pin(stream::once(async { Arc::new(res) }))
+                    }
+                    _ => next.run(cmd, session),
+                }
+            })
+            .fn_received({
+                let order = order.clone();
+                move |_| order.lock().unwrap().push("hook")
+            })
+            .into();
+
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        assert_res_ok(&res.next().await.unwrap(), &[Value::default()], &[]);
+        assert_eq!(*order.lock().unwrap(), ["outer", "inner", "hook"]);
+
+        // 被 layer 拒绝的请求不会到达 hook
+        order.lock().unwrap().clear();
+        let mut res = service.execute(CommandRequest::new_hset("readonly", "k1", "v1".into()));
+        assert_res_error(&res.next().await.unwrap(), 403, "readonly");
+        assert_eq!(*order.lock().unwrap(), ["outer", "inner"]);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_request_should_return_429() {
+        let config = LimitConfig {
+            max_connections_per_identity: Some(1),
+            rate_limit: Some(RateLimitConfig {
+                rate: 1,
+                burst: Some(1),
+            }),
+            ..Default::default()
+        };
+        let service: Service = ServiceInner::new(MemTable::default())
+            .limits(Limits::new(config))
+            .into();
+        let peer = "127.0.0.1:9527".parse().ok();
+
+        let session = service.create_session(peer, Some("alice".into())).unwrap();
+        let mut res = service.execute_with_session(CommandRequest::new_hget("t1", "k1"), &session);
+        assert_res_error(&res.next().await.unwrap(), 404, "Not found");
+
+        let mut res = service.execute_with_session(CommandRequest::new_hget("t1", "k1"), &session);
+        let data = res.next().await.unwrap();
+        assert_res_error(&data, 429, "Too many requests");
+        assert_eq!(data.code(), ErrorCode::TooManyRequests);
+
+        // 同一个身份只能有一个连接
+        assert!(service.create_session(peer, Some("alice".into())).is_err());
+        drop(session);
+        assert!(service.create_session(peer, Some("alice".into())).is_ok());
+    }
+
+    #[tokio::test]
+    async fn keyspace_events_should_be_published() {
+        let config = KeyspaceConfig {
+            tables: vec!["t1".into()],
+            include_value: false,
+        };
+        let service: Service = ServiceInner::new(MemTable::default())
+            .keyspace(Keyspace::new(&config).unwrap())
+            .into();
+
+        let mut events = service.execute(CommandRequest::new_subscribe("__keyspace__:t1"));
+        let id: i64 = events.next().await.unwrap().as_ref().try_into().unwrap();
+        assert!(id > 0);
+
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        res.next().await.unwrap();
+        let mut res = service.execute(CommandRequest::new_hset("t2", "k1", "v1".into()));
+        res.next().await.unwrap();
+        let mut res = service.execute(CommandRequest::new_hdel("t1", "k1"));
+        res.next().await.unwrap();
+
+        let data = events.next().await.unwrap();
+        assert_res_ok(&data, &["set".into(), "k1".into()], &[]);
+        let data = events.next().await.unwrap();
+        assert_res_ok(&data, &["del".into(), "k1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn durable_topic_should_replay_from_offset() {
+        let config = DurableConfig {
+            topics: vec!["orders".into()],
+            ..Default::default()
+        };
+        let service: Service = ServiceInner::new(MemTable::default())
+            .durable_topics(&config)
+            .unwrap()
+            .into();
+
+        for i in 0..3 {
+            let cmd = CommandRequest::new_publish("orders", vec![Value::from(i as i64)]);
+            service.execute(cmd).next().await.unwrap();
+        }
+
+        // 从 offset 1 开始订阅，先收到历史消息，然后是新的消息
+        let mut res = service.execute(CommandRequest::new_subscribe_from("orders", 1));
+        let id: i64 = res.next().await.unwrap().as_ref().try_into().unwrap();
+        assert!(id > 0);
+        for i in 1..3 {
+            let data = res.next().await.unwrap();
+            assert_res_ok(&data, &[Value::from(i)], &[]);
+            assert_eq!(data.offset, i as u64);
+        }
+
+        let cmd = CommandRequest::new_publish("orders", vec![Value::from(3)]);
+        service.execute(cmd).next().await.unwrap();
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &[Value::from(3)], &[]);
+        assert_eq!(data.offset, 3);
+
+        // 没有持久化的 topic 不能从 offset 订阅
+        let mut res = service.execute(CommandRequest::new_subscribe_from("lobby", 0));
+        assert_res_error(&res.next().await.unwrap(), 400, "not durable");
+
+        // 客户端不能直接读写消息日志
+        let mut res = service.execute(CommandRequest::new_hgetall("__log__:orders"));
+        assert_res_error(&res.next().await.unwrap(), 403, "reserved");
+        let cmd = CommandRequest::new_hdel("__log__:orders", "00000000000000000000");
+        let mut res = service.execute(cmd);
+        assert_res_error(&res.next().await.unwrap(), 403, "reserved");
+    }
+
+    #[tokio::test]
+    async fn streaming_commands_should_respect_deadline() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+
+        // 订阅不支持 deadline
+        let cmd = CommandRequest::new_subscribe("lobby").with_deadline(Duration::from_secs(1));
+        let mut res = service.execute(cmd);
+        assert_res_error(&res.next().await.unwrap(), 400, "deadline");
+
+        // 订阅者一直不读取，塞满它的队列之后，wait 模式的 publish 要等待队列的空位
+        let mut slow = service.execute(CommandRequest::new_subscribe("lobby"));
+        slow.next().await.unwrap();
+        let mut timeout = None;
+        for i in 0..1024 {
+            let cmd = CommandRequest::new_publish_wait("lobby", vec![Value::from(i as i64)])
+                .with_deadline(Duration::from_millis(50));
+            let data = service.execute(cmd).next().await.unwrap();
+            if data.status != StatusCode::OK.as_u16() as u32 {
+                assert_res_error(&data, 408, "Request timeout");
+                timeout = Some(i);
+                break;
+            }
+        }
+        assert!(timeout.unwrap() > 128);
+
+        // 只返回一个结果的 stream 命令在 deadline 之前完成
+        let cmd = CommandRequest::new_publish("lobby", vec![]).with_deadline(Duration::from_secs(1));
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &[Value::from(1)], &[]);
+    }
+
+    #[tokio::test]
+    async fn expired_request_should_return_timeout() {
+        let service: Service<SlowStore> = ServiceInner::new(SlowStore::default()).into();
+
+        // 没有 deadline 的请求会等到 storage 执行完
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &[Value::default()], &[]);
+
+        // 超过 deadline 的请求返回 408
+        let cmd = CommandRequest::new_hget("t1", "k1").with_deadline(Duration::from_millis(10));
+        let mut res = service.execute(cmd);
+        let data = res.next().await.unwrap();
+        assert_res_error(&data, 408, "Request timeout");
+        assert_eq!(data.code(), ErrorCode::Timeout);
+
+        // 已经开始执行的写命令不会返回超时，而是等它执行完返回真实的结果
+        let cmd = CommandRequest::new_hset("t1", "k2", "v2".into())
+            .with_deadline(Duration::from_millis(10));
+        let mut res = service.execute(cmd);
+        assert_res_ok(&res.next().await.unwrap(), &[Value::default()], &[]);
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k2"));
+        assert_res_ok(&res.next().await.unwrap(), &["v2".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn acl_should_reject_before_dispatch() {
+        let config = AuthConfig {
+            users: vec![UserConfig {
+                name: "alice".into(),
+                token: "alice-token".into(),
+            }],
+            rules: vec![AclRule {
+                identity: "alice".into(),
+                pattern: "t*".into(),
+                permissions: vec![Permission::Read, Permission::Write],
+            }],
+        };
+        let service: Service = ServiceInner::new(MemTable::default())
+            .acl(Acl::new(&config).unwrap())
+            .into();
+        let session = Arc::new(Session::default());
+
+        // 未认证时返回 403，数据不会被写入
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut res = service.execute_with_session(cmd.clone(), &session);
+        let data = res.next().await.unwrap();
+        assert_res_error(&data, 403, "Permission denied");
+
+        // 错误的 token 认证失败
+        let mut res = service.execute_with_session(CommandRequest::new_auth("bad"), &session);
+        let data = res.next().await.unwrap();
+        assert_res_error(&data, 403, "invalid token");
+
+        // 认证之后可以正常执行
+        let mut res = service.execute_with_session(CommandRequest::new_auth("alice-token"), &session);
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &[], &[]);
+        assert_eq!(session.identity(), Some("alice".into()));
+
+        let mut res = service.execute_with_session(cmd, &session);
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &[Value::default()], &[]);
+
+        // 没有权限的 table 依旧返回 403
+        let cmd = CommandRequest::new_hget("users", "k1");
+        let mut res = service.execute_with_session(cmd, &session);
+        let data = res.next().await.unwrap();
+        assert_res_error(&data, 403, "no Read permission on users");
+    }
+
+    #[tokio::test]
+    async fn acl_should_be_replaceable() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut res = service.execute(cmd.clone());
+        assert_res_ok(&res.next().await.unwrap(), &[Value::default()], &[]);
+
+        // 开启认证后，之后的命令需要权限
+        service.set_acl(Some(Acl::new(&AuthConfig::default()).unwrap()));
+        let mut res = service.execute(cmd.clone());
+        assert_res_error(&res.next().await.unwrap(), 403, "Permission denied");
+
+        service.set_acl(None);
+        let mut res = service.execute(cmd);
+        assert_res_ok(&res.next().await.unwrap(), &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_should_check_owner() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let alice = Arc::new(Session::default());
+        let bob = Arc::new(Session::default());
+
+        let mut stream = service.execute_with_session(CommandRequest::new_subscribe("lobby"), &alice);
+        let id: i64 = stream.next().await.unwrap().as_ref().try_into().unwrap();
+
+        // 其他连接不能取消这个订阅
+        let cmd = CommandRequest::new_unsubscribe("lobby", id as _);
+        let mut res = service.execute_with_session(cmd.clone(), &bob);
+        assert_res_error(&res.next().await.unwrap(), 404, "subscription");
+
+        let mut res = service.execute_with_session(cmd.clone(), &alice);
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+        assert!(stream.next().await.is_none());
+
+        // stream 结束后，连接上不再记录这个订阅
+        drop(stream);
+        assert!(!alice.owns_subscription(id as _));
+    }
+
+    #[tokio::test]
+    async fn namespaces_should_isolate_tables() {
+        let mut config = NamespaceConfig::default();
+        config.bindings.insert("alice".into(), "team-a".into());
+        let service: Service = ServiceInner::new(MemTable::default())
+            .namespaces(Namespaces::new(config))
+            .into();
+
+        // alice 绑定了 team-a，写入的数据对其他 namespace 不可见
+        let alice = service.create_session(None, Some("alice".into())).unwrap();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut res = service.execute_with_session(cmd, &alice);
+        assert_res_ok(&res.next().await.unwrap(), &[Value::default()], &[]);
+
+        let bob = service.create_session(None, Some("bob".into())).unwrap();
+        let mut res = service.execute_with_session(CommandRequest::new_hget("t1", "k1"), &bob);
+        assert_res_error(&res.next().await.unwrap(), 404, "Not found");
+
+        // 也不能通过带前缀的名字直接访问
+        let cmd = CommandRequest::new_hget("team-a/t1", "k1");
+        let mut res = service.execute_with_session(cmd, &bob);
+        assert_res_error(&res.next().await.unwrap(), 400, "reserved character");
+
+        // 没有绑定的客户端可以 Select 到其他 namespace
+        let mut res = service.execute_with_session(CommandRequest::new_select("team-a"), &bob);
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+        let mut res = service.execute_with_session(CommandRequest::new_hget("t1", "k1"), &bob);
+        assert_res_ok(&res.next().await.unwrap(), &["v1".into()], &[]);
+
+        // 绑定了 namespace 的客户端不能切换
+        let mut res = service.execute_with_session(CommandRequest::new_select("team-b"), &alice);
+        assert_res_error(&res.next().await.unwrap(), 403, "bound to namespace team-a");
+
+        // 推送的数据中的 topic 不带 namespace 前缀
+        let mut events = service.execute_with_session(CommandRequest::new_psubscribe("t*"), &alice);
+        events.next().await.unwrap();
+        let cmd = CommandRequest::new_publish("t1", vec!["hello".into()]);
+        let mut res = service.execute_with_session(cmd, &alice);
+        res.next().await.unwrap();
+        assert_eq!(events.next().await.unwrap().topic, "t1");
+
+        // 只能看到自己 namespace 里的 topic，返回的名字也不带前缀
+        let cmd = CommandRequest::new_list_topics("");
+        let mut res = service.execute_with_session(cmd, &alice);
+        assert_res_ok(&res.next().await.unwrap(), &[], &[Kvpair::new("t1", 0.into())]);
+        let mut res = service.execute_with_session(CommandRequest::new_list_topics(""), &bob);
+        assert_res_ok(&res.next().await.unwrap(), &[], &[Kvpair::new("t1", 0.into())]);
+        let mut res = service.execute_with_session(CommandRequest::new_select("team-b"), &bob);
+        res.next().await.unwrap();
+        let mut res = service.execute_with_session(CommandRequest::new_list_topics(""), &bob);
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+    }
+
+    #[tokio::test]
+    async fn pattern_subscriptions_should_not_cross_namespaces() {
+        let mut config = NamespaceConfig::default();
+        config.bindings.insert("alice".into(), "team-a".into());
+        let service: Service = ServiceInner::new(MemTable::default())
+            .namespaces(Namespaces::new(config))
+            .into();
+        let alice = service.create_session(None, Some("alice".into())).unwrap();
+        let bob = service.create_session(None, Some("bob".into())).unwrap();
+
+        // "**" 能匹配分隔符，开启 namespace 时不允许使用
+        let mut res = service.execute_with_session(CommandRequest::new_psubscribe("**"), &bob);
+        assert_res_error(&res.next().await.unwrap(), 400, "not allowed with namespaces");
+
+        // 没有 namespace 的客户端看不到 team-a 中的 topic
+        let mut events = service.execute_with_session(CommandRequest::new_psubscribe("*"), &bob);
+        events.next().await.unwrap();
+        let cmd = CommandRequest::new_publish("t1", vec!["secret".into()]);
+        service.execute_with_session(cmd, &alice).next().await.unwrap();
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        service.execute_with_session(cmd, &bob).next().await.unwrap();
+
+        let res = events.next().await.unwrap();
+        assert_eq!(res.topic, "lobby");
+        assert_eq!(res.values, &["hello".into()]);
+    }
+
+    #[tokio::test]
+    async fn clients_without_namespace_should_not_list_other_namespaces() {
+        let mut config = NamespaceConfig::default();
+        config.bindings.insert("alice".into(), "team-a".into());
+        let service: Service = ServiceInner::new(MemTable::default())
+            .namespaces(Namespaces::new(config))
+            .into();
+        let alice = service.create_session(None, Some("alice".into())).unwrap();
+        let bob = service.create_session(None, Some("bob".into())).unwrap();
+
+        let mut sub = service.execute_with_session(CommandRequest::new_subscribe("t1"), &alice);
+        sub.next().await.unwrap();
+        let mut psub = service.execute_with_session(CommandRequest::new_psubscribe("t*"), &alice);
+        psub.next().await.unwrap();
+        let cmd = CommandRequest::new_publish("t1", vec!["hello".into()]);
+        service.execute_with_session(cmd, &alice).next().await.unwrap();
+
+        let cmd = CommandRequest::new_list_topics("");
+        let mut res = service.execute_with_session(cmd, &bob);
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+
+        let cmd = CommandRequest::new_list_subscriptions("");
+        let mut res = service.execute_with_session(cmd, &bob);
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+
+        let mut res = service.execute_with_session(CommandRequest::new_topic_info("t1"), &bob);
+        let res = res.next().await.unwrap();
+        assert!(res.pairs.iter().all(|pair| pair.value == Some(0.into())));
+
+        // alice 自己可以看到
+        let cmd = CommandRequest::new_list_topics("");
+        let mut res = service.execute_with_session(cmd, &alice);
+        assert_res_ok(&res.next().await.unwrap(), &[], &[Kvpair::new("t1", 1.into())]);
+    }
+
+    /// 每个操作都要等 50ms 的 storage，用于测试 deadline
+    #[derive(Default)]
+    struct SlowStore(MemTable);
+
+    impl SlowStore {
+        fn delay(&self) -> &MemTable {
+            std::thread::sleep(Duration::from_millis(50));
+            &self.0
+        }
+    }
+
+    impl Storage for SlowStore {
+        fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            self.delay().get(table, key)
+        }
+
+        fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+            self.delay().set(table, key, value)
+        }
+
+        fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+            self.delay().contains(table, key)
+        }
+
+        fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            self.delay().del(table, key)
+        }
+
+        fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+            self.delay().get_all(table)
+        }
+
+        fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+            self.delay().get_iter(table)
+        }
+
+        fn size(&self) -> Result<StorageSize, KvError> {
+            self.0.size()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,403 @@
+use dashmap::DashMap;
+use http::StatusCode;
+use prost::Message;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, KvError, NamespaceConfig,
+    QuotaConfig, Storage,
+};
+
+/// namespace 和 table / topic 之间的分隔符，开启 namespace 后 table / topic 的名字里不能包含它
+/// 注意不能使用 ':'，SledDb 用它来分隔 table 和 key
+pub(super) const SEPARATOR: char = '/';
+
+/// 多租户的 namespace 管理：身份绑定，table / topic 的前缀，以及配额
+///
+/// 用量是从服务器启动开始统计的，对于 SledDb 这样持久化的存储，重启之前写入的数据不计入用量
+#[derive(Debug, Default)]
+pub struct Namespaces {
+    config: NamespaceConfig,
+    usage: DashMap<String, Arc<Usage>>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    /// 同一个 namespace 的写命令在这个锁内串行执行
+    lock: Mutex<()>,
+    keys: AtomicI64,
+    bytes: AtomicI64,
+}
+
+impl Usage {
+    fn load(&self) -> UsageDelta {
+        UsageDelta {
+            keys: self.keys.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    fn add(&self, delta: UsageDelta) {
+        self.keys.fetch_add(delta.keys, Ordering::Relaxed);
+        self.bytes.fetch_add(delta.bytes, Ordering::Relaxed);
+    }
+}
+
+/// 一次写入对 namespace 用量的影响
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct UsageDelta {
+    pub keys: i64,
+    pub bytes: i64,
+}
+
+impl Namespaces {
+    pub fn new(config: NamespaceConfig) -> Self {
+        Self {
+            config,
+            usage: DashMap::new(),
+        }
+    }
+
+    /// 获取 identity 绑定的 namespace
+    pub fn binding(&self, identity: &str) -> Option<&str> {
+        self.config.bindings.get(identity).map(|ns| ns.as_str())
+    }
+
+    /// 检查 namespace 的名字是否合法
+    pub fn validate_name(&self, name: &str) -> Result<(), KvError> {
+        if name.contains(SEPARATOR) {
+            return Err(KvError::InvalidCommand(format!(
+                "namespace {} contains reserved character {:?}",
+                name, SEPARATOR
+            )));
+        }
+        Ok(())
+    }
+
+    /// 给命令中的 table / topic 加上 namespace 前缀
+    pub fn apply(&self, cmd: &mut CommandRequest, namespace: Option<&str>) -> Result<(), KvError> {
+        // "**" 可以匹配分隔符，模式订阅会收到其他 namespace 中 topic 的数据
+        if let Some(pattern) = pattern(cmd).filter(|p| p.contains("**")) {
+            return Err(KvError::InvalidCommand(format!(
+                "pattern {} contains \"**\", which is not allowed with namespaces",
+                pattern
+            )));
+        }
+
+        for name in resources_mut(cmd) {
+            // 不允许通过 "ns/table" 这样的名字直接访问其他 namespace 的数据
+            if name.contains(SEPARATOR) {
+                return Err(KvError::InvalidCommand(format!(
+                    "{} contains reserved character {:?}",
+                    name, SEPARATOR
+                )));
+            }
+
+            if let Some(ns) = namespace {
+                *name = format!("{}{}{}", ns, SEPARATOR, name);
+            }
+        }
+        Ok(())
+    }
+
+    /// 执行 namespace 中的命令，写命令在 namespace 的锁内检查配额、执行并更新用量
+    ///
+    /// 同一个 namespace 的写命令串行执行，并发的写入不会超过配额，也不会重复计算用量
+    pub fn execute<S: Storage>(
+        &self,
+        namespace: &str,
+        cmd: CommandRequest,
+        store: &S,
+        dispatch: impl FnOnce(CommandRequest, &S) -> CommandResponse,
+    ) -> CommandResponse {
+        if !is_write(&cmd) {
+            return dispatch(cmd, store);
+        }
+
+        let usage = self.usage.entry(namespace.into()).or_default().clone();
+        let _guard = usage.lock.lock().unwrap();
+        let delta = match usage_delta(&cmd, store).and_then(|d| self.check(namespace, &usage, d)) {
+            Ok(delta) => delta,
+            Err(e) => return e.into(),
+        };
+
+        let res = dispatch(cmd, store);
+        if res.status == StatusCode::OK.as_u16() as u32 {
+            usage.add(delta);
+        }
+        res
+    }
+
+    /// table 中的一个 key 被存储淘汰后，扣除对应 namespace 的用量
+    ///
+    /// 淘汰可能发生在其他 namespace 的写命令中，所以这里不获取 namespace 的锁
+    pub fn release(&self, table: &str, bytes: u64) {
+        let usage = table
+            .split_once(SEPARATOR)
+            .and_then(|(namespace, _)| self.usage.get(namespace));
+        if let Some(usage) = usage {
+            usage.add(UsageDelta {
+                keys: -1,
+                bytes: -(bytes as i64),
+            });
+        }
+    }
+
+    /// 获取 namespace 当前的用量
+    pub fn usage(&self, namespace: &str) -> UsageDelta {
+        match self.usage.get(namespace) {
+            Some(usage) => usage.load(),
+            None => UsageDelta::default(),
+        }
+    }
+
+    /// 检查写入之后是否会超过配额
+    fn check(
+        &self,
+        namespace: &str,
+        usage: &Usage,
+        delta: UsageDelta,
+    ) -> Result<UsageDelta, KvError> {
+        let quota = self.quota(namespace);
+        let usage = usage.load();
+        let keys = usage.keys + delta.keys;
+        let bytes = usage.bytes + delta.bytes;
+
+        match (quota.max_keys, quota.max_bytes) {
+            (Some(max), _) if delta.keys > 0 && keys > max as i64 => Err(KvError::QuotaExceeded(
+                format!("namespace {} exceeds max keys {}", namespace, max),
+            )),
+            (_, Some(max)) if delta.bytes > 0 && bytes > max as i64 => Err(KvError::QuotaExceeded(
+                format!("namespace {} exceeds max bytes {}", namespace, max),
+            )),
+            _ => Ok(delta),
+        }
+    }
+
+    fn quota(&self, namespace: &str) -> &QuotaConfig {
+        self.config
+            .quotas
+            .get(namespace)
+            .unwrap_or(&self.config.default_quota)
+    }
+}
+
+/// 获取命令中 table / topic 的名字
+fn resources_mut(cmd: &mut CommandRequest) -> Vec<&mut String> {
+    match &mut cmd.request_data {
+        Some(RequestData::Hget(v)) => vec![&mut v.table],
+        Some(RequestData::Hgetall(v)) => vec![&mut v.table],
+        Some(RequestData::Hmget(v)) => vec![&mut v.table],
+        Some(RequestData::Hset(v)) => vec![&mut v.table],
+        Some(RequestData::Hmset(v)) => vec![&mut v.table],
+        Some(RequestData::Hdel(v)) => vec![&mut v.table],
+        Some(RequestData::Hmdel(v)) => vec![&mut v.table],
+        Some(RequestData::Hexist(v)) => vec![&mut v.table],
+        Some(RequestData::Hmexist(v)) => vec![&mut v.table],
+        Some(RequestData::Subscribe(v)) => vec![&mut v.topic],
+        Some(RequestData::Unsubscribe(v)) => vec![&mut v.topic],
+        Some(RequestData::Publish(v)) => vec![&mut v.topic],
+        Some(RequestData::Ack(v)) => vec![&mut v.topic],
+        Some(RequestData::ListSubscriptions(v)) => vec![&mut v.prefix],
+        Some(RequestData::ListTopics(v)) => vec![&mut v.prefix],
+        Some(RequestData::TopicInfo(v)) => vec![&mut v.topic],
+        Some(RequestData::NumSub(v)) => v.topics.iter_mut().collect(),
+        Some(RequestData::Psubscribe(v)) => vec![&mut v.pattern],
+        Some(RequestData::Punsubscribe(v)) => vec![&mut v.pattern],
+        Some(RequestData::Auth(_)) | Some(RequestData::Select(_)) | None => vec![],
+    }
+}
+
+/// 模式订阅命令中的 pattern
+fn pattern(cmd: &CommandRequest) -> Option<&str> {
+    match &cmd.request_data {
+        Some(RequestData::Psubscribe(v)) => Some(&v.pattern),
+        Some(RequestData::Punsubscribe(v)) => Some(&v.pattern),
+        _ => None,
+    }
+}
+
+/// 会改变用量的写命令
+fn is_write(cmd: &CommandRequest) -> bool {
+    matches!(
+        cmd.request_data,
+        Some(RequestData::Hset(_))
+            | Some(RequestData::Hmset(_))
+            | Some(RequestData::Hdel(_))
+            | Some(RequestData::Hmdel(_))
+    )
+}
+
+/// 根据 storage 中现有的数据，计算写命令带来的用量变化
+fn usage_delta(cmd: &CommandRequest, store: &impl Storage) -> Result<UsageDelta, KvError> {
+    let mut delta = UsageDelta::default();
+    match &cmd.request_data {
+        Some(RequestData::Hset(v)) => {
+            if let Some(pair) = &v.pair {
+                let value = pair.value.as_ref().map(|v| v.encoded_len()).unwrap_or_default();
+                set_delta(&mut delta, store, &v.table, &pair.key, value)?;
+            }
+        }
+        Some(RequestData::Hmset(v)) => {
+            // 重复的 key 只计算一次，最后写入的值生效
+            let values: HashMap<_, _> = v
+                .pairs
+                .iter()
+                .map(|pair| {
+                    let value = pair.value.as_ref().map(|v| v.encoded_len()).unwrap_or_default();
+                    (pair.key.as_str(), value)
+                })
+                .collect();
+            for (key, value) in values {
+                set_delta(&mut delta, store, &v.table, key, value)?;
+            }
+        }
+        Some(RequestData::Hdel(v)) => del_delta(&mut delta, store, &v.table, &v.key)?,
+        Some(RequestData::Hmdel(v)) => {
+            let keys: HashSet<_> = v.keys.iter().collect();
+            for key in keys {
+                del_delta(&mut delta, store, &v.table, key)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(delta)
+}
+
+fn set_delta(
+    delta: &mut UsageDelta,
+    store: &impl Storage,
+    table: &str,
+    key: &str,
+    value: usize,
+) -> Result<(), KvError> {
+    match store.get(table, key)? {
+        Some(old) => delta.bytes += value as i64 - old.encoded_len() as i64,
+        None => {
+            delta.keys += 1;
+            delta.bytes += (key.len() + value) as i64;
+        }
+    }
+    Ok(())
+}
+
+fn del_delta(
+    delta: &mut UsageDelta,
+    store: &impl Storage,
+    table: &str,
+    key: &str,
+) -> Result<(), KvError> {
+    if let Some(old) = store.get(table, key)? {
+        delta.keys -= 1;
+        delta.bytes -= (key.len() + old.encoded_len()) as i64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dispatch, EvictionPolicy, Kvpair, MemTable, MemoryConfig};
+
+    #[test]
+    fn namespace_should_prefix_names() {
+        let namespaces = Namespaces::new(NamespaceConfig::default());
+
+        let mut cmd = CommandRequest::new_hget("orders", "k1");
+        namespaces.apply(&mut cmd, Some("team-a")).unwrap();
+        assert_eq!(cmd, CommandRequest::new_hget("team-a/orders", "k1"));
+
+        let mut cmd = CommandRequest::new_subscribe("lobby");
+        namespaces.apply(&mut cmd, Some("team-a")).unwrap();
+        assert_eq!(cmd, CommandRequest::new_subscribe("team-a/lobby"));
+
+        // 不能直接访问其他 namespace 的 table
+        let mut cmd = CommandRequest::new_hget("team-b/orders", "k1");
+        assert!(namespaces.apply(&mut cmd, None).is_err());
+    }
+
+    #[test]
+    fn namespace_quota_should_work() {
+        let mut config = NamespaceConfig::default();
+        config.default_quota.max_keys = Some(2);
+        let namespaces = Namespaces::new(config);
+        let store = MemTable::new();
+
+        let execute = |cmd: CommandRequest| {
+            namespaces.execute("ns", cmd, &store, dispatch).into_result()?;
+            Ok::<_, KvError>(())
+        };
+
+        execute(CommandRequest::new_hset("ns/t1", "k1", "v1".into())).unwrap();
+        execute(CommandRequest::new_hset("ns/t1", "k2", "v2".into())).unwrap();
+        // 更新已有的 key 不会增加 key 的数量
+        execute(CommandRequest::new_hset("ns/t1", "k2", "v3".into())).unwrap();
+
+        let result = execute(CommandRequest::new_hset("ns/t1", "k3", "v3".into()));
+        assert!(matches!(result, Err(KvError::QuotaExceeded(_))));
+
+        // 删除之后又可以写入了
+        execute(CommandRequest::new_hdel("ns/t1", "k1")).unwrap();
+        execute(CommandRequest::new_hset("ns/t1", "k3", "v3".into())).unwrap();
+        assert_eq!(namespaces.usage("ns").keys, 2);
+
+        // 同一个命令中重复的 key 只计算一次
+        let pairs = vec![Kvpair::new("k4", "v4".into()), Kvpair::new("k4", "v5".into())];
+        execute(CommandRequest::new_hdel("ns/t1", "k3")).unwrap();
+        execute(CommandRequest::new_hmset("ns/t1", pairs)).unwrap();
+        assert_eq!(namespaces.usage("ns").keys, 2);
+    }
+
+    #[test]
+    fn evicted_keys_should_release_usage() {
+        let namespaces = Arc::new(Namespaces::new(NamespaceConfig::default()));
+        let released = namespaces.clone();
+        let memory = MemoryConfig {
+            max_memory: 100,
+            policy: EvictionPolicy::Lru,
+        };
+        let store = MemTable::with_limit(memory)
+            .on_evict(move |table, size| released.release(table, size));
+
+        for i in 0..20 {
+            let cmd = CommandRequest::new_hset("ns/t1", format!("k{:02}", i), "v1".into());
+            namespaces.execute("ns", cmd, &store, dispatch).into_result().unwrap();
+        }
+        let size = store.size().unwrap();
+        assert!(size.keys < 20);
+        assert_eq!(namespaces.usage("ns").keys, size.keys as i64);
+        assert_eq!(namespaces.usage("ns").bytes, size.bytes as i64);
+    }
+
+    #[test]
+    fn concurrent_writes_should_not_exceed_quota() {
+        let mut config = NamespaceConfig::default();
+        config.default_quota.max_keys = Some(10);
+        let namespaces = Arc::new(Namespaces::new(config));
+        let store = Arc::new(MemTable::new());
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let namespaces = namespaces.clone();
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for j in 0..10 {
+                        // 每个线程写入不同的 key，也都写入同一个 key
+                        let key = if j % 2 == 0 { format!("k{}-{}", i, j) } else { "shared".into() };
+                        let cmd = CommandRequest::new_hset("ns/t1", key, "v".into());
+                        namespaces.execute("ns", cmd, store.as_ref(), dispatch);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let keys = store.get_all("ns/t1").unwrap().len() as i64;
+        assert_eq!(keys, 10);
+        assert_eq!(namespaces.usage("ns").keys, keys);
+    }
+}
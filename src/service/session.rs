@@ -0,0 +1,106 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use tokio::sync::Notify;
+
+use super::ConnectionPermit;
+
+/// 每个连接的会话状态，同一个连接上的所有 yamux stream 共享一个 Session
+#[derive(Debug, Default)]
+pub struct Session {
+    /// 客户端的身份，来自客户端证书或者 Auth 命令
+    identity: RwLock<Option<String>>,
+    /// 当前使用的 namespace，None 表示默认的 namespace
+    namespace: RwLock<Option<String>>,
+    /// 客户端的地址
+    peer: Option<SocketAddr>,
+    /// 当前身份占用的连接名额
+    permit: Mutex<Option<ConnectionPermit<String>>>,
+    /// 这个连接上创建的订阅，只能取消自己的订阅
+    subscriptions: Mutex<HashSet<u64>>,
+    /// 连接是否已经断开
+    closed: AtomicBool,
+    close_notify: Notify,
+}
+
+impl Session {
+    pub fn new(identity: Option<String>) -> Self {
+        Self {
+            identity: RwLock::new(identity),
+            namespace: RwLock::new(None),
+            peer: None,
+            permit: Mutex::new(None),
+            subscriptions: Mutex::new(HashSet::new()),
+            closed: AtomicBool::new(false),
+            close_notify: Notify::new(),
+        }
+    }
+
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    /// 获取客户端的地址
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// 保存当前身份占用的连接名额，之前的名额被归还
+    pub fn set_permit(&self, permit: ConnectionPermit<String>) {
+        *self.permit.lock().unwrap() = Some(permit);
+    }
+
+    /// 获取当前连接的身份
+    pub fn identity(&self) -> Option<String> {
+        self.identity.read().unwrap().clone()
+    }
+
+    /// 认证成功后更新当前连接的身份
+    pub fn set_identity(&self, identity: impl Into<String>) {
+        *self.identity.write().unwrap() = Some(identity.into());
+    }
+
+    /// 获取当前连接使用的 namespace
+    pub fn namespace(&self) -> Option<String> {
+        self.namespace.read().unwrap().clone()
+    }
+
+    /// 切换当前连接使用的 namespace
+    pub fn set_namespace(&self, namespace: Option<String>) {
+        *self.namespace.write().unwrap() = namespace;
+    }
+
+    /// 记录这个连接创建的订阅
+    pub fn add_subscription(&self, id: u64) {
+        self.subscriptions.lock().unwrap().insert(id);
+    }
+
+    /// 订阅的 stream 结束后删除记录
+    pub fn remove_subscription(&self, id: u64) {
+        self.subscriptions.lock().unwrap().remove(&id);
+    }
+
+    /// 订阅是否是这个连接创建的
+    pub fn owns_subscription(&self, id: u64) -> bool {
+        self.subscriptions.lock().unwrap().contains(&id)
+    }
+
+    /// 连接断开时调用，通知所有还在等待订阅数据的 stream 退出
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.close_notify.notify_waiters();
+    }
+
+    /// 等待连接断开
+    pub async fn closed(&self) {
+        loop {
+            let notified = self.close_notify.notified();
+            if self.closed.load(Ordering::Acquire) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use glob::Pattern;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{self, Instant};
+
+use crate::{metrics, BackpressureConfig, CommandResponse, KvError, SlowSubscriberPolicy};
+
+use super::namespace::SEPARATOR;
+use super::topic::MATCH_OPTIONS;
+
+/// 每个订阅者的队列里最多存放的消息
+const QUEUE_CAPACITY: usize = 128;
+
+/// Block 策略下，消息最多等待这么久，超时后被丢弃
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 每个 topic 在订阅者太慢时使用的策略
+#[derive(Debug, Default)]
+pub struct Backpressure {
+    policy: SlowSubscriberPolicy,
+    topics: Vec<(Pattern, SlowSubscriberPolicy)>,
+}
+
+impl Backpressure {
+    pub fn new(config: &BackpressureConfig) -> Result<Self, KvError> {
+        let topics = config
+            .topics
+            .iter()
+            .map(|t| {
+                Pattern::new(&t.topic)
+                    .map(|p| (p, t.policy))
+                    .map_err(|e| KvError::InvalidConfig(format!("Invalid topic {}: {}", t.topic, e)))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            policy: config.policy,
+            topics,
+        })
+    }
+
+    /// topic 使用的策略，使用第一个匹配的配置
+    /// 配置中的 topic 不带 namespace 前缀，对所有 namespace 都生效
+    pub fn policy(&self, topic: &str) -> SlowSubscriberPolicy {
+        let name = topic.split_once(SEPARATOR).map_or(topic, |(_, name)| name);
+        self.topics
+            .iter()
+            .find(|(p, _)| p.matches_with(name, MATCH_OPTIONS))
+            .map(|(_, policy)| *policy)
+            .unwrap_or(self.policy)
+    }
+}
+
+/// 消息放进订阅者队列的结果
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Enqueue {
+    /// 消息放进了队列
+    Queued,
+    /// 队列满了，丢弃了最旧的消息后放进了队列
+    DroppedOldest,
+    /// 队列满了，丢弃了这条消息
+    DroppedNewest,
+}
+
+/// 一个订阅者，publish 把消息放进它自己的队列，由单独的任务转发给客户端
+///
+/// 每个订阅者有自己的队列，一个订阅者太慢不会影响其他订阅者
+/// 丢弃的消息数量会放在下一条发给这个订阅者的消息里
+pub struct Subscriber {
+    queue: Mutex<VecDeque<Arc<CommandResponse>>>,
+    dropped: AtomicU64,
+    closed: AtomicBool,
+    /// 是否有 Block 策略的消息在等待队列的空位
+    blocked: AtomicBool,
+    /// 队列里有了新消息，或者订阅被关闭
+    readable: Notify,
+    /// 队列有了空位，或者订阅被关闭
+    writable: Notify,
+}
+
+impl Subscriber {
+    /// 创建订阅者，并启动把队列里的消息转发到 tx 的任务
+    /// 客户端释放了 tx 对应的 receiver 时（比如连接断开），调用 on_closed
+    pub fn new(
+        tx: mpsc::Sender<Arc<CommandResponse>>,
+        on_closed: impl FnOnce() + Send + 'static,
+    ) -> Arc<Self> {
+        let subscriber = Arc::new(Self {
+            queue: Mutex::new(VecDeque::with_capacity(QUEUE_CAPACITY)),
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            blocked: AtomicBool::new(false),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        });
+        let s = subscriber.clone();
+        tokio::spawn(async move {
+            if s.forward(tx).await {
+                s.close();
+                on_closed();
+            }
+        });
+        subscriber
+    }
+
+    /// 按照策略把消息放进队列，返回错误时这个订阅需要被删除
+    ///
+    /// Block 策略下同时只有一条消息等待空位，这样等待的消息（以及 publish 的任务）不会无限增长
+    /// 已经有消息在等待时，新的消息直接丢弃，保证消息的顺序；等待超过 BLOCK_TIMEOUT 的消息也被丢弃
+    pub async fn send(
+        &self,
+        res: Arc<CommandResponse>,
+        policy: SlowSubscriberPolicy,
+    ) -> Result<Enqueue, KvError> {
+        let mut result = Enqueue::Queued;
+        let mut waiting: Option<Waiting> = None;
+        let deadline = Instant::now() + BLOCK_TIMEOUT;
+        loop {
+            // 先注册等待，避免错过检查之后的通知
+            let writable = self.writable.notified();
+            if self.closed.load(Ordering::Acquire) {
+                return Err(KvError::Internal("subscriber is closed".into()));
+            }
+
+            {
+                let mut queue = self.queue.lock().unwrap();
+                // 排在等待的消息后面，保证 Block 策略下消息的顺序
+                let blocked = policy == SlowSubscriberPolicy::Block
+                    && waiting.is_none()
+                    && self.blocked.load(Ordering::Acquire);
+                if queue.len() < QUEUE_CAPACITY && !blocked {
+                    queue.push_back(res);
+                    drop(waiting.take());
+                    break;
+                }
+
+                match policy {
+                    SlowSubscriberPolicy::Block if blocked => {
+                        self.drop_message();
+                        return Ok(Enqueue::DroppedNewest);
+                    }
+                    SlowSubscriberPolicy::Block => {
+                        if waiting.is_none() {
+                            self.blocked.store(true, Ordering::Release);
+                            waiting = Some(Waiting(&self.blocked));
+                        }
+                    }
+                    SlowSubscriberPolicy::DropOldest => {
+                        queue.pop_front();
+                        queue.push_back(res);
+                        self.drop_message();
+                        result = Enqueue::DroppedOldest;
+                        break;
+                    }
+                    SlowSubscriberPolicy::DropNewest => {
+                        self.drop_message();
+                        return Ok(Enqueue::DroppedNewest);
+                    }
+                    SlowSubscriberPolicy::Disconnect => {
+                        drop(queue);
+                        self.drop_message();
+                        self.close();
+                        return Err(KvError::Internal("subscriber is too slow".into()));
+                    }
+                }
+            }
+            if time::timeout_at(deadline, writable).await.is_err() {
+                self.drop_message();
+                return Ok(Enqueue::DroppedNewest);
+            }
+        }
+
+        self.readable.notify_one();
+        Ok(result)
+    }
+
+    /// 关闭订阅，队列里剩下的消息发送完之后，客户端的 stream 结束
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+
+    fn drop_message(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        metrics::PUBLISH_DROPPED.inc();
+    }
+
+    /// 转发队列里的消息，客户端断开时返回 true，订阅被关闭时返回 false
+    async fn forward(&self, tx: mpsc::Sender<Arc<CommandResponse>>) -> bool {
+        loop {
+            let readable = self.readable.notified();
+            let next = self.queue.lock().unwrap().pop_front();
+            match next {
+                Some(mut res) => {
+                    self.writable.notify_one();
+                    let dropped = self.dropped.swap(0, Ordering::Relaxed);
+                    if dropped > 0 {
+                        Arc::make_mut(&mut res).dropped = dropped;
+                    }
+                    if tx.send(res).await.is_err() {
+                        return true;
+                    }
+                }
+                None if self.closed.load(Ordering::Acquire) => return false,
+                None => {
+                    tokio::select! {
+                        _ = readable => {}
+                        _ = tx.closed() => return true,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 等待空位的消息，结束等待时清除 blocked 标记
+struct Waiting<'a>(&'a AtomicBool);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TopicPolicyConfig, Value};
+
+    fn message(i: i64) -> Arc<CommandResponse> {
+        Arc::new(Value::from(i).into())
+    }
+
+    /// 在客户端不读取的情况下塞满订阅者的队列，返回发送的消息数
+    async fn fill(subscriber: &Subscriber) -> i64 {
+        let mut total = 0;
+        while subscriber.queue.lock().unwrap().len() < QUEUE_CAPACITY {
+            subscriber.send(message(total), SlowSubscriberPolicy::Block).await.unwrap();
+            total += 1;
+            tokio::task::yield_now().await;
+        }
+        total
+    }
+
+    #[test]
+    fn backpressure_should_use_first_matched_policy() {
+        let backpressure = Backpressure::new(&BackpressureConfig {
+            policy: SlowSubscriberPolicy::Block,
+            topics: vec![
+                TopicPolicyConfig {
+                    topic: "metrics.*".into(),
+                    policy: SlowSubscriberPolicy::DropOldest,
+                },
+                TopicPolicyConfig {
+                    topic: "*".into(),
+                    policy: SlowSubscriberPolicy::Disconnect,
+                },
+            ],
+        })
+        .unwrap();
+        assert_eq!(backpressure.policy("metrics.cpu"), SlowSubscriberPolicy::DropOldest);
+        assert_eq!(backpressure.policy("orders"), SlowSubscriberPolicy::Disconnect);
+
+        // namespace 中的 topic 按照去掉前缀的名字匹配
+        assert_eq!(backpressure.policy("team-a/metrics.cpu"), SlowSubscriberPolicy::DropOldest);
+        assert_eq!(backpressure.policy("team-a/orders"), SlowSubscriberPolicy::Disconnect);
+    }
+
+    #[test]
+    fn backpressure_should_reject_invalid_topic() {
+        let result = Backpressure::new(&BackpressureConfig {
+            policy: SlowSubscriberPolicy::Block,
+            topics: vec![TopicPolicyConfig {
+                topic: "[".into(),
+                policy: SlowSubscriberPolicy::DropOldest,
+            }],
+        });
+        assert!(matches!(result, Err(KvError::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_should_report_dropped_count() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let subscriber = Subscriber::new(tx, || {});
+        let total = fill(&subscriber).await;
+
+        let policy = SlowSubscriberPolicy::DropOldest;
+        let res = subscriber.send(message(total), policy).await.unwrap();
+        assert_eq!(res, Enqueue::DroppedOldest);
+        subscriber.send(message(total + 1), policy).await.unwrap();
+
+        // channel 里的消息先收到，之后的消息带着丢弃的数量
+        let mut dropped = 0;
+        let mut last = Value::default();
+        while let Ok(Some(res)) =
+            tokio::time::timeout(std::time::Duration::from_millis(50), rx.recv()).await
+        {
+            dropped += res.dropped;
+            last = res.values[0].clone();
+        }
+        assert_eq!(dropped, 2);
+        assert_eq!(last, Value::from(total + 1));
+    }
+
+    #[tokio::test]
+    async fn drop_newest_and_disconnect_should_not_block() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let subscriber = Subscriber::new(tx, || {});
+        let total = fill(&subscriber).await;
+
+        let res = subscriber.send(message(total), SlowSubscriberPolicy::DropNewest).await;
+        assert_eq!(res.unwrap(), Enqueue::DroppedNewest);
+        let res = subscriber.send(message(total), SlowSubscriberPolicy::Disconnect).await;
+        assert!(res.is_err());
+
+        // 断开后，队列里剩下的消息发送完，stream 结束
+        let mut count = 0;
+        while rx.recv().await.is_some() {
+            count += 1;
+        }
+        assert_eq!(count, total);
+    }
+
+    #[tokio::test]
+    async fn block_should_only_wait_for_one_message() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let subscriber = Subscriber::new(tx, || {});
+        let total = fill(&subscriber).await;
+
+        // 第一条消息等待空位，之后的消息直接丢弃
+        let policy = SlowSubscriberPolicy::Block;
+        let s = subscriber.clone();
+        let blocked = tokio::spawn(async move { s.send(message(total), policy).await });
+        tokio::task::yield_now().await;
+        assert!(subscriber.blocked.load(Ordering::Acquire));
+        let res = subscriber.send(message(total + 1), policy).await;
+        assert_eq!(res.unwrap(), Enqueue::DroppedNewest);
+
+        // 客户端读取之后，等待的消息放进队列
+        rx.recv().await.unwrap();
+        assert_eq!(blocked.await.unwrap().unwrap(), Enqueue::Queued);
+        assert!(!subscriber.blocked.load(Ordering::Acquire));
+
+        let mut last = Value::default();
+        let mut dropped = 0;
+        while let Ok(Some(res)) =
+            tokio::time::timeout(std::time::Duration::from_millis(50), rx.recv()).await
+        {
+            dropped += res.dropped;
+            last = res.values[0].clone();
+        }
+        assert_eq!(dropped, 1);
+        assert_eq!(last, Value::from(total));
+    }
+
+    #[tokio::test]
+    async fn dropped_receiver_should_call_on_closed() {
+        let (tx, rx) = mpsc::channel(1);
+        let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+        let subscriber = Subscriber::new(tx, move || closed_tx.send(()).unwrap());
+
+        // 没有新的消息，客户端断开后也能及时清理
+        drop(rx);
+        closed_rx.await.unwrap();
+        let res = subscriber.send(message(0), SlowSubscriberPolicy::Block).await;
+        assert!(res.is_err());
+    }
+}
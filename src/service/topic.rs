@@ -1,136 +1,659 @@
-use dashmap::{DashMap, DashSet};
+use dashmap::{mapref::one::Ref, DashMap, DashSet};
+use futures::future::join_all;
+use glob::{MatchOptions, Pattern};
+use rand::Rng;
 use std::sync::{
-    atomic::{AtomicU32, Ordering},
+    atomic::{AtomicU64, Ordering},
     Arc,
 };
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, info, instrument, warn};
 
-use crate::{CommandResponse, KvError, Value};
+use crate::{metrics, CommandResponse, KvError, Kvpair, Value};
+
+use super::consumer_group::{ConsumerGroup, DEFAULT_ACK_TIMEOUT};
+use super::namespace::SEPARATOR;
+use super::subscriber::{Backpressure, Enqueue, Subscriber};
+use super::TopicLog;
 
 /// topic 里最大存放的数据
 const BROADCAST_CAPACITY: usize = 128;
 
-/// 下一个 subscription id
-static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+/// 超过这个数量的 topic 统计时，清理掉已经没有订阅的 topic 的统计
+const MAX_IDLE_TOPICS: usize = 10_000;
+
+/// 模式订阅的匹配规则，'*' 不能匹配 namespace 的分隔符 '/'
+/// '**' 可以匹配分隔符，开启 namespace 时 Namespaces::apply 会拒绝它
+pub(super) const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// subscription id 的低位是递增的序号，高位是 Broadcaster 的 epoch
+const SEQUENCE_BITS: u32 = 40;
+
+/// 生成 subscription id
+///
+/// 每个 Broadcaster 创建时随机生成一个 epoch，服务器重启之后 epoch 也会变化，
+/// 客户端手里旧的 id 不会碰巧指向新的订阅。id 不超过 63 位，可以放进 Value 的 integer 里
+#[derive(Debug)]
+struct SubscriptionIds {
+    epoch: u64,
+    next: AtomicU64,
+}
+
+impl Default for SubscriptionIds {
+    fn default() -> Self {
+        let epoch: u64 = rand::thread_rng().gen_range(1..1 << (63 - SEQUENCE_BITS));
+        Self {
+            epoch: epoch << SEQUENCE_BITS,
+            next: AtomicU64::new(1),
+        }
+    }
+}
 
-/// 获取下一个 subscription id
-fn get_next_subscription_id() -> u32 {
-    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+impl SubscriptionIds {
+    /// 获取下一个 subscription id
+    fn next(&self) -> u64 {
+        let sequence = self.next.fetch_add(1, Ordering::Relaxed) & ((1 << SEQUENCE_BITS) - 1);
+        self.epoch | sequence
+    }
 }
 
 pub trait Topic: Send + Sync + 'static {
     /// 订阅某个主题
     fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>>;
     /// 取消对某个主题的订阅
-    fn unsubscribe(self, name: String, id: u32) -> Result<u32, KvError>;
-    /// 往主题里发布一个数据
-    fn publish(self, name: String, value: Arc<CommandResponse>);
+    fn unsubscribe(self, name: String, id: u64) -> Result<u64, KvError>;
+    /// 从 offset 开始订阅持久化的主题，先收到历史消息，然后是新的消息
+    fn subscribe_from(
+        self,
+        name: String,
+        offset: u64,
+    ) -> Result<mpsc::Receiver<Arc<CommandResponse>>, KvError>;
+    /// 订阅所有匹配 glob 模式的主题
+    fn psubscribe(self, pattern: String) -> Result<mpsc::Receiver<Arc<CommandResponse>>, KvError>;
+    /// 取消模式订阅
+    fn punsubscribe(self, pattern: String, id: u64) -> Result<u64, KvError>;
+    /// 以 consumer 的名字加入主题的消费组，组里每条消息只会投递给一个消费者
+    fn subscribe_group(
+        self,
+        name: String,
+        group: String,
+        consumer: String,
+    ) -> mpsc::Receiver<Arc<CommandResponse>>;
+    /// 确认消费组中的消息，返回确认成功的数量
+    fn ack(self, name: String, group: String, ids: Vec<u64>) -> Result<u32, KvError>;
+    /// 列出名字以 prefix 开头的主题和模式上所有的订阅，key 是主题或者模式，value 是 subscription id
+    fn list_subscriptions(self, prefix: String) -> Vec<Kvpair>;
+    /// 列出名字以 prefix 开头的主题，key 是主题，value 是订阅数
+    fn list_topics(self, prefix: String) -> Vec<Kvpair>;
+    /// 主题的订阅数、发布的消息数和丢弃的消息数
+    fn topic_info(self, name: String) -> Vec<Kvpair>;
+    /// 每个主题的订阅数，不包括模式订阅
+    fn num_sub(self, names: Vec<String>) -> Vec<Kvpair>;
+    /// 往主题里发布一个数据，消息在后台发送给所有的订阅者
+    fn publish(self, name: String, value: Arc<CommandResponse>) -> Delivery;
+}
+
+/// 一次 publish 的结果
+#[derive(Debug)]
+pub struct Delivery {
+    /// 发布时收到消息的订阅者数量，包括投递成功的消费组
+    pub receivers: usize,
+    /// 所有的订阅者发送完成后，得到实际放进队列的订阅者数量（包括投递成功的消费组）
+    pub delivered: oneshot::Receiver<usize>,
 }
 
 /// 用于主题发布和订阅的数据结构
 #[derive(Default)]
 pub struct Broadcaster {
     /// 所有的主题列表
-    topics: DashMap<String, DashSet<u32>>,
+    topics: DashMap<String, DashSet<u64>>,
+    /// 所有的模式订阅
+    patterns: DashMap<String, PatternSubscription>,
     /// 所有的订阅列表
-    subscriptions: DashMap<u32, mpsc::Sender<Arc<CommandResponse>>>,
+    subscriptions: DashMap<u64, Arc<Subscriber>>,
+    /// 所有的消费组，topic -> 组名 -> 消费组
+    groups: DashMap<String, DashMap<String, Arc<ConsumerGroup>>>,
+    /// 消费组中消息的 Ack 超时时间，不设置时使用 DEFAULT_ACK_TIMEOUT
+    ack_timeout: Option<Duration>,
+    /// 订阅者太慢时的处理策略
+    backpressure: Backpressure,
+    /// 持久化 topic 的消息日志
+    log: Option<TopicLog>,
+    /// 每个 topic 的统计
+    stats: DashMap<String, TopicStats>,
+    /// subscription id 生成器
+    ids: SubscriptionIds,
+}
+
+/// topic 发布和丢弃的消息数
+#[derive(Debug, Default)]
+struct TopicStats {
+    published: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// 一个 glob 模式下的所有订阅
+struct PatternSubscription {
+    pattern: Pattern,
+    ids: DashSet<u64>,
+}
+
+/// publish 时需要发送的订阅，以及它来自哪个主题或者模式
+enum Source {
+    Topic,
+    Pattern(String),
 }
 
 impl Topic for Arc<Broadcaster> {
 
     #[instrument(name = "topic_subscribe", skip_all)]
     fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>> {
-        let id = {
-            let entry = self.topics.entry(name).or_default();
-            let id = get_next_subscription_id();
-            entry.value().insert(id);
-            id
+        let id = self.add_topic_subscription(name.clone());
+        let on_closed = self.topic_cleanup(name, id);
+        self.add_subscription(id, on_closed)
+    }
+
+    #[instrument(name = "topic_unsubscribe", skip_all)]
+    fn unsubscribe(self, name: String, id: u64) -> Result<u64, KvError> {
+        match self.remove_subscription(name, id) {
+            Some(id) => Ok(id),
+            None => Err(KvError::NotFound(format!("subscription {}", id))),
+        }
+    }
+
+    #[instrument(name = "topic_subscribe_from", skip_all)]
+    fn subscribe_from(
+        self,
+        name: String,
+        offset: u64,
+    ) -> Result<mpsc::Receiver<Arc<CommandResponse>>, KvError> {
+        let log = match &self.log {
+            Some(log) if log.is_durable(&name) => log,
+            _ => return Err(KvError::InvalidCommand(format!("topic {} is not durable", name))),
         };
 
-        // 生成一个 mpsc channel
+        // 先订阅新的消息，再读取历史消息，这样中间发布的消息不会丢失
+        // 消息在写入日志之后才会发送给订阅者，所以 offset 小于 boundary 的新消息一定已经在历史消息里了
+        let id = self.add_topic_subscription(name.clone());
+        let (live_tx, mut live) = mpsc::channel(BROADCAST_CAPACITY);
+        let on_closed = self.topic_cleanup(name.clone(), id);
+        self.subscriptions.insert(id, Subscriber::new(live_tx, on_closed));
+
+        let history = match log.read(&name, offset) {
+            Ok(v) => v,
+            Err(e) => {
+                self.remove_subscription(name, id);
+                return Err(e);
+            }
+        };
+        let boundary = history.last().map(|m| m.offset + 1).unwrap_or(offset);
+
         let (tx, rx) = mpsc::channel(BROADCAST_CAPACITY);
+        tokio::spawn(async move {
+            // 第一个消息是 subscription id
+            let v: Value = (id as i64).into();
+            if tx.send(Arc::new(v.into())).await.is_err() {
+                return;
+            }
 
-        let v: Value = (id as i64).into();
+            for res in history {
+                if tx.send(Arc::new(res)).await.is_err() {
+                    return;
+                }
+            }
 
-        // 立刻发送 subscription id 到 rx
-        let tx1 = tx.clone();
-        tokio::spawn(async move {
-            if let Err(e) = tx1.send(Arc::new(v.into())).await {
-                warn!("Failed to send subscription id: {}. Err: {:?}", id, e);
+            loop {
+                // 客户端断开时释放 live，订阅随之被清理
+                let res = tokio::select! {
+                    res = live.recv() => res,
+                    _ = tx.closed() => None,
+                };
+                match res {
+                    Some(res) if res.offset < boundary => {}
+                    Some(res) => {
+                        if tx.send(res).await.is_err() {
+                            return;
+                        }
+                    }
+                    None => return,
+                }
             }
         });
 
-        // 把 tx 存入subscription table
-        self.subscriptions.insert(id, tx);
-        debug!("Subscription {} is add", id);
+        Ok(rx)
+    }
 
-        // 返回 rx 给网络处理的上下文
-        rx
+    #[instrument(name = "topic_psubscribe", skip_all)]
+    fn psubscribe(self, pattern: String) -> Result<mpsc::Receiver<Arc<CommandResponse>>, KvError> {
+        let compiled = Pattern::new(&pattern).map_err(|e| {
+            KvError::InvalidCommand(format!("invalid pattern {}: {}", pattern, e))
+        })?;
+
+        let id = {
+            let entry = self
+                .patterns
+                .entry(pattern.clone())
+                .or_insert_with(|| PatternSubscription {
+                    pattern: compiled,
+                    ids: DashSet::new(),
+                });
+            let id = self.ids.next();
+            entry.value().ids.insert(id);
+            id
+        };
+        let on_closed = self.pattern_cleanup(pattern, id);
+        Ok(self.add_subscription(id, on_closed))
     }
 
-    #[instrument(name = "topic_unsubscribe", skip_all)]
-    fn unsubscribe(self, name: String, id: u32) -> Result<u32, KvError> {
-        match self.remove_subscription(name, id) {
+    #[instrument(name = "topic_punsubscribe", skip_all)]
+    fn punsubscribe(self, pattern: String, id: u64) -> Result<u64, KvError> {
+        match self.remove_pattern_subscription(pattern, id) {
             Some(id) => Ok(id),
             None => Err(KvError::NotFound(format!("subscription {}", id))),
         }
     }
 
-    #[instrument(name = "topic_publish", skip_all)]
-    fn publish(self, name: String, value: Arc<CommandResponse>) {
+    #[instrument(name = "topic_subscribe_group", skip_all)]
+    fn subscribe_group(
+        self,
+        name: String,
+        group: String,
+        consumer: String,
+    ) -> mpsc::Receiver<Arc<CommandResponse>> {
+        // 第一个消息是 subscription id，channel 是新建的，一定能发送成功
+        let id = self.ids.next();
+        let (tx, rx) = mpsc::channel(BROADCAST_CAPACITY);
+        let v: Value = (id as i64).into();
+        let _ = tx.try_send(Arc::new(v.into()));
+        let closed = tx.clone();
+
+        // 持有 entry 的锁加入消费组，这样消费组不会在加入之前因为没有消费者被删除
+        let ack_timeout = self.ack_timeout.unwrap_or(DEFAULT_ACK_TIMEOUT);
+        self.groups
+            .entry(name.clone())
+            .or_default()
+            .entry(group)
+            .or_insert_with(|| ConsumerGroup::new(ack_timeout))
+            .join(consumer, id, tx);
+
+        // 客户端断开时离开消费组，它没有 Ack 的消息会投递给其他消费者
+        let broadcaster = Arc::downgrade(&self);
         tokio::spawn(async move {
-            let mut ids = vec![];
-            if let Some(topic) = self.topics.get(&name) {
-                // 复制整个 topic 下所有的 subscription id
-                // 这里我们每个 id 是 u32, 如果一个 topic 下有 10k 订阅，复制的成本
-                // 也就是 40k 堆内存（外加一些控制结构），所以效率不算差
-                // 这也是为什么我们用 NEXT_ID 来控制 subscription id 的生成
-                let subscriptions = topic.value().clone();
-
-                drop(topic);
-
-                for id in subscriptions.into_iter() {
-                    if let Some(tx) = self.subscriptions.get(&id) {
-                        if let Err(e) = tx.send(value.clone()).await {
-                            warn!("Publish to {} failed ! error: {:?}", id, e);
-                            ids.push(id);
-                        }
-                    }
+            closed.closed().await;
+            if let Some(b) = broadcaster.upgrade() {
+                b.leave_group(&name, id);
+            }
+        });
+        debug!("Subscription {} is add", id);
+        rx
+    }
+
+    #[instrument(name = "topic_ack", skip_all)]
+    fn ack(self, name: String, group: String, ids: Vec<u64>) -> Result<u32, KvError> {
+        match self.groups.get(&name).and_then(|g| g.get(&group).map(|g| g.clone())) {
+            Some(group) => Ok(group.ack(&ids) as u32),
+            None => Err(KvError::NotFound(format!("group {} of topic {}", group, name))),
+        }
+    }
+
+    #[instrument(name = "topic_list_subscriptions", skip_all)]
+    fn list_subscriptions(self, prefix: String) -> Vec<Kvpair> {
+        let mut result = vec![];
+        for topic in self.topics.iter().filter(|t| t.key().starts_with(&prefix)) {
+            result.extend(topic.value().iter().map(|id| (topic.key().clone(), *id)));
+        }
+        for pattern in self.patterns.iter().filter(|p| p.key().starts_with(&prefix)) {
+            result.extend(pattern.ids.iter().map(|id| (pattern.key().clone(), *id)));
+        }
+        for groups in self.groups.iter().filter(|g| g.key().starts_with(&prefix)) {
+            for group in groups.value().iter() {
+                result.extend(group.ids().into_iter().map(|id| (groups.key().clone(), id)));
+            }
+        }
+        result.sort();
+
+        result
+            .into_iter()
+            .map(|(name, id)| Kvpair::new(name, (id as i64).into()))
+            .collect()
+    }
+
+    #[instrument(name = "topic_list_topics", skip_all)]
+    fn list_topics(self, prefix: String) -> Vec<Kvpair> {
+        let mut names = self
+            .topics
+            .iter()
+            .map(|t| t.key().clone())
+            .chain(self.groups.iter().map(|g| g.key().clone()))
+            .chain(self.stats.iter().map(|s| s.key().clone()))
+            .filter(|name| name.starts_with(&prefix))
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+
+        names
+            .into_iter()
+            .map(|name| {
+                let count = self.subscriber_count(&name);
+                Kvpair::new(name, (count as i64).into())
+            })
+            .collect()
+    }
+
+    #[instrument(name = "topic_info", skip_all)]
+    fn topic_info(self, name: String) -> Vec<Kvpair> {
+        // 只统计和 topic 在同一个 namespace 中的模式订阅
+        let namespace = |name: &str| name.split_once(SEPARATOR).map(|(ns, _)| ns.to_owned());
+        let patterns = self
+            .patterns
+            .iter()
+            .filter(|p| namespace(p.key()) == namespace(&name))
+            .filter(|p| p.pattern.matches_with(&name, MATCH_OPTIONS))
+            .map(|p| p.ids.len())
+            .sum::<usize>();
+        let groups = self.groups.get(&name).map(|g| g.len()).unwrap_or_default();
+        let (published, dropped) = match self.stats.get(&name) {
+            Some(stats) => (
+                stats.published.load(Ordering::Relaxed),
+                stats.dropped.load(Ordering::Relaxed),
+            ),
+            None => (0, 0),
+        };
+
+        vec![
+            Kvpair::new("subscribers", (self.subscriber_count(&name) as i64).into()),
+            Kvpair::new("patterns", (patterns as i64).into()),
+            Kvpair::new("groups", (groups as i64).into()),
+            Kvpair::new("published", (published as i64).into()),
+            Kvpair::new("dropped", (dropped as i64).into()),
+        ]
+    }
+
+    #[instrument(name = "topic_num_sub", skip_all)]
+    fn num_sub(self, names: Vec<String>) -> Vec<Kvpair> {
+        names
+            .into_iter()
+            .map(|name| {
+                let count = self.subscriber_count(&name);
+                Kvpair::new(name, (count as i64).into())
+            })
+            .collect()
+    }
+
+    #[instrument(name = "topic_publish", skip_all)]
+    fn publish(self, name: String, mut value: Arc<CommandResponse>) -> Delivery {
+        // 带上具体的 topic，模式订阅的客户端据此知道数据来自哪个 topic
+        Arc::make_mut(&mut value).topic = name.clone();
+        self.stats(&name).published.fetch_add(1, Ordering::Relaxed);
+
+        // 持久化的 topic 先写入日志，分配 offset
+        if let Some(log) = self.log.as_ref().filter(|log| log.is_durable(&name)) {
+            if let Err(e) = log.append(&name, Arc::make_mut(&mut value)) {
+                warn!("Failed to append message to topic log {}: {:?}", name, e);
+            }
+        }
+
+        // 每个消费组只投递给组里的一个消费者
+        let mut groups_delivered = 0;
+        if let Some(groups) = self.groups.get(&name) {
+            for group in groups.iter() {
+                if group.publish(&value) {
+                    groups_delivered += 1;
                 }
             }
+        }
+
+        let subscribers: Vec<_> = self
+            .subscribers(&name)
+            .into_iter()
+            .filter_map(|(id, source)| Some((id, source, self.subscriptions.get(&id)?.clone())))
+            .collect();
+        let receivers = subscribers.len() + groups_delivered;
 
-            for id in ids {
-                self.remove_subscription(name.clone(), id);
+        // 同时发送给所有的订阅者，一个订阅者太慢不会影响其他订阅者
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let policy = self.backpressure.policy(&name);
+            let sends = subscribers.into_iter().map(|(id, source, subscriber)| {
+                let value = value.clone();
+                async move {
+                    let result = subscriber.send(value, policy).await;
+                    (id, source, result)
+                }
+            });
+
+            let mut delivered = groups_delivered;
+            for (id, source, result) in join_all(sends).await {
+                let e = match result {
+                    Ok(Enqueue::Queued) => {
+                        delivered += 1;
+                        continue;
+                    }
+                    Ok(enqueue) => {
+                        if enqueue == Enqueue::DroppedOldest {
+                            delivered += 1;
+                        }
+                        self.stats(&name).dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    Err(e) => e,
+                };
+                warn!("Publish to {} failed ! error: {:?}", id, e);
+                metrics::PUBLISH_FAILURES.inc();
+                match source {
+                    Source::Topic => self.remove_subscription(name.clone(), id),
+                    Source::Pattern(pattern) => self.remove_pattern_subscription(pattern, id),
+                };
             }
+            // publish 的调用者可能不关心结果
+            let _ = tx.send(delivered);
         });
+
+        Delivery {
+            receivers,
+            delivered: rx,
+        }
     }
 }
 
 impl Broadcaster {
-    pub fn remove_subscription(&self, name: String, id: u32) -> Option<u32> {
-        if let Some(v) = self.topics.get_mut(&name) {
-            // 在 topics 表里找到 topic 的 subscription id，删除
-            v.remove(&id);
-
-            // 如果这个 topic 为空，则也可以删除 topic
-            if v.is_empty() {
-                info!("Topic: {:?} id deleted", &name);
-                drop(v);
-                self.topics.remove(&name);
+    /// 创建一个 Broadcaster，匹配 log 配置的 topic 会被持久化
+    pub fn new(log: Option<TopicLog>) -> Self {
+        Self {
+            log,
+            ..Default::default()
+        }
+    }
+
+    /// 设置消费组中消息的 Ack 超时时间
+    pub fn with_ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// 设置订阅者太慢时的处理策略
+    pub fn with_backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// 在 topic 下添加一个订阅，返回 subscription id
+    fn add_topic_subscription(&self, name: String) -> u64 {
+        let entry = self.topics.entry(name).or_default();
+        let id = self.ids.next();
+        entry.value().insert(id);
+        id
+    }
+
+    /// 客户端断开时删除 topic 下的订阅
+    fn topic_cleanup(self: &Arc<Self>, name: String, id: u64) -> impl FnOnce() + Send + 'static {
+        let broadcaster = Arc::downgrade(self);
+        move || {
+            if let Some(b) = broadcaster.upgrade() {
+                b.remove_subscription(name, id);
             }
         }
+    }
 
-        debug!("Subscription {} is removed!", id);
+    /// 客户端断开时删除模式订阅
+    fn pattern_cleanup(self: &Arc<Self>, pattern: String, id: u64) -> impl FnOnce() + Send + 'static {
+        let broadcaster = Arc::downgrade(self);
+        move || {
+            if let Some(b) = broadcaster.upgrade() {
+                b.remove_pattern_subscription(pattern, id);
+            }
+        }
+    }
+
+    /// 给订阅生成一个 channel，并先发送 subscription id
+    fn add_subscription(
+        &self,
+        id: u64,
+        on_closed: impl FnOnce() + Send + 'static,
+    ) -> mpsc::Receiver<Arc<CommandResponse>> {
+        // 生成一个 mpsc channel
+        let (tx, rx) = mpsc::channel(BROADCAST_CAPACITY);
+
+        let v: Value = (id as i64).into();
+
+        // 立刻发送 subscription id 到 rx，channel 是新建的，一定能发送成功
+        if let Err(e) = tx.try_send(Arc::new(v.into())) {
+            warn!("Failed to send subscription id: {}. Err: {:?}", id, e);
+        }
+
+        // 把 tx 存入subscription table
+        self.subscriptions.insert(id, Subscriber::new(tx, on_closed));
+        debug!("Subscription {} is add", id);
+
+        // 返回 rx 给网络处理的上下文
+        rx
+    }
+
+    /// 获取 topic 的所有订阅，包括匹配 topic 的模式订阅
+    fn subscribers(&self, name: &str) -> Vec<(u64, Source)> {
+        let mut result = vec![];
+        if let Some(topic) = self.topics.get(name) {
+            // 复制整个 topic 下所有的 subscription id
+            // 这里我们每个 id 是 u64, 如果一个 topic 下有 10k 订阅，复制的成本
+            // 也就是 80k 堆内存（外加一些控制结构），所以效率不算差
+            result.extend(topic.value().iter().map(|id| (*id, Source::Topic)));
+        }
+
+        for entry in self.patterns.iter() {
+            if entry.pattern.matches_with(name, MATCH_OPTIONS) {
+                let ids = entry.ids.iter().map(|id| (*id, Source::Pattern(entry.key().clone())));
+                result.extend(ids);
+            }
+        }
+        result
+    }
+
+    /// 删除模式下的订阅，id 不属于这个模式时返回 None
+    pub fn remove_pattern_subscription(&self, pattern: String, id: u64) -> Option<u64> {
+        let removed = match self.patterns.get(&pattern) {
+            Some(v) => v.ids.remove(&id).is_some(),
+            None => false,
+        };
+        if !removed {
+            return None;
+        }
+
+        // 如果这个模式下没有订阅了，则删除模式
+        self.patterns.remove_if(&pattern, |_, v| v.ids.is_empty());
+        self.close_subscription(id)
+    }
+    /// topic 的订阅数，包括消费组中的消费者，不包括模式订阅
+    fn subscriber_count(&self, name: &str) -> usize {
+        let topic = self.topics.get(name).map(|t| t.len()).unwrap_or_default();
+        let groups = self
+            .groups
+            .get(name)
+            .map(|g| g.iter().map(|g| g.ids().len()).sum::<usize>())
+            .unwrap_or_default();
+        topic + groups
+    }
+
+    /// 获取 topic 的统计，统计太多时清理掉没有订阅的 topic
+    fn stats(&self, name: &str) -> Ref<'_, String, TopicStats> {
+        if let Some(stats) = self.stats.get(name) {
+            return stats;
+        }
+
+        if self.stats.len() > MAX_IDLE_TOPICS {
+            self.stats
+                .retain(|name, _| self.topics.contains_key(name) || self.groups.contains_key(name));
+        }
+        self.stats.entry(name.into()).or_default().downgrade()
+    }
+
+    /// 从 topic 的消费组中删除消费者，返回是否找到了这个消费者
+    /// 最后一个消费者离开后删除消费组，组里没有 Ack 的消息也一起丢弃
+    fn leave_group(&self, name: &str, id: u64) -> bool {
+        let left = match self.groups.get(name) {
+            Some(groups) => {
+                let left = groups.iter().any(|g| g.leave(id));
+                groups.retain(|_, g| !g.is_empty());
+                left
+            }
+            None => false,
+        };
+        self.groups.remove_if(name, |_, groups| groups.is_empty());
+        left
+    }
 
-        // 在 subscription 表中删除
-        self.subscriptions.remove(&id).map(|(id, _)| id)
+    /// 当前有订阅者的 topic 数量
+    pub fn topic_count(&self) -> usize {
+        self.topics.len()
+    }
+
+    /// 当前的订阅数量
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    /// 删除 topic 下的订阅，id 不属于这个 topic 时返回 None
+    pub fn remove_subscription(&self, name: String, id: u64) -> Option<u64> {
+        // 消费组的订阅不在 topics 和 subscription 表中，从消费组中删除
+        if self.leave_group(&name, id) {
+            debug!("Subscription {} is removed!", id);
+            return Some(id);
+        }
+
+        // 在 topics 表里找到 topic 的 subscription id，删除
+        let removed = match self.topics.get(&name) {
+            Some(v) => v.remove(&id).is_some(),
+            None => false,
+        };
+        if !removed {
+            return None;
+        }
+
+        // 如果这个 topic 为空，则也可以删除 topic
+        if self.topics.remove_if(&name, |_, v| v.is_empty()).is_some() {
+            info!("Topic: {:?} id deleted", &name);
+        }
+        self.close_subscription(id)
+    }
+
+    /// 从 topics / patterns 中删除订阅之后，在 subscription 表中删除并关闭订阅
+    fn close_subscription(&self, id: u64) -> Option<u64> {
+        debug!("Subscription {} is removed!", id);
+        self.subscriptions.remove(&id).map(|(id, subscriber)| {
+            subscriber.close();
+            id
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::convert::TryInto;
+    use std::convert::{TryFrom, TryInto};
+    use tokio::time;
     use tokio::sync::mpsc::Receiver;
     use crate::assert_res_ok;
     use super::*;
@@ -170,8 +693,222 @@ mod tests {
         assert_res_ok(&res2, &[v.clone()], &[]);
     }
 
-    pub async fn get_id(res: &mut Receiver<Arc<CommandResponse>>) -> u32 {
+    #[tokio::test]
+    async fn subscription_ids_should_be_unique_per_broadcaster() {
+        let b1 = Arc::new(Broadcaster::default());
+        let b2 = Arc::new(Broadcaster::default());
+        let id1 = get_id(&mut b1.clone().subscribe("lobby".into())).await;
+        let id2 = get_id(&mut b2.clone().subscribe("lobby".into())).await;
+
+        // 不同的 Broadcaster（比如重启前后的服务器）的 epoch 不同
+        assert_ne!(id1 >> SEQUENCE_BITS, id2 >> SEQUENCE_BITS);
+        assert!(b2.unsubscribe("lobby".into(), id1).is_err());
+        assert!(i64::try_from(id1).is_ok());
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_with_wrong_topic_should_keep_subscription() {
+        let b = Arc::new(Broadcaster::default());
+        let mut stream = b.clone().subscribe("lobby".into());
+        let mut pstream = b.clone().psubscribe("lob*".into()).unwrap();
+        let id = get_id(&mut stream).await;
+        let pid = get_id(&mut pstream).await;
+
+        // topic 不对，或者用 topic 取消模式订阅，都找不到订阅
+        assert!(b.clone().unsubscribe("orders".into(), id).is_err());
+        assert!(b.clone().unsubscribe("lobby".into(), pid).is_err());
+        assert!(b.clone().punsubscribe("lobby".into(), pid).is_err());
+        assert_eq!(b.subscription_count(), 2);
+
+        let v: Value = "hello".into();
+        b.clone().publish("lobby".into(), Arc::new(v.clone().into()));
+        assert_res_ok(&stream.recv().await.unwrap(), std::slice::from_ref(&v), &[]);
+        assert_res_ok(&pstream.recv().await.unwrap(), &[v], &[]);
+
+        assert_eq!(b.clone().unsubscribe("lobby".into(), id).unwrap(), id);
+        assert!(stream.recv().await.is_none());
+        assert!(b.topics.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pattern_subscribe_should_work() {
+        let b = Arc::new(Broadcaster::default());
+
+        let mut stream = b.clone().psubscribe("orders.*".into()).unwrap();
+        let id = get_id(&mut stream).await;
+
+        let v: Value = "hello".into();
+        b.clone().publish("users.created".into(), Arc::new(v.clone().into()));
+        b.clone().publish("orders.created".into(), Arc::new(v.clone().into()));
+
+        // 只收到匹配的 topic 的数据，并且带上了具体的 topic
+        let res = stream.recv().await.unwrap();
+        assert_res_ok(&res, &["hello".into()], &[]);
+        assert_eq!(res.topic, "orders.created");
+
+        // '*' 不会匹配 namespace 的分隔符
+        b.clone().publish("team-a/orders.created".into(), Arc::new(v.clone().into()));
+
+        assert!(b.clone().punsubscribe("users.*".into(), id).is_err());
+        b.clone().punsubscribe("orders.*".into(), id).unwrap();
+        assert!(stream.recv().await.is_none());
+        assert_eq!(b.patterns.len(), 0);
+
+        assert!(b.clone().psubscribe("[".into()).is_err());
+    }
+
+    #[tokio::test]
+    async fn consumer_group_should_share_messages() {
+        let b = Arc::new(Broadcaster::default().with_ack_timeout(Duration::from_millis(50)));
+        let orders = "orders".to_string();
+
+        let mut c1 = b.clone().subscribe_group(orders.clone(), "g1".into(), "c1".into());
+        let mut c2 = b.clone().subscribe_group(orders.clone(), "g1".into(), "c2".into());
+        let mut other = b.clone().subscribe_group(orders.clone(), "g2".into(), "c1".into());
+        let id1 = get_id(&mut c1).await;
+        get_id(&mut c2).await;
+        get_id(&mut other).await;
+
+        let v: Value = "hello".into();
+        b.clone().publish(orders.clone(), Arc::new(v.clone().into()));
+        b.clone().publish(orders.clone(), Arc::new(v.into()));
+
+        // 同一个组里每条消息只投递给一个消费者，其他的组也能收到所有消息
+        let m1 = c1.recv().await.unwrap();
+        let m2 = c2.recv().await.unwrap();
+        assert_ne!(m1.message_id, m2.message_id);
+        assert_eq!(other.recv().await.unwrap().message_id, 1);
+        assert_eq!(other.recv().await.unwrap().message_id, 2);
+
+        // c1 退出，它没有 Ack 的消息超时后投递给 c2
+        b.clone().unsubscribe(orders.clone(), id1).unwrap();
+        b.clone().ack(orders.clone(), "g1".into(), vec![m2.message_id]).unwrap();
+        assert_eq!(c2.recv().await.unwrap().message_id, m1.message_id);
+
+        assert!(b.clone().ack(orders, "g3".into(), vec![1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn empty_consumer_groups_should_be_removed() {
+        let b = Arc::new(Broadcaster::default());
+        let orders = "orders".to_string();
+
+        let mut c1 = b.clone().subscribe_group(orders.clone(), "g1".into(), "c1".into());
+        let c2 = b.clone().subscribe_group(orders.clone(), "g2".into(), "c1".into());
+        let id1 = get_id(&mut c1).await;
+
+        // 一个消费者取消订阅，另一个断开连接
+        b.clone().unsubscribe(orders.clone(), id1).unwrap();
+        drop(c2);
+        time::sleep(Duration::from_millis(10)).await;
+        assert!(b.groups.is_empty());
+
+        // 之后发布的消息不会留在消费组中
+        for i in 0..BROADCAST_CAPACITY * 4 {
+            b.clone().publish(orders.clone(), Arc::new(Value::from(i as i64).into()));
+        }
+        assert!(b.groups.is_empty());
+        assert!(b.clone().ack(orders, "g1".into(), vec![1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_should_not_block_others() {
+        let b = Arc::new(Broadcaster::default());
+        let lobby = "lobby".to_string();
+
+        // slow 一直不读取，它的队列满了之后发给它的消息被阻塞，阻塞的消息之外的消息被丢弃
+        let _slow = b.clone().subscribe(lobby.clone());
+        let mut fast = b.clone().subscribe(lobby.clone());
+        get_id(&mut fast).await;
+
+        let total = BROADCAST_CAPACITY * 4;
+        for i in 0..total {
+            b.clone().publish(lobby.clone(), Arc::new(Value::from(i as i64).into()));
+            let res = fast.recv().await.unwrap();
+            assert_res_ok(&res, &[Value::from(i as i64)], &[]);
+        }
+        let dropped = b.clone().topic_info(lobby).into_iter().find(|p| p.key == "dropped");
+        assert!(i64::try_from(dropped.unwrap().value.unwrap()).unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn closed_subscription_should_be_removed() {
+        let b = Arc::new(Broadcaster::default());
+
+        let mut s1 = b.clone().subscribe("lobby".into());
+        let mut s2 = b.clone().psubscribe("lob*".into()).unwrap();
+        let mut s3 = b.clone().subscribe_group("lobby".into(), "g1".into(), "c1".into());
+        let id1 = get_id(&mut s1).await;
+        let id2 = get_id(&mut s2).await;
+        let id3 = get_id(&mut s3).await;
+
+        let ids = |pairs: Vec<Kvpair>| {
+            pairs
+                .into_iter()
+                .map(|p| (p.key, i64::try_from(p.value.unwrap()).unwrap() as u64))
+                .collect::<Vec<_>>()
+        };
+        let mut expected = vec![
+            ("lob*".to_string(), id2),
+            ("lobby".to_string(), id1),
+            ("lobby".to_string(), id3),
+        ];
+        expected.sort();
+        assert_eq!(ids(b.clone().list_subscriptions("lo".into())), expected);
+        assert!(b.clone().list_subscriptions("x".into()).is_empty());
+
+        // 客户端断开后，不需要等到下一次 publish，订阅就会被删除
+        drop((s1, s2, s3));
+        time::sleep(Duration::from_millis(10)).await;
+        assert!(b.clone().list_subscriptions("".into()).is_empty());
+        assert_eq!(b.subscription_count(), 0);
+        assert_eq!(b.topic_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn topic_introspection_should_work() {
+        let b = Arc::new(Broadcaster::default().with_backpressure(
+            Backpressure::new(&crate::BackpressureConfig {
+                policy: crate::SlowSubscriberPolicy::DropNewest,
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+
+        let mut s1 = b.clone().subscribe("orders".into());
+        let mut s2 = b.clone().psubscribe("ord*".into()).unwrap();
+        let mut s3 = b.clone().subscribe_group("orders".into(), "g1".into(), "c1".into());
+        let _s4 = b.clone().subscribe("users".into());
+        get_id(&mut s1).await;
+        get_id(&mut s2).await;
+        get_id(&mut s3).await;
+
+        // s1 及时读取，没有人读取 s2，它的队列满了之后消息被丢弃
+        let total = BROADCAST_CAPACITY * 3;
+        for i in 0..total {
+            b.clone().publish("orders".into(), Arc::new(Value::from(i as i64).into()));
+            s1.recv().await.unwrap();
+        }
+
+        let info = b.clone().topic_info("orders".into());
+        let get = |key: &str| -> i64 {
+            let pair = info.iter().find(|p| p.key == key).unwrap();
+            pair.value.clone().unwrap().try_into().unwrap()
+        };
+        assert_eq!(get("subscribers"), 2);
+        assert_eq!(get("patterns"), 1);
+        assert_eq!(get("groups"), 1);
+        assert_eq!(get("published"), total as i64);
+        assert!(get("dropped") > 0);
+
+        let topics = b.clone().list_topics("".into());
+        assert_eq!(topics.iter().map(|p| p.key.as_str()).collect::<Vec<_>>(), ["orders", "users"]);
+        let counts = b.clone().num_sub(vec!["users".into(), "lobby".into()]);
+        assert_eq!(counts, [Kvpair::new("users", 1.into()), Kvpair::new("lobby", 0.into())]);
+    }
+
+    pub async fn get_id(res: &mut Receiver<Arc<CommandResponse>>) -> u64 {
         let id: i64 = res.recv().await.unwrap().as_ref().try_into().unwrap();
-        id as u32
+        id as u64
     }
 }
\ No newline at end of file
@@ -0,0 +1,158 @@
+use bytes::Bytes;
+use dashmap::DashMap;
+use glob::Pattern;
+use prost::Message;
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::{value, CommandResponse, DurableConfig, KvError, Storage};
+
+/// 持久化的 topic 消息保存在 `__log__:{topic}` 这个 table 里，客户端不能直接访问这些 table
+const LOG_TABLE_PREFIX: &str = "__log__:";
+
+/// table 是否是 topic 的消息日志
+pub(crate) fn is_log_table(table: &str) -> bool {
+    table.starts_with(LOG_TABLE_PREFIX)
+}
+
+/// 持久化 topic 的消息日志，每条消息有一个单调递增的 offset
+///
+/// 消息保存在 Storage 中，key 是补齐到 20 位的 offset，value 是编码后的 CommandResponse
+pub struct TopicLog {
+    store: Arc<dyn Storage>,
+    topics: Vec<Pattern>,
+    max_messages: u64,
+    /// topic -> 下一条消息的 offset
+    next: DashMap<String, u64>,
+}
+
+impl TopicLog {
+    pub fn new(store: Arc<dyn Storage>, config: &DurableConfig) -> Result<Self, KvError> {
+        let topics = config
+            .topics
+            .iter()
+            .map(|t| {
+                Pattern::new(t).map_err(|e| {
+                    KvError::InvalidConfig(format!("Invalid durable topic {}: {}", t, e))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            store,
+            topics,
+            max_messages: config.max_messages,
+            next: DashMap::new(),
+        })
+    }
+
+    /// topic 是否需要持久化
+    pub fn is_durable(&self, topic: &str) -> bool {
+        self.topics.iter().any(|p| p.matches(topic))
+    }
+
+    /// 把消息写入日志，返回分配的 offset，写入成功后才会在 res 中设置 offset
+    pub fn append(&self, topic: &str, res: &mut CommandResponse) -> Result<u64, KvError> {
+        if !self.next.contains_key(topic) {
+            let next = self.recover(topic)?;
+            self.next.entry(topic.into()).or_insert(next);
+        }
+
+        // 持有 entry 的锁直到写入完成，保证同一个 topic 的 offset 和写入顺序一致
+        let mut next = self.next.get_mut(topic).unwrap();
+        let offset = *next;
+
+        // 保存的消息中带上 offset，写入失败时 res 保持不变
+        let old = std::mem::replace(&mut res.offset, offset);
+        let data = Bytes::from(res.encode_to_vec());
+        res.offset = old;
+
+        let table = log_table(topic);
+        self.store.set(&table, offset_key(offset), data.into())?;
+        *next += 1;
+        res.offset = offset;
+
+        // 超过保留数量的消息被删除，消息已经写入了，删除失败不影响这次的结果
+        if offset >= self.max_messages {
+            if let Err(e) = self.store.del(&table, &offset_key(offset - self.max_messages)) {
+                warn!("Failed to trim log of topic {}: {:?}", topic, e);
+            }
+        }
+        Ok(offset)
+    }
+
+    /// 读取 offset 不小于 from 的所有消息，按 offset 排序
+    pub fn read(&self, topic: &str, from: u64) -> Result<Vec<CommandResponse>, KvError> {
+        let mut messages = self
+            .store
+            .get_iter(&log_table(topic))?
+            .filter_map(|pair| match pair.key.parse::<u64>() {
+                Ok(offset) if offset >= from => Some((offset, pair.value)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|(offset, _)| *offset);
+
+        messages
+            .into_iter()
+            .map(|(_, v)| match v.and_then(|v| v.value) {
+                Some(value::Value::Binary(buf)) => Ok(CommandResponse::decode(buf)?),
+                v => Err(KvError::Internal(format!("Invalid log entry: {:?}", v))),
+            })
+            .collect()
+    }
+
+    /// 从 Storage 中恢复 topic 的下一个 offset
+    fn recover(&self, topic: &str) -> Result<u64, KvError> {
+        let last = self
+            .store
+            .get_iter(&log_table(topic))?
+            .filter_map(|pair| pair.key.parse::<u64>().ok())
+            .max();
+        Ok(last.map(|v| v + 1).unwrap_or_default())
+    }
+}
+
+fn log_table(topic: &str) -> String {
+    format!("{}{}", LOG_TABLE_PREFIX, topic)
+}
+
+/// 补齐 offset，让 SledDb 中的 key 按照 offset 的顺序排列
+fn offset_key(offset: u64) -> String {
+    format!("{:020}", offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, Value};
+
+    #[test]
+    fn topic_log_should_work() {
+        let store: Arc<dyn Storage> = Arc::new(MemTable::new());
+        let config = DurableConfig {
+            topics: vec!["orders*".into()],
+            max_messages: 2,
+        };
+        let log = TopicLog::new(store.clone(), &config).unwrap();
+        assert!(log.is_durable("orders"));
+        assert!(!log.is_durable("lobby"));
+
+        for i in 0..3 {
+            let mut res: CommandResponse = Value::from(i as i64).into();
+            assert_eq!(log.append("orders", &mut res).unwrap(), i);
+            assert_eq!(res.offset, i);
+        }
+
+        // 只保留了最新的两条
+        let messages = log.read("orders", 0).unwrap();
+        assert_eq!(messages.iter().map(|m| m.offset).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(messages[1].values, [Value::from(2)]);
+
+        // 重新创建的 TopicLog 从 Storage 中恢复 offset
+        let log = TopicLog::new(store, &config).unwrap();
+        let mut res: CommandResponse = Value::from(3).into();
+        assert_eq!(log.append("orders", &mut res).unwrap(), 3);
+        assert_eq!(log.read("orders", 3).unwrap().len(), 1);
+    }
+}
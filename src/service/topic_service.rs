@@ -1,13 +1,20 @@
 
 use futures::{stream, Stream};
-use std::{pin::Pin, sync::Arc};
+use std::{pin::Pin, sync::Arc, time::Duration};
+use tokio::time;
 use tokio_stream::wrappers::ReceiverStream;
 
-use crate::{CommandResponse, Publish, Subscribe, Unsubscribe};
+use crate::{
+    subscribe, Ack, CommandResponse, KvError, ListSubscriptions, ListTopics, NumSub, Psubscribe,
+    Publish, Punsubscribe, Subscribe, TopicInfo, Unsubscribe, Value,
+};
 use crate::service::topic::Topic;
 
 pub type StreamingResponse = Pin<Box<dyn Stream<Item = Arc<CommandResponse>> + Send>>;
 
+/// wait 模式的 Publish 最多等待的时间，请求设置了 deadline 时以 deadline 为准
+const PUBLISH_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub trait TopicService {
     /// 处理 Command，返回 Response
     fn execute(self, topic: impl Topic) -> StreamingResponse;
@@ -15,8 +22,28 @@ pub trait TopicService {
 
 impl TopicService for Subscribe {
     fn execute(self, topic: impl Topic) -> StreamingResponse {
-        let rx = topic.subscribe(self.topic);
-        Box::pin(ReceiverStream::new(rx))
+        if !self.group.is_empty() {
+            // 消费组只接收新的消息，不支持从 offset 开始重放
+            if matches!(self.start, Some(subscribe::Start::FromOffset(_))) {
+                let e = KvError::InvalidCommand("group subscription can't start from offset".into());
+                return Box::pin(stream::once(async { Arc::new(e.into()) }));
+            }
+            return Box::pin(ReceiverStream::new(topic.subscribe_group(
+                self.topic,
+                self.group,
+                self.consumer,
+            )));
+        }
+
+        match self.start {
+            Some(subscribe::Start::FromOffset(offset)) => {
+                match topic.subscribe_from(self.topic, offset) {
+                    Ok(rx) => Box::pin(ReceiverStream::new(rx)),
+                    Err(e) => Box::pin(stream::once(async { Arc::new(e.into()) })),
+                }
+            }
+            _ => Box::pin(ReceiverStream::new(topic.subscribe(self.topic))),
+        }
     }
 }
 
@@ -30,10 +57,79 @@ impl TopicService for Unsubscribe {
     }
 }
 
+impl TopicService for Psubscribe {
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        match topic.psubscribe(self.pattern) {
+            Ok(rx) => Box::pin(ReceiverStream::new(rx)),
+            Err(e) => Box::pin(stream::once(async { Arc::new(e.into()) })),
+        }
+    }
+}
+
+impl TopicService for Punsubscribe {
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        let res = match topic.punsubscribe(self.pattern, self.id) {
+            Ok(_) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        };
+        Box::pin(stream::once(async { Arc::new(res) }))
+    }
+}
+
+impl TopicService for Ack {
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        // 返回确认成功的消息数量
+        let res = match topic.ack(self.topic, self.group, self.ids) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        };
+        Box::pin(stream::once(async { Arc::new(res) }))
+    }
+}
+
+impl TopicService for ListSubscriptions {
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        let res = topic.list_subscriptions(self.prefix).into();
+        Box::pin(stream::once(async { Arc::new(res) }))
+    }
+}
+
+impl TopicService for ListTopics {
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        let res = topic.list_topics(self.prefix).into();
+        Box::pin(stream::once(async { Arc::new(res) }))
+    }
+}
+
+impl TopicService for TopicInfo {
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        let res = topic.topic_info(self.topic).into();
+        Box::pin(stream::once(async { Arc::new(res) }))
+    }
+}
+
+impl TopicService for NumSub {
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        let res = topic.num_sub(self.topics).into();
+        Box::pin(stream::once(async { Arc::new(res) }))
+    }
+}
+
 impl TopicService for Publish {
     fn execute(self, topic: impl Topic) -> StreamingResponse {
-        topic.publish(self.topic, Arc::new(self.data.into()));
-        Box::pin(stream::once(async { Arc::new(CommandResponse::ok()) }))
+        let delivery = topic.publish(self.topic, Arc::new(self.data.into()));
+        if !self.wait {
+            let res = Value::from(delivery.receivers as i64).into();
+            return Box::pin(stream::once(async { Arc::new(res) }));
+        }
+
+        Box::pin(stream::once(async move {
+            let res = match time::timeout(PUBLISH_WAIT_TIMEOUT, delivery.delivered).await {
+                Ok(delivered) => Value::from(delivered.unwrap_or_default() as i64).into(),
+                Err(_) => KvError::Timeout("waiting for subscribers".into()).into(),
+            };
+            Arc::new(res)
+        }))
     }
 }
 
@@ -52,7 +148,27 @@ mod tests {
         let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
         let mut res = dispatch_stream(cmd, topic);
         let data = res.next().await.unwrap();
-        assert_res_ok(&data, &[], &[]);
+        assert_res_ok(&data, &[0.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn dispatch_publish_should_return_receivers() {
+        let topic = Arc::new(Broadcaster::default());
+        let mut s1 = dispatch_stream(CommandRequest::new_subscribe("lobby"), topic.clone());
+        let mut s2 = dispatch_stream(CommandRequest::new_psubscribe("lob*"), topic.clone());
+        s1.next().await.unwrap();
+        s2.next().await.unwrap();
+
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        let mut res = dispatch_stream(cmd, topic.clone());
+        assert_res_ok(&res.next().await.unwrap(), &[2.into()], &[]);
+
+        // 等待模式下返回时，消息已经放进了订阅者的队列
+        let cmd = CommandRequest::new_publish_wait("lobby", vec!["world".into()]);
+        let mut res = dispatch_stream(cmd, topic);
+        assert_res_ok(&res.next().await.unwrap(), &[2.into()], &[]);
+        assert_res_ok(&s1.next().await.unwrap(), &["hello".into()], &[]);
+        assert_res_ok(&s2.next().await.unwrap(), &["hello".into()], &[]);
     }
 
 
@@ -74,7 +190,7 @@ mod tests {
             let mut res = dispatch_stream(cmd, topic.clone());
             let id: i64 = res.next().await.unwrap().as_ref().try_into().unwrap();
             drop(res);
-            id as u32
+            id as u64
         };
 
         // publish 时， 这个 subscription 已经失效，所有会被删除
@@ -111,4 +227,22 @@ mod tests {
         assert_res_error(&data, 404, "Not found: subscription 9527");
     }
 
+    #[tokio::test]
+    async fn dispatch_group_subscribe_and_ack_should_work() {
+        let topic = Arc::new(Broadcaster::default());
+        let cmd = CommandRequest::new_subscribe_group("orders", "g1", "c1");
+        let mut res = dispatch_stream(cmd, topic.clone());
+        let id: i64 = res.next().await.unwrap().as_ref().try_into().unwrap();
+        assert!(id > 0);
+
+        let cmd = CommandRequest::new_publish("orders", vec!["hello".into()]);
+        let _ = dispatch_stream(cmd, topic.clone());
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &["hello".into()], &[]);
+
+        let cmd = CommandRequest::new_ack("orders", "g1", vec![data.message_id, 9527]);
+        let mut res = dispatch_stream(cmd, topic);
+        assert_res_ok(&res.next().await.unwrap(), &[1.into()], &[]);
+    }
+
 }
\ No newline at end of file
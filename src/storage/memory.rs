@@ -1,9 +1,70 @@
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
-use dashmap::{mapref::one::Ref, DashMap};
+use crate::service::is_log_table;
+use crate::{EvictionPolicy, KvError, Kvpair, MemoryConfig, Storage, StorageIter, StorageSize, Value};
+use dashmap::mapref::{entry::Entry as Slot, one::Ref};
+use dashmap::DashMap;
+use prost::Message;
+use rand::Rng;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
+/// 淘汰时每次随机采样的 key 的数量，从中选出最应该淘汰的一个
+const EVICTION_SAMPLES: usize = 16;
+
+/// key 被淘汰时的回调，参数是 key 所在的 table 和释放的字节数
+type EvictHook = Arc<dyn Fn(&str, u64) + Send + Sync>;
+
+/// clone 出来的 MemTable 和原来的共享同一份数据
 #[derive(Clone, Debug, Default)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: Arc<DashMap<String, DashMap<String, Entry>>>,
+    /// 内存限制，不设置则不做限制
+    limit: Option<MemoryConfig>,
+    /// 所有 key 和 value 大致占用的字节数
+    used: Arc<AtomicU64>,
+    /// 所有 table 中 key 的数量
+    keys: Arc<AtomicU64>,
+    /// 逻辑时钟，每次访问加一，用于 LRU
+    clock: Arc<AtomicU64>,
+    on_evict: OnEvict,
+}
+
+#[derive(Clone, Default)]
+struct OnEvict(Option<EvictHook>);
+
+impl fmt::Debug for OnEvict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OnEvict").field(&self.0.is_some()).finish()
+    }
+}
+
+/// table 中保存的 value，以及用于淘汰的访问信息
+#[derive(Debug, Default)]
+struct Entry {
+    value: Value,
+    last_access: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl Clone for Entry {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+            last_access: AtomicU64::new(self.last_access.load(Ordering::Relaxed)),
+            hits: AtomicU64::new(self.hits.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Entry {
+    /// 淘汰时的分数，分数越低越先被淘汰
+    fn score(&self, policy: EvictionPolicy, rng: &mut impl Rng) -> u64 {
+        match policy {
+            EvictionPolicy::Lru => self.last_access.load(Ordering::Relaxed),
+            EvictionPolicy::Lfu => self.hits.load(Ordering::Relaxed),
+            _ => rng.gen(),
+        }
+    }
 }
 
 impl MemTable {
@@ -13,8 +74,27 @@ impl MemTable {
         Self::default()
     }
 
+    /// 创建一个有内存限制的 MemTable
+    pub fn with_limit(limit: MemoryConfig) -> Self {
+        Self {
+            limit: Some(limit),
+            ..Default::default()
+        }
+    }
+
+    /// 设置 key 被淘汰时的回调，比如用来更新 namespace 的用量
+    pub fn on_evict(mut self, f: impl Fn(&str, u64) + Send + Sync + 'static) -> Self {
+        self.on_evict = OnEvict(Some(Arc::new(f)));
+        self
+    }
+
+    /// 当前所有 key 和 value 大致占用的字节数
+    pub fn used_memory(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
     /// 如果名为 name 的 hash table 不存在， 则创建，否则返回
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Entry>> {
         match self.tables.get(name) {
             Some(table) => table,
             None => {
@@ -23,17 +103,166 @@ impl MemTable {
             }
         }
     }
+
+    /// 记录一次访问
+    fn touch(&self, entry: &Entry) {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        entry.last_access.store(now, Ordering::Relaxed);
+        entry.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 用 size 字节替换 old 字节，超过内存限制时不做修改并返回 false
+    /// 调用者需要持有 key 所在分片的锁，保证 old 在这期间不会变化
+    fn charge(&self, old: u64, size: u64) -> bool {
+        let max = self.limit.as_ref().map(|l| l.max_memory).unwrap_or(u64::MAX);
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                let used = used.saturating_sub(old) + size;
+                (used <= max).then_some(used)
+            })
+            .is_ok()
+    }
+
+    /// 内存不够写入 size 字节时，根据策略淘汰一个 key，不能淘汰时返回错误
+    fn evict(&self, table: &str, key: &str, size: u64) -> Result<(), KvError> {
+        let limit = match &self.limit {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let oom = || {
+            KvError::OutOfMemory(format!(
+                "{} bytes used, max memory is {}",
+                self.used_memory(),
+                limit.max_memory
+            ))
+        };
+
+        // key 没有过期时间，TtlFirst 没有可以淘汰的 key
+        let evictable = !matches!(
+            limit.policy,
+            EvictionPolicy::NoEviction | EvictionPolicy::TtlFirst
+        );
+        if !evictable || size > limit.max_memory {
+            return Err(oom());
+        }
+
+        let (t, k) = self.candidate(limit.policy, table, key).ok_or_else(oom)?;
+        let removed = self.tables.get(&t).and_then(|table| table.remove(&k));
+        if let Some((k, e)) = removed {
+            let size = entry_size(&k, &e.value);
+            self.used.fetch_sub(size, Ordering::Relaxed);
+            self.keys.fetch_sub(1, Ordering::Relaxed);
+            if let Some(f) = &self.on_evict.0 {
+                f(&t, size);
+            }
+        }
+        Ok(())
+    }
+
+    /// 随机采样 EVICTION_SAMPLES 个 key，返回其中最应该淘汰的一个，不淘汰正在写入的 key
+    /// key 不多的时候直接比较所有的 key。topic 的消息日志由保留数量控制，不会被淘汰
+    fn candidate(&self, policy: EvictionPolicy, table: &str, key: &str) -> Option<(String, String)> {
+        let mut rng = rand::thread_rng();
+        let tables: Vec<_> = self
+            .tables
+            .iter()
+            .filter(|t| !is_log_table(t.key()))
+            .map(|t| (t.key().clone(), t.len()))
+            .collect();
+        let total: usize = tables.iter().map(|(_, len)| len).sum();
+        let mut candidates = Vec::new();
+
+        if total <= EVICTION_SAMPLES {
+            for t in self.tables.iter().filter(|t| !is_log_table(t.key())) {
+                for e in t.value().iter() {
+                    let score = e.score(policy, &mut rng);
+                    candidates.push((score, t.key().clone(), e.key().clone()));
+                }
+            }
+        } else {
+            for _ in 0..EVICTION_SAMPLES {
+                // 先按 key 的数量选出 table，再在 table 的分片中找到第 pos 个 key
+                let mut pos = rng.gen_range(0..total);
+                let name = tables.iter().find_map(|(name, len)| match pos < *len {
+                    true => Some(name),
+                    false => {
+                        pos -= len;
+                        None
+                    }
+                });
+                let t = match name.and_then(|name| self.tables.get(name)) {
+                    Some(t) => t,
+                    None => continue,
+                };
+                for shard in t.shards() {
+                    let shard = shard.read();
+                    if pos < shard.len() {
+                        if let Some((k, e)) = shard.iter().nth(pos) {
+                            let score = e.get().score(policy, &mut rng);
+                            candidates.push((score, t.key().clone(), k.clone()));
+                        }
+                        break;
+                    }
+                    pos -= shard.len();
+                }
+            }
+        }
+
+        candidates
+            .into_iter()
+            .filter(|(_, t, k)| t != table || k != key)
+            .min_by_key(|(score, ..)| *score)
+            .map(|(_, t, k)| (t, k))
+    }
+}
+
+/// key 和 value 大致占用的字节数
+fn entry_size(key: &str, value: &Value) -> u64 {
+    (key.len() + value.encoded_len()) as u64
 }
 
 impl Storage for MemTable {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
         let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.value().clone()))
+        Ok(table.get(key).map(|e| {
+            self.touch(&e);
+            e.value.clone()
+        }))
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.insert(key, value))
+        let size = entry_size(&key, &value);
+        let entry = Entry {
+            value,
+            ..Default::default()
+        };
+        self.touch(&entry);
+
+        let mut key = key;
+        loop {
+            // 在 key 所在分片的锁内检查并更新内存用量，并发的写入不会超过限制
+            {
+                let t = self.get_or_create_table(table);
+                let slot = t.entry(key);
+                let old = match &slot {
+                    Slot::Occupied(e) => entry_size(e.key(), &e.get().value),
+                    Slot::Vacant(_) => 0,
+                };
+                if self.charge(old, size) {
+                    return Ok(match slot {
+                        Slot::Occupied(mut e) => Some(e.insert(entry).value),
+                        Slot::Vacant(e) => {
+                            e.insert(entry);
+                            self.keys.fetch_add(1, Ordering::Relaxed);
+                            None
+                        }
+                    });
+                }
+                key = slot.into_key();
+            }
+            // 淘汰时不能持有分片的锁
+            self.evict(table, &key, size)?;
+        }
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
@@ -43,27 +272,201 @@ impl Storage for MemTable {
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
        let table = self.get_or_create_table(table);
-        Ok(table.remove(key).map(|(_k, v) | v))
+        Ok(table.remove(key).map(|(k, e) | {
+            self.used.fetch_sub(entry_size(&k, &e.value), Ordering::Relaxed);
+            self.keys.fetch_sub(1, Ordering::Relaxed);
+            e.value
+        }))
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
         let table = self.get_or_create_table(table);
         Ok(table
             .iter()
-            .map(|v| Kvpair::new(v.key(), v.value().clone()))
+            .map(|v| Kvpair::new(v.key(), v.value().value.clone()))
             .collect()
         )
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item=Kvpair>>, KvError> {
         let table = self.get_or_create_table(table).clone();
-        let iter = StorageIter::new(table.into_iter());
+        let iter = StorageIter::new(table.into_iter().map(|(k, e)| (k, e.value)));
         Ok(Box::new(iter))
     }
+
+    fn size(&self) -> Result<StorageSize, KvError> {
+        // 直接使用写入时维护的计数，不需要遍历所有的 key
+        Ok(StorageSize {
+            keys: self.keys.load(Ordering::Relaxed),
+            bytes: self.used_memory(),
+        })
+    }
 }
 
 impl From<(String, Value)> for Kvpair {
     fn from(data: (String, Value)) -> Self {
         Kvpair::new(data.0, data.1)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limited(max_memory: u64, policy: EvictionPolicy) -> MemTable {
+        MemTable::with_limit(MemoryConfig { max_memory, policy })
+    }
+
+    #[test]
+    fn memtable_should_track_used_memory() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        let used = store.used_memory();
+        assert_eq!(used, store.size().unwrap().bytes);
+
+        store.set("t1", "k1".into(), "v1v1".into()).unwrap();
+        assert_eq!(store.used_memory(), used + 2);
+
+        store.del("t1", "k1").unwrap();
+        store.del("t1", "k2").unwrap();
+        assert_eq!(store.used_memory(), 0);
+    }
+
+    #[test]
+    fn cloned_memtable_should_share_data() {
+        let store = MemTable::new();
+        let cloned = store.clone();
+        cloned.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.size().unwrap(), cloned.size().unwrap());
+
+        store.del("t1", "k1").unwrap();
+        assert!(!cloned.contains("t1", "k1").unwrap());
+        assert_eq!(cloned.used_memory(), 0);
+    }
+
+    #[test]
+    fn memtable_without_eviction_should_reject_writes() {
+        // 每个 entry 是 2 字节的 key 加上 4 字节的 value
+        let store = limited(12, EvictionPolicy::NoEviction);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+
+        let result = store.set("t1", "k3".into(), "v3".into());
+        assert!(matches!(result, Err(KvError::OutOfMemory(_))));
+
+        // 覆盖已有的 key 不会超过限制
+        store.set("t1", "k2".into(), "v3".into()).unwrap();
+    }
+
+    #[test]
+    fn memtable_lru_should_evict_least_recently_used() {
+        let store = limited(12, EvictionPolicy::Lru);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), "v2".into()).unwrap();
+        store.get("t1", "k1").unwrap();
+
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        assert!(store.contains("t1", "k1").unwrap());
+        assert!(!store.contains("t2", "k2").unwrap());
+        assert!(store.used_memory() <= 12);
+    }
+
+    #[test]
+    fn memtable_lfu_should_evict_least_frequently_used() {
+        let store = limited(12, EvictionPolicy::Lfu);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.get("t1", "k2").unwrap();
+        store.get("t1", "k2").unwrap();
+        store.get("t1", "k1").unwrap();
+
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        assert!(!store.contains("t1", "k1").unwrap());
+        assert!(store.contains("t1", "k2").unwrap());
+    }
+
+    #[test]
+    fn memtable_random_should_stay_under_limit() {
+        let store = limited(60, EvictionPolicy::Random);
+        for i in 0..100 {
+            store.set("t1", format!("k{:02}", i), "v1".into()).unwrap();
+        }
+        assert!(store.used_memory() <= 60);
+        assert!(store.contains("t1", "k99").unwrap());
+    }
+
+    #[test]
+    fn memtable_ttl_first_should_reject_writes() {
+        let store = limited(12, EvictionPolicy::TtlFirst);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+
+        let result = store.set("t1", "k3".into(), "v3".into());
+        assert!(matches!(result, Err(KvError::OutOfMemory(_))));
+    }
+
+    #[test]
+    fn memtable_lru_should_sample_when_keys_are_many() {
+        // 每个 entry 是 4 字节的 key 加上 4 字节的 value，最多 100 个
+        let store = limited(800, EvictionPolicy::Lru);
+        for i in 0..1000 {
+            store.set(&format!("t{}", i % 3), format!("k{:03}", i), "v1".into()).unwrap();
+        }
+        assert_eq!(store.used_memory(), 800);
+        assert_eq!(store.size().unwrap().bytes, 800);
+        assert!(store.contains("t0", "k999").unwrap());
+    }
+
+    #[test]
+    fn memtable_should_report_evicted_keys() {
+        let evicted = Arc::new(AtomicU64::new(0));
+        let counter = evicted.clone();
+        let store = limited(12, EvictionPolicy::Lru).on_evict(move |table, size| {
+            assert_eq!(table, "t1");
+            counter.fetch_add(size, Ordering::Relaxed);
+        });
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        assert_eq!(evicted.load(Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn memtable_should_not_evict_topic_logs() {
+        let store = limited(12, EvictionPolicy::Lru);
+        store.set("__log__:orders", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        assert!(store.contains("__log__:orders", "k1").unwrap());
+        assert!(!store.contains("t1", "k2").unwrap());
+
+        // 只剩下消息日志时不能再淘汰
+        store.set("__log__:orders", "k2".into(), "v2".into()).unwrap();
+        assert!(!store.contains("t1", "k3").unwrap());
+        let res = store.set("t1", "k4".into(), "v4".into());
+        assert!(matches!(res, Err(KvError::OutOfMemory(_))));
+    }
+
+    #[test]
+    fn concurrent_writes_should_stay_under_limit() {
+        let store = Arc::new(limited(600, EvictionPolicy::NoEviction));
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for j in 0..100 {
+                        let _ = store.set("t1", format!("k{}-{:02}", i, j), "v1".into());
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(store.used_memory(), store.size().unwrap().bytes);
+        assert!(store.used_memory() <= 600);
+    }
+}
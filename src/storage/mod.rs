@@ -25,6 +25,20 @@ pub trait Storage: Send + Sync + 'static {
 
     /// 遍历 HashTable，返回 kv pair 的 Iterator
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item=Kvpair>>, KvError>;
+
+    /// 获取整个 storage 的大致大小，不支持的 storage 返回错误
+    fn size(&self) -> Result<StorageSize, KvError> {
+        Err(KvError::Internal("storage size is not supported".into()))
+    }
+}
+
+/// storage 的大小
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StorageSize {
+    /// 所有 table 中 key 的数量
+    pub keys: u64,
+    /// 占用的字节数
+    pub bytes: u64,
 }
 
 
@@ -56,6 +70,7 @@ impl<T> Iterator for StorageIter<T>
 mod tests {
     use crate::storage::sleddb::SledDb;
     use tempfile::tempdir;
+    use prost::Message;
     use super::*;
 
     #[test]
@@ -76,6 +91,25 @@ mod tests {
         test_get_iter(store);
     }
 
+    #[test]
+    fn memtable_size_should_work() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), "v2".into()).unwrap();
+
+        let size = store.size().unwrap();
+        assert_eq!(size.keys, 2);
+        assert!(size.bytes > 0);
+
+        // 覆盖已有的 key 不增加 key 的数量
+        store.set("t1", "k1".into(), "v11".into()).unwrap();
+        store.del("t2", "k2").unwrap();
+        store.del("t2", "k3").unwrap();
+        let size = store.size().unwrap();
+        assert_eq!(size.keys, 1);
+        assert_eq!(size.bytes, ("k1".len() + Value::from("v11").encoded_len()) as u64);
+    }
+
 
     #[test]
     fn sleddb_basic_interface_should_work() {
@@ -1,6 +1,6 @@
 use sled::{Db, IVec};
 use std::{convert::TryInto, path::Path, str};
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use crate::{KvError, Kvpair, Storage, StorageIter, StorageSize, Value};
 
 #[derive(Debug)]
 pub struct SledDb(Db);
@@ -61,6 +61,13 @@ impl Storage for SledDb {
         let iter = StorageIter::new(self.0.scan_prefix(prefix));
         Ok(Box::new(iter))
     }
+
+    fn size(&self) -> Result<StorageSize, KvError> {
+        Ok(StorageSize {
+            keys: self.0.len() as _,
+            bytes: self.0.size_on_disk()?,
+        })
+    }
 }
 
 impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
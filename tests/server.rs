@@ -1,27 +1,29 @@
 use anyhow::Result;
 use mini_kv::{
-    start_client_with_config, start_server_with_config, ClientConfig, CommandRequest,
+    start_client_with_config, start_server_with_listener, start_server_with_reload, AclRule,
+    AuthConfig, BlockingClient, ClientConfig, CommandRequest, ConfigReload, KvError,
     ProstClientStream, ServerConfig, StorageConfig,
 };
 use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
 use tokio::time;
 
 #[tokio::test]
 async fn yamux_server_client_full_tests() -> Result<()> {
-    let addr = "127.0.0.1:10086";
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
 
     let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
-    config.general.addr = addr.into();
     config.storage = StorageConfig::MemTable;
 
     // 启动服务器
     tokio::spawn(async move {
-        start_server_with_config(&config).await.unwrap();
+        start_server_with_listener(&config, listener).await.unwrap();
     });
 
-    time::sleep(Duration::from_millis(10)).await;
     let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
-    config.general.addr = addr.into();
+    config.general.addr = addr.to_string();
 
     let mut ctrl = start_client_with_config(&config).await.unwrap();
     let mut stream = ctrl.open_stream().await?;
@@ -38,4 +40,124 @@ async fn yamux_server_client_full_tests() -> Result<()> {
     assert_eq!(data.values, &["world".into()]);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn blocking_client_should_work() -> Result<()> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.storage = StorageConfig::MemTable;
+
+    // 在单独的线程里启动服务器
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let listener = TcpListener::from_std(listener).unwrap();
+            start_server_with_listener(&config, listener).await.unwrap();
+        });
+    });
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.to_string();
+
+    let mut client = BlockingClient::connect(&config)?;
+
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
+    client.execute_unary(&cmd)?;
+
+    let cmd = CommandRequest::new_hget("table1", "hello");
+    let data = client.execute_unary(&cmd)?;
+    assert_eq!(data.status, 200);
+    assert_eq!(data.values, &["world".into()]);
+
+    // subscribe 之后，publish 的数据能从 iterator 中读到
+    let cmd = CommandRequest::new_subscribe("blocking");
+    let mut stream = client.execute_streaming(&cmd)?;
+    assert!(stream.id > 0);
+
+    let cmd = CommandRequest::new_publish("blocking", vec!["hello".into()]);
+    client.execute_unary(&cmd)?;
+
+    let data = stream.next().unwrap()?;
+    assert_eq!(data.values, &["hello".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn subscriptions_should_be_removed_when_client_disconnects() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.storage = StorageConfig::MemTable;
+
+    tokio::spawn(async move {
+        start_server_with_listener(&config, listener).await.unwrap();
+    });
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.to_string();
+
+    // 第一个连接订阅 lobby
+    let mut ctrl = start_client_with_config(&config).await?;
+    let stream = ctrl.open_stream().await?;
+    let result = stream
+        .execute_streaming(&CommandRequest::new_subscribe("lobby"))
+        .await?;
+
+    // 第二个连接可以看到这个订阅
+    let mut admin = start_client_with_config(&config).await?;
+    let mut stream = admin.open_stream().await?;
+    let cmd = CommandRequest::new_list_subscriptions("");
+    let data = stream.execute_unary(&cmd).await?;
+    assert_eq!(data.pairs.len(), 1);
+    assert_eq!(data.pairs[0].key, "lobby");
+    assert_eq!(data.pairs[0].value, Some((result.id as i64).into()));
+
+    // 第一个连接断开后，订阅被删除
+    drop(result);
+    ctrl.close().await?;
+    time::sleep(Duration::from_millis(100)).await;
+    let mut stream = admin.open_stream().await?;
+    let data = stream.execute_unary(&cmd).await?;
+    assert!(data.pairs.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reload_should_report_invalid_config() -> Result<()> {
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = "127.0.0.1:0".into();
+    config.storage = StorageConfig::MemTable;
+
+    let (tx, rx) = mpsc::channel(1);
+    let running = config.clone();
+    tokio::spawn(async move {
+        start_server_with_reload(&running, rx).await.unwrap();
+    });
+
+    // ACL 中有不合法的 pattern，整个配置都不生效
+    let mut invalid = config.clone();
+    invalid.auth = Some(AuthConfig {
+        users: vec![],
+        rules: vec![AclRule {
+            identity: "*".into(),
+            pattern: "[".into(),
+            permissions: vec![],
+        }],
+    });
+    let (applied, result) = oneshot::channel();
+    tx.send(ConfigReload { config: invalid, applied }).await?;
+    assert!(matches!(result.await?, Err(KvError::InvalidConfig(_))));
+
+    let (applied, result) = oneshot::channel();
+    tx.send(ConfigReload { config, applied }).await?;
+    assert!(result.await?.is_ok());
+
+    Ok(())
+}